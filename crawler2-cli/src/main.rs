@@ -0,0 +1,429 @@
+#![feature(async_await, await_macro, futures_api)]
+
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate slog;
+
+use clap::ArgMatches;
+use crawler2::error::{CrawlErrorKind, CrawlResult};
+use crawler2::prelude::*;
+use slog::{Drain, Level, Logger};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const EXIT_FAILED: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+const EXIT_CANCELLED: i32 = 130;
+const DEFAULT_CHECKPOINT_DIR: &'static str = ".crawler2/checkpoints";
+
+fn logger(matches: &ArgMatches) -> Logger {
+    let level = match matches.occurrences_of("verbose") {
+        0 => Level::Warning,
+        1 => Level::Info,
+        2 => Level::Debug,
+        _ => Level::Trace,
+    };
+
+    match matches.value_of("log-format").unwrap_or("term") {
+        "json" => {
+            let drain = slog_json::Json::default(std::io::stderr()).fuse();
+            let drain = slog_async::Async::new(drain).build().filter_level(level).fuse();
+            Logger::root(drain, o!())
+        }
+        _ => {
+            let decorator = slog_term::TermDecorator::new().stderr().build();
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            let drain = slog_async::Async::new(drain).build().filter_level(level).fuse();
+            Logger::root(drain, o!())
+        }
+    }
+}
+
+/// Parses `key=value` pairs. Values are read as JSON when possible,
+/// so `page=2` gives a number and `output=./out` a string.
+fn parse_args(matches: &ArgMatches) -> Result<Args, String> {
+    let mut args = Args::new();
+    for arg in matches.values_of("arg").into_iter().flatten() {
+        let idx = match arg.find('=') {
+            Some(idx) => idx,
+            None => return Err(format!("invalid argument \"{}\", expected key=value", arg)),
+        };
+        let value = &arg[idx + 1..];
+        args.insert(
+            arg[..idx].to_string(),
+            serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string())),
+        );
+    }
+    Ok(args)
+}
+
+fn is_description(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") | Some("yaml") | Some("yml") => path.is_file(),
+        _ => false,
+    }
+}
+
+/// Loads a description file, or every target description in a directory.
+/// Files in a directory which are not targets (e.g. imported flow
+/// libraries) are skipped.
+fn load_engine(path: &Path, env: Arc<Environment>) -> CrawlResult<Engine> {
+    let mut engine = Engine::new(env.clone());
+
+    if !path.is_dir() {
+        engine.add_file(path)?;
+        return Ok(engine);
+    }
+
+    let mut files = std::fs::read_dir(path)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| is_description(p))
+        .collect::<Vec<_>>();
+    files.sort();
+
+    for file in files {
+        if let Err(e) = engine.add_file(&file) {
+            debug!(env.log(), "skipping file"; "path" => file.to_string_lossy().as_ref(), "error" => e.to_string());
+        }
+    }
+
+    Ok(engine)
+}
+
+fn selected<'a>(engine: &'a Engine, matches: &ArgMatches) -> CrawlResult<Vec<&'a Target>> {
+    match matches.value_of("target") {
+        Some(name) => Ok(vec![engine.target(name)?]),
+        None => Ok(engine.targets().iter().collect()),
+    }
+}
+
+fn list(engine: &Engine) -> i32 {
+    for target in engine.targets() {
+        println!("{}\t{}", target.description().name, target.path().display());
+    }
+    0
+}
+
+fn validate(engine: &Engine, matches: &ArgMatches, args: &Args) -> CrawlResult<i32> {
+    let mut code = 0;
+    for target in selected(engine, matches)? {
+        match target.validate(args) {
+            Ok(_) => println!("{}: ok", target.description().name),
+            Err(e) => {
+                code = EXIT_FAILED;
+                println!("{}:", target.description().name);
+                match e.kind() {
+                    CrawlErrorKind::Validation(errors) => {
+                        for e in errors.iter() {
+                            println!("  {}", e);
+                        }
+                    }
+                    _ => println!("  {}", e),
+                }
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// Prints descriptions with their imported flows inlined. The flows called
+/// by imported flows are renamed to the flows they resolve to.
+fn print(engine: &Engine, matches: &ArgMatches) -> CrawlResult<i32> {
+    for target in selected(engine, matches)? {
+        let mut desc = target.description().clone();
+        desc.imports.clear();
+        for imported in target.imported_flows() {
+            let mut flow = imported.flow.clone();
+            flow.name = imported.name.clone();
+            let namespace = flow_namespace(&imported.name);
+            let mut value = serde_json::to_value(&flow).map_err(|e| CrawlErrorKind::Error(Box::new(e)))?;
+            qualify_flows(&mut value, &|name| target.flow(namespace, name).map(|(name, _)| name));
+            desc.flows
+                .push(serde_json::from_value(value).map_err(|e| CrawlErrorKind::Error(Box::new(e)))?);
+        }
+
+        let out = if matches.is_present("json") {
+            serde_json::to_string_pretty(&desc).map_err(|e| CrawlErrorKind::Error(Box::new(e)))?
+        } else {
+            serde_yaml::to_string(&desc).map_err(|e| CrawlErrorKind::Error(Box::new(e)))?
+        };
+        println!("{}", out);
+    }
+    Ok(0)
+}
+
+/// Replaces the name of every flow called in `value` by what `resolve` gives
+fn qualify_flows<F: Fn(&str) -> Option<String>>(value: &mut Value, resolve: &F) {
+    match value {
+        Value::Object(map) => {
+            if map.get("type").and_then(|t| t.as_str()) == Some("Flow") {
+                let name = map.get("flow_name").and_then(|n| n.as_str()).and_then(resolve);
+                if let Some(name) = name {
+                    map.insert("flow_name".to_string(), Value::String(name));
+                }
+            }
+            for v in map.values_mut() {
+                qualify_flows(v, resolve);
+            }
+        }
+        Value::Array(values) => {
+            for v in values {
+                qualify_flows(v, resolve);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn progress(event: &Event) {
+    match event {
+        Event::TargetStarted { target } => eprintln!("{}: started", target),
+        Event::TargetFinished {
+            target,
+            outputs,
+            errors,
+        } => eprintln!("{}: finished, {} packages, {} errors", target, outputs, errors),
+        Event::FlowEntered { target, flow, .. } => eprintln!("{}: entering flow {}", target, flow),
+        Event::StepFinished {
+            target,
+            step,
+            package,
+            duration_ms,
+            ..
+        } => eprintln!("{}: {} {} ({:.0}ms)", target, step, package, duration_ms),
+        Event::Error {
+            target,
+            step,
+            message,
+            ..
+        } => eprintln!("{}: {} failed: {}", target, step, message),
+        _ => {}
+    }
+}
+
+fn run(engine: Engine, matches: &ArgMatches, args: Args) -> CrawlResult<i32> {
+    let names = selected(&engine, matches)?
+        .iter()
+        .map(|t| t.description().name.clone())
+        .collect::<Vec<_>>();
+    let concurrency = value_t!(matches, "concurrency", usize)
+        .unwrap_or_else(|_| engine.env().config().concurrency.unwrap_or(1));
+
+    let report_file = matches.value_of("report").map(|m| m.to_string());
+    let resume = matches.is_present("resume");
+    let checkpoint = match matches.value_of("checkpoint") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None if resume => Some(PathBuf::from(DEFAULT_CHECKPOINT_DIR)),
+        None => None,
+    };
+
+    if matches.is_present("progress") {
+        engine.observe(progress);
+    }
+
+    // The first signal lets running steps finish, a second one aborts them
+    let cancellation = engine.cancellation().clone();
+    let grace = Duration::from_secs(value_t!(matches, "grace", u64).unwrap_or(10));
+    let c = cancellation.clone();
+    ctrlc::set_handler(move || {
+        if c.is_cancelled() {
+            eprintln!("aborting");
+            c.abort();
+        } else {
+            eprintln!("stopping, press Ctrl-C again to abort running steps");
+            c.cancel(grace);
+        }
+    })
+    .map_err(|e| CrawlErrorKind::Error(Box::new(e)))?;
+
+    let mut exporters = Vec::new();
+    if let Some(addr) = matches.value_of("metrics-addr") {
+        let addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|e| CrawlErrorKind::Error(Box::new(e)))?;
+        exporters.push(engine.serve_metrics(addr)?);
+    }
+    if let Some(file) = matches.value_of("metrics-file") {
+        let interval = value_t!(matches, "metrics-interval", u64).unwrap_or(15);
+        exporters.push(engine.write_metrics(file, Duration::from_secs(interval)));
+    }
+
+    let code = Arc::new(Mutex::new(0));
+    let c = code.clone();
+
+    tokio::run_async(async move {
+        let outcomes = match &checkpoint {
+            Some(dir) => await!(engine.run_resumable(&names, args, concurrency, dir, resume)),
+            None => await!(engine.run_many(&names, args, concurrency)),
+        };
+        let secrets = engine.env().secrets();
+        let mut failed = false;
+        let mut reports = Vec::new();
+        for (name, outcome) in outcomes {
+            match outcome {
+                Ok(outcome) => {
+                    reports.push(outcome.report.clone());
+                    println!(
+                        "{}: {} packages, {} errors",
+                        name,
+                        outcome.outputs.len(),
+                        outcome.errors.len()
+                    );
+                    for e in &outcome.errors {
+                        let location = e.path().unwrap_or_else(|| name.to_string());
+                        let message = match e.package() {
+                            Some(package) => format!("{} [{}]", e, package),
+                            None => e.to_string(),
+                        };
+                        eprintln!("{}: {}", location, secrets.redact(&message));
+                    }
+                    failed = failed || !outcome.is_success();
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", name, secrets.redact(&e.to_string()));
+                    failed = true;
+                }
+            }
+        }
+        if let Some(file) = report_file {
+            let written = serde_json::to_string_pretty(&reports)
+                .map_err(|e| e.to_string())
+                .and_then(|json| std::fs::write(&file, json).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                eprintln!("could not write report {}: {}", file, e);
+                failed = true;
+            }
+        }
+        if failed {
+            *c.lock().unwrap() = EXIT_FAILED;
+        }
+    });
+
+    drop(exporters);
+
+    if cancellation.is_cancelled() {
+        return Ok(EXIT_CANCELLED);
+    }
+    let code = *code.lock().unwrap();
+    Ok(code)
+}
+
+fn execute(matches: &ArgMatches) -> CrawlResult<i32> {
+    let (name, sub) = match matches.subcommand() {
+        ("schema", _) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&crawler2::schema::target_schema()).unwrap()
+            );
+            return Ok(0);
+        }
+        (name, Some(sub)) => (name, sub),
+        _ => {
+            eprintln!("{}", matches.usage());
+            return Ok(EXIT_ERROR);
+        }
+    };
+
+    let log = logger(sub);
+    let args = match parse_args(sub) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(EXIT_ERROR);
+        }
+    };
+
+    let mut env = Environment::build(std::env::current_dir()?, log)
+        .env_prefix(DEFAULT_ENV_PREFIX)
+        .default_config()?;
+    if let Some(file) = sub.value_of("env-file") {
+        env = env.dotenv(file)?;
+    } else if Path::new(".env").is_file() {
+        env = env.dotenv(".env")?;
+    }
+    if let Some(file) = sub.value_of("config") {
+        env = env.config_file(file)?;
+    }
+    env = env.config(Config {
+        in_flight: value_t!(sub, "in-flight", usize).ok(),
+        order: match sub.value_of("order") {
+            Some("depth-first") => Some(Order::DepthFirst),
+            Some(_) => Some(Order::BreadthFirst),
+            None => None,
+        },
+        ..Config::default()
+    });
+    if let Some(file) = sub.value_of("secrets") {
+        env = env.secrets_file(file)?;
+    }
+    let env = env.build();
+    let engine = load_engine(Path::new(sub.value_of("path").unwrap()), env)?;
+
+    match name {
+        "list" => Ok(list(&engine)),
+        "validate" => validate(&engine, sub, &args),
+        "print" => print(&engine, sub),
+        "run" => run(engine, sub, args),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let matches = clap_app!(crawler2 =>
+        (version: crate_version!())
+        (about: "Runs crawler2 target descriptions")
+        (@arg verbose: -v ... +global "Increases log verbosity")
+        (@arg log-format: --("log-format") +takes_value +global possible_value[term json] "Log output format")
+        (@arg config: --config +takes_value +global "Config file layered on top of the crawler.yaml files")
+        (@arg env-file: --("env-file") +takes_value +global "Variables file to use instead of ./.env")
+        (@arg secrets: --secrets +takes_value +global "File of secrets for ${secret:NAME}")
+        (@subcommand run =>
+            (about: "Runs all targets in <path>, or only <target>")
+            (@arg path: +required "Description file or directory")
+            (@arg target: "Name of the target to run")
+            (@arg arg: -a --arg +takes_value +multiple number_of_values(1) "Target argument as key=value")
+            (@arg concurrency: -c --concurrency +takes_value "Number of targets to run at once")
+            (@arg in-flight: --("in-flight") +takes_value "Number of steps each target runs at once")
+            (@arg order: --order +takes_value possible_value[breadth-first depth-first] "Order in which a target crawls, defaults to breadth-first")
+            (@arg grace: --grace +takes_value "Seconds running steps may take to finish when stopped, defaults to 10")
+            (@arg progress: --progress "Prints progress to stderr")
+            (@arg checkpoint: --checkpoint +takes_value "Directory where progress is recorded, so the run can be resumed")
+            (@arg resume: --resume "Continues the previous run recorded in the checkpoint directory")
+            (@arg report: --report +takes_value "Writes a JSON report of the run to a file")
+            (@arg metrics-addr: --("metrics-addr") +takes_value "Serves Prometheus metrics on this address while running")
+            (@arg metrics-file: --("metrics-file") +takes_value "Writes Prometheus metrics to this file while running")
+            (@arg metrics-interval: --("metrics-interval") +takes_value "Seconds between writes of the metrics file, defaults to 15")
+        )
+        (@subcommand list =>
+            (about: "Lists the targets in <path>")
+            (@arg path: +required "Description file or directory")
+        )
+        (@subcommand validate =>
+            (about: "Validates target descriptions")
+            (@arg path: +required "Description file or directory")
+            (@arg target: "Name of the target to validate")
+            (@arg arg: -a --arg +takes_value +multiple number_of_values(1) "Target argument as key=value")
+        )
+        (@subcommand print =>
+            (about: "Prints descriptions with imported flows resolved")
+            (@arg path: +required "Description file or directory")
+            (@arg target: "Name of the target to print")
+            (@arg json: --json "Print as JSON instead of YAML")
+        )
+        (@subcommand schema =>
+            (about: "Prints the JSON Schema of target descriptions")
+        )
+    )
+    .get_matches();
+
+    let code = execute(&matches).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        EXIT_ERROR
+    });
+
+    process::exit(code);
+}
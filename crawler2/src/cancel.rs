@@ -0,0 +1,189 @@
+use conveyor::futures::channel::oneshot;
+use conveyor::futures::future::{self, Future, FutureExt};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    aborted: AtomicBool,
+    waiters: Mutex<Vec<oneshot::Sender<()>>>,
+    children: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl Inner {
+    /// Cancels `inner` and its children. Only the token cancelled directly
+    /// starts a timer, as its children are aborted along with it.
+    fn cancel(inner: &Arc<Inner>, grace: Option<Duration>) {
+        if inner.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for child in inner.children() {
+            Inner::cancel(&child, None);
+        }
+        let grace = match grace {
+            Some(grace) => grace,
+            None => return,
+        };
+        let weak = Arc::downgrade(inner);
+        thread::spawn(move || {
+            thread::sleep(grace);
+            if let Some(inner) = weak.upgrade() {
+                inner.abort();
+            }
+        });
+    }
+
+    fn abort(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if self.aborted.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for child in self.children() {
+            child.abort();
+        }
+        for waiter in self.waiters.lock().unwrap().drain(..) {
+            waiter.send(()).ok();
+        }
+    }
+
+    fn children(&self) -> Vec<Arc<Inner>> {
+        self.children
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|c| c.upgrade())
+            .collect()
+    }
+}
+
+/// Stops a running target.
+///
+/// After `cancel` no new steps are started and packages reaching a step fail
+/// with `CrawlErrorKind::Cancelled`. Steps already running get a grace period
+/// to finish before they are aborted. The run then returns the results
+/// produced so far.
+#[derive(Clone, Default)]
+pub struct Cancellation {
+    inner: Arc<Inner>,
+}
+
+impl Cancellation {
+    pub fn new() -> Cancellation {
+        Cancellation::default()
+    }
+
+    /// Returns a token which is cancelled with this one, but can also be
+    /// cancelled on its own
+    pub fn child(&self) -> Cancellation {
+        let child = Cancellation::new();
+        {
+            let mut children = self.inner.children.lock().unwrap();
+            children.retain(|c| c.upgrade().is_some());
+            children.push(Arc::downgrade(&child.inner));
+        }
+        if self.is_aborted() {
+            child.abort();
+        } else if self.is_cancelled() {
+            Inner::cancel(&child.inner, None);
+        }
+        child
+    }
+
+    /// Stops scheduling new work and aborts running steps after `grace`
+    pub fn cancel(&self, grace: Duration) {
+        Inner::cancel(&self.inner, Some(grace));
+    }
+
+    /// Stops scheduling new work and aborts running steps immediately
+    pub fn abort(&self) {
+        self.inner.abort();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.inner.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Resolves when running steps should be aborted
+    pub fn aborted(&self) -> Pin<Box<Future<Output = ()> + Send>> {
+        let mut waiters = self.inner.waiters.lock().unwrap();
+        if self.is_aborted() {
+            return Box::pin(future::ready(()));
+        }
+        // Drop the senders of steps which have finished
+        waiters.retain(|w| !w.is_canceled());
+        let (sender, receiver) = oneshot::channel();
+        waiters.push(sender);
+        Box::pin(receiver.map(|_| ()))
+    }
+}
+
+impl fmt::Debug for Cancellation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cancellation")
+            .field("cancelled", &self.is_cancelled())
+            .field("aborted", &self.is_aborted())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use conveyor::futures::executor::block_on;
+
+    #[test]
+    fn cancel_and_abort() {
+        let parent = Cancellation::new();
+        let child = parent.child();
+        let aborted = child.aborted();
+
+        parent.cancel(Duration::from_millis(20));
+        assert!(child.is_cancelled());
+        assert!(!child.is_aborted());
+
+        block_on(aborted);
+        assert!(child.is_aborted());
+        block_on(child.aborted());
+    }
+
+    #[test]
+    fn child_cancelled_alone() {
+        let parent = Cancellation::new();
+        let child = parent.child();
+        child.abort();
+        assert!(child.is_aborted());
+        assert!(!parent.is_cancelled());
+        assert!(!parent.child().is_cancelled());
+    }
+
+    #[test]
+    fn finished_waiters_dropped() {
+        let cancellation = Cancellation::new();
+        for _ in 0..100 {
+            drop(cancellation.aborted());
+        }
+        let _running = cancellation.aborted();
+        assert_eq!(cancellation.inner.waiters.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn child_of_cancelled() {
+        let parent = Cancellation::new();
+        parent.cancel(Duration::from_millis(20));
+        let child = parent.child();
+        assert!(child.is_cancelled());
+        assert!(!child.is_aborted());
+        block_on(child.aborted());
+        assert!(parent.is_aborted());
+    }
+}
@@ -0,0 +1,227 @@
+//! Checkpoints let an interrupted run continue where it stopped.
+//!
+//! Every package entering a flow is an invocation, identified by the flow,
+//! its arguments and the package name. The journal in the checkpoint
+//! directory records when invocations start and complete. A resumed run
+//! replays the target from its input and skips invocations which completed
+//! before, so only the unfinished part of the crawl is fetched again.
+//!
+//! An invocation completes once all the work derived from it has run,
+//! including the later steps its results pass through, and its results have
+//! left the target. Skipped invocations produce no outputs; their results
+//! were written by the steps of the previous run, or yielded by its
+//! `TargetRunner::run_stream`. Results collected by `run_report` are only
+//! returned at the end, so an interrupted collected run loses the results of
+//! the invocations it completed.
+use super::context::Args;
+use super::error::CrawlResult;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const JOURNAL_FILE: &'static str = "journal.jsonl";
+
+/// An invocation which started but did not complete
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invocation {
+    pub flow: String,
+    pub args: Args,
+    pub package: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry {
+    Start {
+        key: String,
+        #[serde(flatten)]
+        invocation: Invocation,
+    },
+    Done {
+        key: String,
+    },
+    Finish,
+}
+
+#[derive(Debug)]
+struct State {
+    journal: File,
+    pending: HashMap<String, Invocation>,
+    completed: HashSet<String>,
+}
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    dir: PathBuf,
+    state: Mutex<State>,
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint in `dir`. With `resume` the journal of an
+    /// unfinished previous run is loaded, otherwise a new journal is started.
+    pub fn open<P: AsRef<Path>>(dir: P, resume: bool) -> CrawlResult<Checkpoint> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(JOURNAL_FILE);
+
+        let mut pending = HashMap::new();
+        let mut completed = HashSet::new();
+        let mut finished = false;
+        if resume && path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                // A crash may leave the last line incomplete
+                let entry = match serde_json::from_str::<Entry>(&line?) {
+                    Ok(e) => e,
+                    Err(_) => continue,
+                };
+                match entry {
+                    Entry::Start { key, invocation } => {
+                        pending.insert(key, invocation);
+                    }
+                    Entry::Done { key } => {
+                        pending.remove(&key);
+                        completed.insert(key);
+                    }
+                    Entry::Finish => finished = true,
+                }
+            }
+        }
+
+        if finished || !resume {
+            pending.clear();
+            completed.clear();
+            File::create(&path)?;
+        }
+
+        let journal = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(Checkpoint {
+            dir,
+            state: Mutex::new(State {
+                journal,
+                pending,
+                completed,
+            }),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Identifies an invocation of `flow`
+    pub fn key(flow: &str, args: &Args, package: &str) -> String {
+        let args = serde_json::to_string(&args.iter().collect::<BTreeMap<_, _>>())
+            .unwrap_or_default();
+        // FNV-1a, which is stable between builds
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for part in &[flow, args.as_str(), package] {
+            for b in part.bytes().chain(Some(0)) {
+                hash ^= u64::from(b);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        format!("{:016x}", hash)
+    }
+
+    pub fn is_completed(&self, key: &str) -> bool {
+        self.state.lock().unwrap().completed.contains(key)
+    }
+
+    /// Invocations left unfinished by the previous run
+    pub fn pending(&self) -> Vec<Invocation> {
+        self.state.lock().unwrap().pending.values().cloned().collect()
+    }
+
+    pub fn completed(&self) -> usize {
+        self.state.lock().unwrap().completed.len()
+    }
+
+    pub fn start(&self, key: &str, invocation: Invocation) -> CrawlResult<()> {
+        let mut state = self.state.lock().unwrap();
+        write(
+            &mut state.journal,
+            &Entry::Start {
+                key: key.to_string(),
+                invocation: invocation.clone(),
+            },
+        )?;
+        state.pending.insert(key.to_string(), invocation);
+        Ok(())
+    }
+
+    pub fn complete(&self, key: &str) -> CrawlResult<()> {
+        let mut state = self.state.lock().unwrap();
+        write(
+            &mut state.journal,
+            &Entry::Done {
+                key: key.to_string(),
+            },
+        )?;
+        state.pending.remove(key);
+        state.completed.insert(key.to_string());
+        Ok(())
+    }
+
+    /// Marks the run as complete, so resuming starts over
+    pub fn finish(&self) -> CrawlResult<()> {
+        let mut state = self.state.lock().unwrap();
+        write(&mut state.journal, &Entry::Finish)
+    }
+}
+
+fn write(journal: &mut File, entry: &Entry) -> CrawlResult<()> {
+    let mut line = serde_json::to_string(entry).unwrap_or_default();
+    line.push('\n');
+    journal.write_all(line.as_bytes())?;
+    journal.flush()?;
+    Ok(())
+}
+
+pub(crate) fn invocation(flow: &str, args: &Args, package: &str) -> Invocation {
+    Invocation {
+        flow: flow.to_string(),
+        args: args.clone(),
+        package: package.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("crawler2-{}-{}", name, uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn resume() {
+        let dir = temp_dir("checkpoint");
+        let args = args! { "page" => 1 };
+        let a = Checkpoint::key("Crawl", &args, "https://loppen.dk/a");
+        let b = Checkpoint::key("Crawl", &args, "https://loppen.dk/b");
+        assert_ne!(a, b);
+        assert_eq!(a, Checkpoint::key("Crawl", &args! { "page" => 1 }, "https://loppen.dk/a"));
+
+        {
+            let checkpoint = Checkpoint::open(&dir, false).unwrap();
+            checkpoint.start(&a, invocation("Crawl", &args, "https://loppen.dk/a")).unwrap();
+            checkpoint.start(&b, invocation("Crawl", &args, "https://loppen.dk/b")).unwrap();
+            checkpoint.complete(&a).unwrap();
+        }
+
+        let checkpoint = Checkpoint::open(&dir, true).unwrap();
+        assert!(checkpoint.is_completed(&a));
+        assert!(!checkpoint.is_completed(&b));
+        assert_eq!(checkpoint.pending()[0].package, "https://loppen.dk/b");
+        checkpoint.finish().unwrap();
+
+        let checkpoint = Checkpoint::open(&dir, true).unwrap();
+        assert_eq!(checkpoint.completed(), 0);
+        assert!(checkpoint.pending().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+//! Content types and character sets of package content.
+//!
+//! Content types come from HTTP headers, from the extension of a name or
+//! are sniffed from the content. Text is decoded to UTF-8 from its declared
+//! charset, or windows-1252 when it is not valid UTF-8, which is what old
+//! sites declaring nothing or ISO-8859-1 mostly use.
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// MIME type of the parameters-less `content_type`, lowercased
+pub fn mime(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_lowercase()
+}
+
+/// The `charset` parameter of `content_type`
+pub fn charset(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let mut parts = param.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("charset") => {
+                Some(value.trim().trim_matches('"'))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Whether content of type `content_type` is text
+pub fn is_text(content_type: &str) -> bool {
+    let mime = mime(content_type);
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || match mime.as_str() {
+            "application/json" | "application/xml" | "application/javascript"
+            | "application/x-javascript" | "application/yaml" | "application/x-yaml"
+            | "application/toml" | "application/csv" => true,
+            _ => false,
+        }
+}
+
+/// The extension of a file name or URL, without the dot
+pub fn extension(name: &str) -> Option<&str> {
+    let path = name.split(|c| c == '?' || c == '#').next().unwrap_or("");
+    let file = path.rsplit('/').next().unwrap_or("");
+    match file.rfind('.') {
+        // A leading dot marks a hidden file
+        Some(i) if i > 0 => Some(&file[i + 1..]),
+        _ => None,
+    }
+}
+
+/// MIME type guessed from the extension of a file name or URL
+pub fn from_extension(name: &str) -> Option<&'static str> {
+    extension(name).and_then(extension_mime)
+}
+
+/// MIME type of files with the extension `ext`
+pub fn extension_mime(ext: &str) -> Option<&'static str> {
+    let mime = match ext.to_lowercase().as_str() {
+        "html" | "htm" => "text/html",
+        "txt" => "text/plain",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+fn starts_with_ignore_case(body: &[u8], prefix: &[u8]) -> bool {
+    body.len() >= prefix.len() && body[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/// MIME type guessed from the first bytes of the content
+pub fn sniff(body: &[u8]) -> Option<&'static str> {
+    if body.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    if body.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if body.starts_with(b"\xff\xd8\xff") {
+        return Some("image/jpeg");
+    }
+    if body.starts_with(b"GIF87a") || body.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+
+    let text = strip_bom(body);
+    let start = text.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(text.len());
+    let text = &text[start..];
+    if starts_with_ignore_case(text, b"<!doctype html") || starts_with_ignore_case(text, b"<html") {
+        return Some("text/html");
+    }
+    if text.starts_with(b"<?xml") {
+        return Some("application/xml");
+    }
+    if (text.starts_with(b"{") || text.starts_with(b"[")) && serde_json::from_slice::<Value>(text).is_ok() {
+        return Some("application/json");
+    }
+    if !body.contains(&0) {
+        return Some("text/plain");
+    }
+    None
+}
+
+fn strip_bom(body: &[u8]) -> &[u8] {
+    if body.starts_with(b"\xef\xbb\xbf") {
+        &body[3..]
+    } else {
+        body
+    }
+}
+
+/// The content type of content named `name`, from the `declared` type, the
+/// extension of the name or the content, in that order
+pub fn detect(name: &str, declared: Option<&str>, body: Option<&[u8]>) -> Option<String> {
+    if let Some(declared) = declared {
+        if !declared.trim().is_empty() {
+            return Some(declared.to_string());
+        }
+    }
+    from_extension(name)
+        .or_else(|| body.and_then(sniff))
+        .map(|m| m.to_string())
+}
+
+/// A charset declared inside an HTML or XML document
+fn declared_charset(body: &[u8]) -> Option<&'static Encoding> {
+    let head = String::from_utf8_lossy(&body[..std::cmp::min(body.len(), 1024)]).to_lowercase();
+    let i = head.find("charset=").or_else(|| head.find("encoding="))?;
+    let value = head[i..].splitn(2, '=').nth(1)?;
+    let value = value.trim_start_matches(|c| c == '"' || c == '\'');
+    let end = value
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(value.len());
+    Encoding::for_label(value[..end].as_bytes())
+}
+
+/// Decodes text to UTF-8. A byte order mark wins over a charset declared in
+/// `content_type`, which wins over one declared in an HTML or XML document.
+/// Without any the text is UTF-8 when valid, windows-1252 otherwise.
+pub fn decode<'a>(body: &'a [u8], content_type: Option<&str>) -> Cow<'a, str> {
+    let markup = content_type
+        .map(|c| mime(c).contains("html") || mime(c).contains("xml"))
+        .unwrap_or(true);
+    let encoding = content_type
+        .and_then(charset)
+        .and_then(|c| Encoding::for_label(c.as_bytes()))
+        .or_else(|| if markup { declared_charset(body) } else { None })
+        .unwrap_or_else(|| {
+            if std::str::from_utf8(strip_bom(body)).is_ok() {
+                UTF_8
+            } else {
+                WINDOWS_1252
+            }
+        });
+    let (text, _, _) = encoding.decode(body);
+    text
+}
+
+/// Decodes text to UTF-8 like `decode`. An HTML or XML document declaring
+/// another charset is changed to declare UTF-8, so it stays readable when
+/// saved.
+pub fn to_utf8(body: &[u8], content_type: Option<&str>) -> String {
+    let text = decode(body, content_type);
+    let markup = content_type
+        .map(|c| mime(c).contains("html") || mime(c).contains("xml"))
+        .unwrap_or(false);
+    if !markup {
+        return text.into_owned();
+    }
+    declare_utf8(&text).into_owned()
+}
+
+/// Replaces the charset declared inside an HTML or XML document by UTF-8
+fn declare_utf8(text: &str) -> Cow<str> {
+    let mut end = std::cmp::min(text.len(), 1024);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    // ASCII lowercasing keeps the offsets of the text
+    let head = text[..end].to_ascii_lowercase();
+    let start = match head
+        .find("charset=")
+        .map(|i| i + "charset=".len())
+        .or_else(|| head.find("encoding=").map(|i| i + "encoding=".len()))
+    {
+        Some(i) => i,
+        None => return Cow::Borrowed(text),
+    };
+    let start = match head[start..].chars().next() {
+        Some('"') | Some('\'') => start + 1,
+        _ => start,
+    };
+    let len = head[start..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(head.len() - start);
+    if &head[start..start + len] == "utf-8" {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(format!("{}utf-8{}", &text[..start], &text[start + len..]))
+}
+
+/// Sets the charset parameter of `content_type` to UTF-8
+pub fn with_utf8(content_type: &str) -> String {
+    format!("{}; charset=utf-8", mime(content_type))
+}
+
+/// Parses content as JSON, decoding text in other charsets first
+pub fn json(body: &[u8], content_type: Option<&str>) -> Option<Value> {
+    if let Ok(value) = serde_json::from_slice(body) {
+        return Some(value);
+    }
+    serde_json::from_str(&decode(body, content_type)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn content_types() {
+        assert_eq!(charset("text/html; charset=\"ISO-8859-1\""), Some("ISO-8859-1"));
+        assert_eq!(charset("text/html"), None);
+        assert!(is_text("application/ld+json"));
+        assert!(!is_text("image/png"));
+        assert_eq!(from_extension("https://loppen.dk/program.json?page=2"), Some("application/json"));
+        assert_eq!(from_extension("https://loppen.dk/"), None);
+        assert_eq!(extension("concerts/loppen.yml"), Some("yml"));
+        assert_eq!(extension(".hidden"), None);
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), Some("text/html"));
+        assert_eq!(sniff(br#"[{"id": 1}]"#), Some("application/json"));
+        assert_eq!(
+            detect("https://loppen.dk/", None, Some(b"<html>")).as_ref().map(|s| s.as_str()),
+            Some("text/html")
+        );
+    }
+
+    #[test]
+    fn charsets() {
+        // "Koncert på Loppen" in ISO-8859-1
+        let latin1 = b"Koncert p\xe5 Loppen";
+        assert_eq!(decode(latin1, Some("text/plain; charset=iso-8859-1")), "Koncert på Loppen");
+        assert_eq!(decode(latin1, None), "Koncert på Loppen");
+        assert_eq!(decode("Koncert på Loppen".as_bytes(), None), "Koncert på Loppen");
+
+        let html = b"<html><head><meta charset=\"windows-1252\"></head><body>\xe6\xf8\xe5</body></html>";
+        assert!(decode(html, Some("text/html")).contains("æøå"));
+        assert_eq!(with_utf8("text/html; charset=iso-8859-1"), "text/html; charset=utf-8");
+
+        // The declared charset follows the re-encoded text
+        assert_eq!(
+            to_utf8(html, Some("text/html")),
+            "<html><head><meta charset=\"utf-8\"></head><body>æøå</body></html>"
+        );
+        let xml = b"<?xml version=\"1.0\" encoding='ISO-8859-1'?><a>\xe5</a>";
+        assert_eq!(
+            to_utf8(xml, Some("application/xml")),
+            "<?xml version=\"1.0\" encoding='utf-8'?><a>å</a>"
+        );
+        assert_eq!(to_utf8(b"charset=latin1", Some("text/plain")), "charset=latin1");
+    }
+}
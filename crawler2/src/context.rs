@@ -1,12 +1,23 @@
 use super::error::{CrawlErrorKind, CrawlResult, CrawlError};
+use super::descriptions::flow_namespace;
 use super::target::Target;
-use super::utils::interpolate;
-use super::work::WorkBox;
+use super::environment::Environment;
+use super::cancel::Cancellation;
+use super::checkpoint::Checkpoint;
+use super::events::Events;
+use super::report::Reporter;
+use super::scheduler::Scheduler;
+use super::utils::{try_interpolate_secrets, WorkArcWrapper};
+use super::work::{WorkBox, WorkOutput};
+use conveyor::futures::future::{self, Future};
+use conveyor::{into_box, Station};
 use conveyor_work::package::Package;
 use serde_json::Value;
 use slog::{FnValue, Logger};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 pub type Args = HashMap<String, Value>;
 use uuid::Uuid;
 
@@ -21,6 +32,8 @@ pub struct Context {
     id: Uuid,
     args: Option<Args>,
     logger: Option<Logger>,
+    namespace: Option<String>,
+    path: Option<String>,
     parent: ParentOrRoot,
 }
 
@@ -31,6 +44,8 @@ impl Context {
             parent,
             args,
             logger,
+            namespace: None,
+            path: None,
         }
     }
 
@@ -55,25 +70,31 @@ impl Context {
         }
     }
 
-    pub fn interpolate(&self, name: &str) -> Option<String> {
+    pub fn interpolate(&self, name: &str) -> CrawlResult<String> {
         let args = self.all_args();
         info!(self.log(), "interpolate"; "text" => name, "args" => FnValue(|_| serde_json::to_string(&args).unwrap()));
 
-        // let temp = interpolate(name, self.args());
-        // match &self.parent {
-        //     ParentOrRoot::Parent(p) => p.interpolate(&temp),
-        //     ParentOrRoot::Root(r) => r.interpolate(&temp),
-        // }
-        Some(interpolate(name, &args))
+        try_interpolate_secrets(name, &args, self.env().secrets())
     }
 
-    pub fn interpolate_with(&self, text: &str, args: &Args) -> String {
+    pub fn interpolate_with(&self, text: &str, args: &Args) -> CrawlResult<String> {
         let mut oargs = self.all_args();
         for o in args.iter() {
             oargs.insert(o.0.clone(), o.1.clone());
         }
         info!(self.log(), "interpolate"; "text" => text, "args" => FnValue(|_| serde_json::to_string(&oargs).unwrap()));
-        interpolate(text, &oargs)
+        try_interpolate_secrets(text, &oargs, self.env().secrets())
+    }
+
+    pub fn target(&self) -> &Target {
+        match &self.parent {
+            ParentOrRoot::Parent(p) => p.target(),
+            ParentOrRoot::Root(r) => r.target(),
+        }
+    }
+
+    pub fn env(&self) -> &Environment {
+        self.target().env()
     }
 
     pub fn root(&mut self) -> &mut RootContext {
@@ -83,6 +104,68 @@ impl Context {
         }
     }
 
+    /// Namespace of the imported flow the context belongs to, if any
+    pub fn namespace(&self) -> Option<&str> {
+        match &self.namespace {
+            None => match &self.parent {
+                ParentOrRoot::Parent(p) => p.namespace(),
+                ParentOrRoot::Root(_) => None,
+            },
+            Some(s) => Some(s.as_str()),
+        }
+    }
+
+    /// Path of the current step in the description, e.g. `flows[Crawl].work[1]`
+    pub fn path(&self) -> &str {
+        match &self.path {
+            None => match &self.parent {
+                ParentOrRoot::Parent(p) => p.path(),
+                ParentOrRoot::Root(_) => "",
+            },
+            Some(s) => s.as_str(),
+        }
+    }
+
+    pub fn with_path<S: Into<String>>(mut self, path: S) -> Context {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn reporter(&self) -> &Reporter {
+        match &self.parent {
+            ParentOrRoot::Parent(p) => p.reporter(),
+            ParentOrRoot::Root(r) => r.reporter(),
+        }
+    }
+
+    pub fn events(&self) -> &Events {
+        match &self.parent {
+            ParentOrRoot::Parent(p) => p.events(),
+            ParentOrRoot::Root(r) => r.events(),
+        }
+    }
+
+    pub fn cancellation(&self) -> &Cancellation {
+        match &self.parent {
+            ParentOrRoot::Parent(p) => p.cancellation(),
+            ParentOrRoot::Root(r) => r.cancellation(),
+        }
+    }
+
+    pub fn checkpoint(&self) -> Option<&Arc<Checkpoint>> {
+        match &self.parent {
+            ParentOrRoot::Parent(p) => p.checkpoint(),
+            ParentOrRoot::Root(r) => r.checkpoint(),
+        }
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        match &self.parent {
+            ParentOrRoot::Parent(p) => p.scheduler(),
+            ParentOrRoot::Root(r) => r.scheduler(),
+        }
+    }
+
     pub fn log(&self) -> &Logger {
         match &self.logger {
             None => match &self.parent {
@@ -101,6 +184,8 @@ impl Context {
         Context {
             id: id,
             logger: Some(logger),
+            namespace: None,
+            path: None,
             parent: ParentOrRoot::Parent(Box::new(self.clone())),
             args,
         }
@@ -121,15 +206,22 @@ impl Context {
         args
     }
 
+    /// Returns the station of a flow, compiled once per run for the same arguments
     pub fn flow(&mut self, name: &str, args: Args) -> CrawlResult<WorkBox<Package>> {
-        let clone = self.root().target().description().clone();
-        let found = match clone.flows.iter().find(|m| m.name == name) {
+        let target = self.root().target().clone();
+        let (name, found) = match target.flow(self.namespace(), name) {
             Some(m) => m,
             None => return Err(CrawlErrorKind::NotFound(name.to_string()).into()),
         };
 
-        //let mut ctx = self.
-        found.build(&args, self)
+        let mut all = self.all_args();
+        all.extend(args.clone());
+        let key = FlowCache::key(&name, &all);
+
+        let mut ctx = Context::new(ParentOrRoot::Parent(Box::new(self.clone())), None, None);
+        ctx.namespace = flow_namespace(&name).map(|m| m.to_string());
+        let flows = self.root().flows().clone();
+        flows.get_or_build(key, || found.build(&args, &mut ctx))
     }
 }
 
@@ -138,6 +230,12 @@ struct RootInner {
     id: Uuid,
     args: Args,
     target: Target,
+    reporter: Reporter,
+    events: Events,
+    cancellation: Cancellation,
+    checkpoint: Option<Arc<Checkpoint>>,
+    scheduler: Scheduler,
+    flows: FlowCache,
 }
 
 #[derive(Clone, Debug)]
@@ -147,13 +245,29 @@ pub struct RootContext {
 
 impl RootContext {
     pub fn new(target: Target, args: Args) -> RootContext {
+        RootContext::with_checkpoint(target, args, None)
+    }
+
+    /// Creates a context whose flows record their progress in `checkpoint`
+    pub fn with_checkpoint(target: Target, args: Args, checkpoint: Option<Arc<Checkpoint>>) -> RootContext {
         let id = Uuid::new_v4();
 
+        let reporter = Reporter::new(&target.description().name);
+        let events = target.env().events().child();
+        let cancellation = target.env().cancellation().child();
+        let scheduler = Scheduler::from_config(target.env().config());
+
         RootContext {
             inner: Arc::new(RootInner {
                 id: id,
                 target: target,
                 args: args,
+                reporter: reporter,
+                events: events,
+                cancellation: cancellation,
+                checkpoint: checkpoint,
+                scheduler: scheduler,
+                flows: FlowCache::default(),
             }),
         }
     }
@@ -166,21 +280,53 @@ impl RootContext {
         &self.inner.target
     }
 
+    pub fn reporter(&self) -> &Reporter {
+        &self.inner.reporter
+    }
+
+    /// Events of this run, which are also emitted on the environment
+    pub fn events(&self) -> &Events {
+        &self.inner.events
+    }
+
+    /// Cancels this run, and is cancelled with the environment
+    pub fn cancellation(&self) -> &Cancellation {
+        &self.inner.cancellation
+    }
+
+    pub fn checkpoint(&self) -> Option<&Arc<Checkpoint>> {
+        self.inner.checkpoint.as_ref()
+    }
+
+    /// Runs the work of this target, see `Scheduler`
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.inner.scheduler
+    }
+
+    pub(crate) fn flows(&self) -> &FlowCache {
+        &self.inner.flows
+    }
+
     pub fn resolve_path<S: AsRef<str>>(&self, path: S) -> CrawlResult<String> {
         let p = self.inner.target.path().to_str().unwrap();
         pathutils::resolve(p, path).map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e))))
     }
 
     pub fn flow(&mut self, name: &str, args: Args) -> CrawlResult<WorkBox<Package>> {
-        let clone = self.inner.target.description().clone();
-        let found = match clone.flows.iter().find(|m| m.name == name) {
+        let target = self.inner.target.clone();
+        let (name, found) = match target.flow(None, name) {
             Some(m) => m,
             None => return Err(CrawlErrorKind::NotFound(name.to_string()).into()),
         };
 
+        let mut all = self.all_args();
+        all.extend(args.clone());
+        let key = FlowCache::key(&name, &all);
+
         let mut ctx = Context::new(ParentOrRoot::Root(self.clone()), None, None);
+        ctx.namespace = flow_namespace(&name).map(|m| m.to_string());
 
-        found.build(&args, &mut ctx)
+        self.inner.flows.get_or_build(key, || found.build(&args, &mut ctx))
     }
 
     pub fn args(&self) -> &Args {
@@ -195,9 +341,10 @@ impl RootContext {
         args
     }
 
-    pub fn interpolate(&self, name: &str) -> Option<String> {
+    pub fn interpolate(&self, name: &str) -> CrawlResult<String> {
         debug!(self.target().env().log(), "interpolate"; "text" => name, "args" => FnValue(|_| serde_json::to_string(&self.inner.args).unwrap()));
-        Some(interpolate(name, &self.inner.args))
+        let env = self.target().env();
+        try_interpolate_secrets(name, &self.all_args(), env.secrets())
     }
 
     pub fn child(&self, name: &str, args: Option<Args>) -> Context {
@@ -210,8 +357,135 @@ impl RootContext {
                     .log()
                     .new(o!("context" => format!("{}({})",name.to_string(), id))),
             ),
+            namespace: None,
+            path: None,
             parent: ParentOrRoot::Root(self.clone()),
             args,
         }
     }
 }
+
+/// Flows compiled during a run, keyed by flow name and arguments.
+/// `None` marks a flow which is being compiled.
+#[derive(Clone, Default)]
+pub(crate) struct FlowCache {
+    flows: Arc<Mutex<HashMap<String, Option<Arc<WorkBox<Package>>>>>>,
+}
+
+impl FlowCache {
+    fn key(name: &str, args: &Args) -> String {
+        let args = args.iter().collect::<BTreeMap<_, _>>();
+        format!("{}{}", name, serde_json::to_string(&args).unwrap_or_default())
+    }
+
+    /// Returns the flow compiled for `key`, compiling it with `build` first if
+    /// needed. A flow referring to itself while it is compiled gets a station
+    /// which looks it up when it runs.
+    fn get_or_build<F: FnOnce() -> CrawlResult<WorkBox<Package>>>(
+        &self,
+        key: String,
+        build: F,
+    ) -> CrawlResult<WorkBox<Package>> {
+        {
+            let mut flows = self.flows.lock().unwrap();
+            match flows.get(&key) {
+                Some(Some(station)) => return Ok(into_box(WorkArcWrapper::new(station.clone()))),
+                Some(None) => {
+                    return Ok(into_box(LazyFlow {
+                        cache: self.clone(),
+                        key,
+                    }))
+                }
+                None => {
+                    flows.insert(key.clone(), None);
+                }
+            }
+        }
+
+        match build() {
+            Ok(station) => {
+                let station = Arc::new(station);
+                self.flows.lock().unwrap().insert(key, Some(station.clone()));
+                Ok(into_box(WorkArcWrapper::new(station)))
+            }
+            Err(e) => {
+                self.flows.lock().unwrap().remove(&key);
+                Err(e)
+            }
+        }
+    }
+
+    /// Drops the compiled flows, which hold on to the context of the run
+    pub(crate) fn clear(&self) {
+        self.flows.lock().unwrap().clear();
+    }
+}
+
+impl fmt::Debug for FlowCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FlowCache({} flows)", self.flows.lock().unwrap().len())
+    }
+}
+
+struct LazyFlow {
+    cache: FlowCache,
+    key: String,
+}
+
+impl Station for LazyFlow {
+    type Input = Package;
+    type Output = Vec<WorkOutput<Package>>;
+    type Future = Pin<Box<Future<Output = conveyor::Result<Self::Output>> + Send>>;
+
+    fn execute(&self, input: Self::Input) -> Self::Future {
+        let station = match self.cache.flows.lock().unwrap().get(&self.key) {
+            Some(Some(station)) => station.clone(),
+            _ => {
+                let e = CrawlError::new(CrawlErrorKind::NotFound(format!("flow: {}", self.key)));
+                return Box::pin(future::ready(Err(e.into())));
+            }
+        };
+        station.execute(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use conveyor::futures::executor::block_on;
+    use conveyor::station_fn;
+    use std::cell::Cell;
+
+    fn echo() -> WorkBox<Package> {
+        into_box(station_fn(async move |package: Package| {
+            Ok(vec![WorkOutput::Result(Ok(package))])
+        }))
+    }
+
+    #[test]
+    fn flow_cache() {
+        let cache = FlowCache::default();
+        let key = FlowCache::key("Crawl", &args! { "script" => "index.js" });
+        assert_ne!(key, FlowCache::key("Crawl", &args! { "script" => "concert.js" }));
+
+        let builds = Cell::new(0);
+        let lazy = Cell::new(None);
+        cache
+            .get_or_build(key.clone(), || {
+                builds.set(builds.get() + 1);
+                // A flow referring to itself gets a station resolved when it runs
+                lazy.set(Some(cache.get_or_build(key.clone(), || unreachable!())?));
+                Ok(echo())
+            })
+            .unwrap();
+        cache.get_or_build(key.clone(), || unreachable!()).unwrap();
+        assert_eq!(builds.get(), 1);
+
+        let ret = block_on(lazy.take().unwrap().execute(Package::new("page", "body"))).unwrap();
+        assert_eq!(ret.len(), 1);
+
+        cache.clear();
+        assert!(block_on(LazyFlow { cache: cache.clone(), key }.execute(Package::new("page", "body"))).is_err());
+    }
+}
@@ -1,14 +1,17 @@
+use super::super::checkpoint::{self, Checkpoint};
 use super::super::context::{Args, Context};
 use super::super::error::CrawlResult;
-use super::super::utils::station_fn_ctx2;
-use super::super::utils::{WorkArcWrapper, WorkBoxWrapper};
-use super::super::work::{Work, WorkBox, WorkOutput, Worker};
+use super::super::events::Event;
+use super::super::metrics;
+use super::super::utils::{station_fn_ctx2, WorkBoxWrapper};
+use super::super::work::{and_then, Completion, WorkBox, WorkOutput};
 use conveyor::{into_box, Chain};
 use conveyor_work::package::Package;
 use slog::FnValue;
 use std::sync::Arc;
 use std::time::Instant;
 
+use super::validate::ValidationErrors;
 use super::work_description::*;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -19,57 +22,427 @@ pub struct FlowDescription {
 
 impl FlowDescription {
     pub fn build(&self, args: &Args, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
-        let mut flow_ctx = ctx.child(&format!("Flow({})", self.name), Some(args.clone()));
+        if self.work.is_empty() {
+            return Err(ValidationErrors::single(format!("flows[{}].work", self.name), "no steps defined").into());
+        }
+
+        let path = format!("flows[{}]", self.name);
+        let flow_ctx = ctx
+            .child(&format!("Flow({})", self.name), Some(args.clone()))
+            .with_path(path.as_str());
+
+        // Imported flows are reported and checkpointed under their namespace
+        let qualified = match ctx.namespace() {
+            Some(ns) => format!("{}.{}", ns, self.name),
+            None => self.name.clone(),
+        };
 
         let start = Instant::now();
         info!(flow_ctx.log(),"building flow"; "steps" => self.work.len(), "args" => serde_json::to_string(args).unwrap());
 
-        let mut work = self.work[0].request_station(&mut flow_ctx).unwrap();
-        for w in self.work.iter().skip(1) {
-            let ww = w.request_station(&mut flow_ctx).unwrap();
+        let mut work =
+            self.work[0].request_station(&mut flow_ctx.clone().with_path(format!("{}.work[0]", path)))?;
+        for (i, w) in self.work.iter().enumerate().skip(1) {
+            let ww = w.request_station(&mut flow_ctx.clone().with_path(format!("{}.work[{}]", path, i)))?;
             work = into_box(WorkBoxWrapper::new(work).pipe(station_fn_ctx2(
-                async move |pack: Vec<WorkOutput<Package>>, ctx: Arc<WorkBox<Package>>| {
-                    //
-                    let mut v = Vec::new();
-                    let mut out = Vec::new();
-                    for p in pack {
-                        let p = match p {
-                            WorkOutput::Result(e) => match e {
-                                Ok(o) => Work::new(o, WorkArcWrapper::new(ctx.clone())),
-                                Err(e) => {
-                                    out.push(Err(e));
-                                    continue;
-                                }
-                            },
-                            WorkOutput::Work(e) => e.chain(WorkArcWrapper::new(ctx.clone())),
-                            WorkOutput::Then(_) => unimplemented!("not chain"),
-                        };
-                        v.push(p);
-                    }
-
-                    let w = Worker::new();
-                    let mut ret = await!(w.run(v));
-                    out.extend(ret);
-                    Ok(out
-                        .into_iter()
-                        .map(|m| WorkOutput::Result(m))
-                        .collect::<Vec<_>>())
+                async move |pack: Vec<WorkOutput<Package>>, ctx: Arc<Arc<WorkBox<Package>>>| {
+                    Ok(and_then(pack, &ctx))
                 },
-                Arc::new(ww),
+                Arc::new(Arc::new(ww)),
             )));
         }
 
         info!(flow_ctx.log(), "flow finished";  "time" => FnValue(move |_| format!("{:?}",start.elapsed())));
 
         Ok(into_box(station_fn_ctx2(
-            async move |pack: Package, ctx: Arc<(Context, WorkBox<Package>)>| {
+            async move |pack: Package, ctx: Arc<(Context, WorkBox<Package>, String, Args)>| {
                 info!(ctx.0.log(), "flow started");
+                let events = ctx.0.events();
+                if events.is_observed() {
+                    events.emit(Event::FlowEntered {
+                        target: ctx.0.target().description().name.clone(),
+                        flow: ctx.2.clone(),
+                        args: ctx.3.clone(),
+                    });
+                }
+                let key = match ctx.0.checkpoint() {
+                    Some(c) => {
+                        let flow = &ctx.2;
+                        let key = Checkpoint::key(flow, &ctx.3, pack.name());
+                        if c.is_completed(&key) {
+                            info!(ctx.0.log(), "flow completed in previous run"; "package" => pack.name());
+                            return Ok(Vec::new());
+                        }
+                        c.start(&key, checkpoint::invocation(flow, &ctx.3, pack.name()))?;
+                        Some(key)
+                    }
+                    None => None,
+                };
+
                 let now = Instant::now();
-                let ret = await!(ctx.1.execute(pack));
-                info!(ctx.0.log(), "flow executed"; "time" => FnValue(move |_| format!("{:?}",now.elapsed())));
+                let mut ret = await!(ctx.1.execute(pack));
+                if let (Some(key), Ok(outputs)) = (key, &mut ret) {
+                    // The invocation is complete once its results have passed
+                    // the remaining steps and left the target
+                    let checkpoint = ctx.0.checkpoint().unwrap().clone();
+                    let log = ctx.0.log().clone();
+                    let completion = Completion::new(move |complete| {
+                        if !complete {
+                            return;
+                        }
+                        if let Err(e) = checkpoint.complete(&key) {
+                            warn!(log, "could not update checkpoint"; "error" => e.to_string());
+                        }
+                    });
+                    for o in outputs.drain(..).collect::<Vec<_>>() {
+                        outputs.push(o.complete_with(&completion));
+                    }
+                    completion.release();
+                }
+                let elapsed = now.elapsed();
+                info!(ctx.0.log(), "flow executed"; "time" => FnValue(move |_| format!("{:?}", elapsed)));
+                let (outputs, errors) = match &ret {
+                    Ok(ret) => {
+                        let errors = ret.iter().filter(|m| match m {
+                            WorkOutput::Result(Err(_)) => true,
+                            _ => false,
+                        }).count();
+                        (ret.len() - errors, errors)
+                    }
+                    Err(_) => (0, 1),
+                };
+                ctx.0.reporter().flow(&ctx.2, elapsed, outputs, errors);
+                let target = ctx.0.target().description().name.as_str();
+                ctx.0
+                    .env()
+                    .metrics()
+                    .observe(&metrics::FLOW_DURATION, &[target, &ctx.2], elapsed);
                 ret
             },
-            Arc::new((flow_ctx, work)),
+            Arc::new((flow_ctx, work, qualified, args.clone())),
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::super::context::{ParentOrRoot, RootContext};
+    use super::super::super::error::CrawlErrorKind;
+    use super::super::super::prelude::*;
+    use super::super::super::work::{WorkBox, WorkOutput};
+    use super::*;
+    use conveyor::futures::executor::block_on;
+    use conveyor::{into_box, station_fn};
+    use slog::{Discard, Logger};
+
+    /// A step emitting the outputs `f` returns for the name of its input
+    fn step(f: fn(&str) -> Vec<WorkOutput<Package>>) -> Box<WorkType> {
+        let service: WorkBox<Package> =
+            into_box(station_fn(async move |package: Package| Ok(f(package.name()))));
+        Box::new(worktypes::PassThrough {
+            service: Some(Arc::new(service)),
+        })
+    }
+
+    fn flow(name: &str) -> Box<WorkType> {
+        Box::new(worktypes::Flow {
+            flow_name: name.to_string(),
+            arguments: None,
+        })
+    }
+
+    fn work(work: Box<WorkType>, then: Option<Box<WorkType>>) -> WorkDescription {
+        WorkDescription {
+            work,
+            then,
+            when: None,
+            retries: None,
+            on_error: None,
+        }
+    }
+
+    fn run(steps: Vec<WorkDescription>, flows: Vec<FlowDescription>) -> Vec<String> {
+        let mut names = run_results(steps, flows)
+            .into_iter()
+            .map(|m| m.unwrap().name().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn run_results(steps: Vec<WorkDescription>, flows: Vec<FlowDescription>) -> Vec<CrawlResult<Package>> {
+        let env = Environment::build("/", Logger::root(Discard, o!())).build();
+        let desc = TargetDescription {
+            name: "Test".to_string(),
+            imports: Vec::new(),
+            work: WorkTargetDescription {
+                input: serde_json::Value::String("https://loppen.dk".to_string()),
+                steps,
+            },
+            flows,
+        };
+        let runner = Target::new("/", env, desc).unwrap().build(Args::new()).unwrap();
+        block_on(runner.run()).unwrap()
+    }
+
+    #[test]
+    fn empty_flow() {
+        let env = Environment::build("/", Logger::root(Discard, o!())).build();
+        let desc = TargetDescription {
+            name: "Test".to_string(),
+            imports: Vec::new(),
+            work: WorkTargetDescription {
+                input: serde_json::Value::Null,
+                steps: Vec::new(),
+            },
+            flows: Vec::new(),
+        };
+        let target = Target::new("/", env, desc).unwrap();
+        let mut ctx = Context::new(ParentOrRoot::Root(RootContext::new(target, Args::new())), None, None);
+        let flow = FlowDescription {
+            name: "Empty".to_string(),
+            work: Vec::new(),
+        };
+        match flow.build(&Args::new(), &mut ctx) {
+            Err(e) => match e.kind() {
+                CrawlErrorKind::Validation(errors) => {
+                    assert_eq!(errors.to_string(), "flows[Empty].work: no steps defined")
+                }
+                kind => panic!("unexpected error: {:?}", kind),
+            },
+            Ok(_) => panic!("empty flow built"),
+        }
+    }
+
+    #[test]
+    fn then_in_called_flow() {
+        // Outer hands its `Then` to Inner, whose `Then` has no `then` of its
+        // own and goes on to the step after Outer
+        let names = run(
+            vec![
+                work(flow("Outer"), None),
+                work(step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("end:{}", n), "")))]), None),
+            ],
+            vec![
+                FlowDescription {
+                    name: "Outer".to_string(),
+                    work: vec![work(
+                        step(|n| {
+                            vec![
+                                WorkOutput::Result(Ok(Package::new(&format!("{}/o0", n), ""))),
+                                WorkOutput::Then(Package::new(&format!("{}/t", n), "")),
+                            ]
+                        }),
+                        Some(flow("Inner")),
+                    )],
+                },
+                FlowDescription {
+                    name: "Inner".to_string(),
+                    work: vec![work(
+                        step(|n| vec![WorkOutput::Then(Package::new(&format!("{}/i0", n), ""))]),
+                        None,
+                    )],
+                },
+            ],
+        );
+        assert_eq!(names, vec!["end:Test/o0", "end:Test/t/i0"]);
+    }
+
+    #[test]
+    fn then_reaches_calling_step() {
+        let names = run(
+            vec![work(flow("Outer"), None)],
+            vec![
+                FlowDescription {
+                    name: "Outer".to_string(),
+                    work: vec![work(
+                        flow("Inner"),
+                        Some(step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("caught:{}", n), "")))])),
+                    )],
+                },
+                FlowDescription {
+                    name: "Inner".to_string(),
+                    work: vec![work(
+                        step(|n| {
+                            vec![
+                                WorkOutput::Result(Ok(Package::new(&format!("{}/r", n), ""))),
+                                WorkOutput::Then(Package::new(&format!("{}/i0", n), "")),
+                            ]
+                        }),
+                        None,
+                    )],
+                },
+            ],
+        );
+        assert_eq!(names, vec!["Test/r", "caught:Test/i0"]);
+    }
+
+    #[test]
+    fn then_without_receiver() {
+        let names = run(
+            vec![work(flow("Inner"), None)],
+            vec![FlowDescription {
+                name: "Inner".to_string(),
+                work: vec![work(
+                    step(|n| vec![WorkOutput::Then(Package::new(&format!("{}/i0", n), ""))]),
+                    None,
+                )],
+            }],
+        );
+        assert_eq!(names, vec!["Test/i0"]);
+    }
+
+    #[test]
+    fn retries_and_on_error() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut failing = work(
+            step(|_| {
+                ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+                vec![WorkOutput::Result(Err(CrawlErrorKind::NotFound("page".to_string()).into()))]
+            }),
+            None,
+        );
+        failing.retries = Some(2);
+        // The handler fails too, so what it received shows up in the results
+        failing.on_error = Some(vec![work(
+            step(|n| vec![WorkOutput::Result(Err(CrawlErrorKind::NotFound(format!("handled:{}", n)).into()))]),
+            None,
+        )]);
+
+        let results = run_results(vec![failing], Vec::new());
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3);
+        assert_eq!(results.len(), 1);
+        let error = results.into_iter().next().unwrap().err().unwrap();
+        assert_eq!(error.to_string(), "CrawlError<NotFound(handled:Test)>");
+        assert_eq!(error.path().unwrap(), "Test > steps[0].on_error[0] (PassThrough)");
+
+        // What a handler emits continues after the failed step
+        let mut handled = work(
+            step(|_| vec![WorkOutput::Result(Err(CrawlErrorKind::NotFound("page".to_string()).into()))]),
+            None,
+        );
+        handled.on_error = Some(vec![work(
+            step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("handled:{}", n), "")))]),
+            None,
+        )]);
+        let next = work(step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("next:{}", n), "")))]), None);
+        assert_eq!(run(vec![handled, next], Vec::new()), vec!["next:handled:Test"]);
+    }
+
+    #[test]
+    fn retries_keep_metadata() {
+        use std::sync::Mutex;
+        lazy_static! {
+            static ref SEEN: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
+        }
+
+        let emit = work(
+            step(|_| vec![WorkOutput::Result(Ok(Package::new("page", "").with_content_type("text/html")))]),
+            None,
+        );
+        let service: WorkBox<Package> = into_box(station_fn(async move |package: Package| {
+            let attempts = {
+                let mut seen = SEEN.lock().unwrap();
+                seen.push(package.content_type().map(|c| c.to_string()));
+                seen.len()
+            };
+            Ok(if attempts < 3 {
+                vec![WorkOutput::Result(Err(CrawlErrorKind::NotFound("page".to_string()).into()))]
+            } else {
+                vec![WorkOutput::Result(Ok(package))]
+            })
+        }));
+        let mut flaky = work(
+            Box::new(worktypes::PassThrough {
+                service: Some(Arc::new(service)),
+            }),
+            None,
+        );
+        flaky.retries = Some(2);
+
+        assert_eq!(run(vec![emit, flaky], Vec::new()), vec!["page"]);
+        assert_eq!(*SEEN.lock().unwrap(), vec![Some("text/html".to_string()); 3]);
+    }
+
+    #[test]
+    fn checkpoint_completes_at_end_of_target() {
+        use std::sync::Mutex;
+        lazy_static! {
+            static ref DIR: std::path::PathBuf =
+                std::env::temp_dir().join(format!("crawler2-flow-{}", uuid::Uuid::new_v4()));
+            static ref DONE: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+        }
+        fn done() -> usize {
+            std::fs::read_to_string(DIR.join(checkpoint::JOURNAL_FILE))
+                .unwrap()
+                .lines()
+                .filter(|l| l.contains(r#""op":"done""#))
+                .count()
+        }
+
+        let steps = vec![
+            work(flow("Inner"), None),
+            work(
+                step(|n| {
+                    DONE.lock().unwrap().push(done());
+                    vec![WorkOutput::Result(Ok(Package::new(&format!("end:{}", n), "")))]
+                }),
+                None,
+            ),
+        ];
+        let flows = vec![FlowDescription {
+            name: "Inner".to_string(),
+            work: vec![work(step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("{}/i", n), "")))]), None)],
+        }];
+        let env = Environment::build("/", Logger::root(Discard, o!())).build();
+        let desc = TargetDescription {
+            name: "Test".to_string(),
+            imports: Vec::new(),
+            work: WorkTargetDescription {
+                input: serde_json::Value::String("https://loppen.dk".to_string()),
+                steps,
+            },
+            flows,
+        };
+        let checkpoint = Checkpoint::open(&*DIR, false).unwrap();
+        let runner = Target::new("/", env, desc).unwrap().build_resumable(Args::new(), checkpoint).unwrap();
+        let names = block_on(runner.run())
+            .unwrap()
+            .into_iter()
+            .map(|m| m.unwrap().name().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, vec!["end:Test/i"]);
+        // The result of Inner was still on its way when the next step ran
+        assert_eq!(*DONE.lock().unwrap(), vec![0]);
+        assert_eq!(done(), 1);
+        std::fs::remove_dir_all(&*DIR).unwrap();
+    }
+
+    #[test]
+    fn when() {
+        let emit = work(
+            step(|_| {
+                vec![
+                    WorkOutput::Result(Ok(Package::new("a.json", ""))),
+                    WorkOutput::Result(Ok(Package::new("b.html", ""))),
+                ]
+            }),
+            None,
+        );
+
+        // Packages not matching `when` skip the step
+        let mut only_json = work(
+            step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("json:{}", n), "")))]),
+            None,
+        );
+        only_json.when = Some(Predicate {
+            name: Some(r"\.json$".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(run(vec![emit, only_json], Vec::new()), vec!["b.html", "json:a.json"]);
+    }
+}
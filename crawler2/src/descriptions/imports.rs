@@ -0,0 +1,206 @@
+use super::super::error::{CrawlError, CrawlErrorKind, CrawlResult};
+use super::flow_description::FlowDescription;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportDescription {
+    /// Path of the file, relative to the importing file
+    pub path: String,
+    /// Namespace the flows are imported under, defaults to the file stem
+    #[serde(rename = "as", skip_serializing_if = "Option::is_none", default)]
+    pub namespace: Option<String>,
+}
+
+/// A file holding shared flows, which can import other files in turn
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FlowLibrary {
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub imports: Vec<ImportDescription>,
+    pub flows: Vec<FlowDescription>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportedFlow {
+    /// Fully qualified name, e.g. `common.Crawl`
+    pub name: String,
+    pub flow: FlowDescription,
+}
+
+/// Reads a JSON or YAML description file
+pub fn read_description<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> CrawlResult<T> {
+    let path = path.as_ref();
+    if !path.exists() || path.is_dir() {
+        return Err(CrawlErrorKind::InvalidDescriptionFile(path.to_path_buf()).into());
+    }
+
+    let ext = match path.extension().and_then(|e| e.to_str()) {
+        Some(e) => e,
+        None => return Err(CrawlErrorKind::InvalidDescriptionFile(path.to_path_buf()).into()),
+    };
+
+    let file = fs::File::open(path)?;
+
+    match ext {
+        "json" => serde_json::from_reader(file)
+            .map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e)))),
+        "yaml" | "yml" => serde_yaml::from_reader(file)
+            .map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e)))),
+        _ => Err(CrawlError::new(CrawlErrorKind::InvalidDescriptionFile(
+            path.to_path_buf(),
+        ))),
+    }
+}
+
+/// Loads the flows of `imports`, resolving paths relative to `dir`
+pub fn load_imports<P: AsRef<Path>>(
+    dir: P,
+    imports: &[ImportDescription],
+) -> CrawlResult<Vec<ImportedFlow>> {
+    let mut out = Vec::new();
+    load(dir.as_ref(), imports, "", &mut Vec::new(), &mut out)?;
+    Ok(out)
+}
+
+fn load(
+    dir: &Path,
+    imports: &[ImportDescription],
+    prefix: &str,
+    stack: &mut Vec<PathBuf>,
+    out: &mut Vec<ImportedFlow>,
+) -> CrawlResult<()> {
+    let dir_str = dir.to_str().unwrap_or("/");
+    for import in imports {
+        let path = pathutils::resolve(dir_str, &import.path)
+            .map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e))))?;
+        let path = fs::canonicalize(&path)
+            .map_err(|_| CrawlError::new(CrawlErrorKind::InvalidDescriptionFile(PathBuf::from(&path))))?;
+
+        if stack.contains(&path) {
+            let mut cycle = stack.clone();
+            cycle.push(path);
+            return Err(CrawlErrorKind::CyclicImport(cycle).into());
+        }
+
+        let namespace = match &import.namespace {
+            Some(ns) => ns.clone(),
+            None => path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default()
+                .to_string(),
+        };
+        let prefix = format!("{}{}.", prefix, namespace);
+
+        let library: FlowLibrary = read_description(&path)?;
+        let parent = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+
+        stack.push(path);
+        load(&parent, &library.imports, &prefix, stack, out)?;
+        stack.pop();
+
+        out.extend(library.flows.into_iter().map(|flow| ImportedFlow {
+            name: format!("{}{}", prefix, flow.name),
+            flow,
+        }));
+    }
+    Ok(())
+}
+
+/// Finds a flow by name. A flow running in `namespace` sees the flows of its
+/// own namespace first, so flows in a library can call each other unqualified.
+pub fn find_flow<'a>(
+    local: &'a [FlowDescription],
+    imported: &'a [ImportedFlow],
+    namespace: Option<&str>,
+    name: &str,
+) -> Option<(String, &'a FlowDescription)> {
+    if let Some(ns) = namespace {
+        let qualified = format!("{}.{}", ns, name);
+        if let Some(f) = imported.iter().find(|f| f.name == qualified) {
+            return Some((qualified, &f.flow));
+        }
+    }
+
+    if let Some(f) = local.iter().find(|f| f.name == name) {
+        return Some((name.to_string(), f));
+    }
+
+    imported
+        .iter()
+        .find(|f| f.name == name)
+        .map(|f| (name.to_string(), &f.flow))
+}
+
+/// Namespace of a fully qualified flow name
+pub fn flow_namespace(name: &str) -> Option<&str> {
+    name.rfind('.').map(|idx| &name[..idx])
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Write;
+
+    fn write(dir: &Path, name: &str, content: &str) {
+        let mut file = fs::File::create(dir.join(name)).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("crawler2-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn namespaced_imports() {
+        let dir = temp_dir("imports");
+        write(
+            &dir,
+            "common.yaml",
+            "imports:\n- path: ./util.yaml\n  as: util\nflows:\n- name: Crawl\n  work:\n  - type: Http\n",
+        );
+        write(&dir, "util.yaml", "flows:\n- name: Parse\n  work:\n  - type: ToJson\n");
+
+        let flows = load_imports(
+            &dir,
+            &[ImportDescription {
+                path: "./common.yaml".to_string(),
+                namespace: None,
+            }],
+        )
+        .unwrap();
+
+        let names = flows.iter().map(|f| f.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["common.util.Parse", "common.Crawl"]);
+
+        let (name, _) = find_flow(&[], &flows, Some("common"), "util.Parse").unwrap();
+        assert_eq!(name, "common.util.Parse");
+        assert!(find_flow(&[], &flows, None, "Crawl").is_none());
+        assert_eq!(flow_namespace("common.util.Parse"), Some("common.util"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cyclic_imports() {
+        let dir = temp_dir("cycle");
+        write(&dir, "a.yaml", "imports:\n- path: ./b.yaml\nflows: []\n");
+        write(&dir, "b.yaml", "imports:\n- path: ./a.yaml\nflows: []\n");
+
+        let err = load_imports(
+            &dir,
+            &[ImportDescription {
+                path: "./a.yaml".to_string(),
+                namespace: None,
+            }],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("CyclicImport"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,13 @@
 mod flow_description;
+mod imports;
 mod utils;
+mod validate;
 mod work_description;
 mod work_target;
 
 pub use self::flow_description::*;
+pub use self::imports::*;
 pub use self::utils::*;
+pub use self::validate::*;
 pub use self::work_description::*;
 pub use self::work_target::*;
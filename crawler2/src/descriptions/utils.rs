@@ -1,8 +1,8 @@
 use super::super::context::Context;
-use super::super::error::{CrawlErrorKind, CrawlResult};
+use super::super::error::CrawlResult;
 use super::super::utils::{station_fn_ctx2, WorkBoxWrapper};
-use super::super::work::{WorkBox, WorkOutput, Worker};
-use super::WorkDescription;
+use super::super::work::{and_then, WorkBox, WorkOutput};
+use super::{ValidationErrors, WorkDescription};
 use conveyor::{into_box, Chain};
 use conveyor_work::package::Package;
 use slog::FnValue;
@@ -13,28 +13,20 @@ pub fn compile_steps(
     steps: &[WorkDescription],
     ctx: &mut Context,
 ) -> CrawlResult<WorkBox<Package>> {
+    let prefix = match ctx.path() {
+        "" => "steps".to_string(),
+        path => format!("{}.steps", path),
+    };
+
     if steps.is_empty() {
-        return Err(CrawlErrorKind::NotFound("no steps defined".to_string()).into());
+        return Err(ValidationErrors::single(prefix, "no steps defined").into());
     }
 
     let start = Instant::now();
 
     info!(ctx.log(),"building target"; "steps" => steps.len());
 
-    let mut work = steps[0].request_station(ctx)?;
-    if steps.len() > 1 {
-        for w in steps.iter().skip(1) {
-            let next = w.request_station(ctx)?;
-            work = into_box(WorkBoxWrapper::new(work).pipe(station_fn_ctx2(
-                async move |pack: Vec<WorkOutput<Package>>, ctx: Arc<WorkBox<Package>>| {
-                    let work = Worker::new();
-                    let ret = await!(work.run_chain(pack, ctx.clone()));
-                    Ok(ret.into_iter().map(|m| WorkOutput::Result(m)).collect())
-                },
-                Arc::new(next),
-            )));
-        }
-    }
+    let work = chain_steps(steps, ctx, &prefix)?;
 
     info!(ctx.log(), "built target"; "time" => FnValue(move |_| format!("{:?}",start.elapsed())));
 
@@ -50,3 +42,26 @@ pub fn compile_steps(
         Arc::new((ctx.clone(), work)),
     )))
 }
+
+/// Builds `steps` into a single station, each step at the path `{prefix}[index]`
+pub(crate) fn chain_steps(
+    steps: &[WorkDescription],
+    ctx: &Context,
+    prefix: &str,
+) -> CrawlResult<WorkBox<Package>> {
+    if steps.is_empty() {
+        return Err(ValidationErrors::single(prefix, "no steps defined").into());
+    }
+
+    let mut work = steps[0].request_station(&mut ctx.clone().with_path(format!("{}[0]", prefix)))?;
+    for (i, w) in steps.iter().enumerate().skip(1) {
+        let next = w.request_station(&mut ctx.clone().with_path(format!("{}[{}]", prefix, i)))?;
+        work = into_box(WorkBoxWrapper::new(work).pipe(station_fn_ctx2(
+            async move |pack: Vec<WorkOutput<Package>>, ctx: Arc<Arc<WorkBox<Package>>>| {
+                Ok(and_then(pack, &ctx))
+            },
+            Arc::new(Arc::new(next)),
+        )));
+    }
+    Ok(work)
+}
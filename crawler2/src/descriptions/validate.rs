@@ -0,0 +1,444 @@
+use super::super::context::Args;
+use super::super::template::Template;
+use super::imports::{find_flow, flow_namespace, load_imports, ImportedFlow};
+use super::work_description::WorkDescription;
+use super::work_target::TargetDescription;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValidationError {
+    /// Location of the problem in the description, e.g. `flows[0].work[1].script`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl ValidationErrors {
+    /// A single error at `path`
+    pub fn single<P: Into<String>, M: Into<String>>(path: P, message: M) -> ValidationErrors {
+        ValidationErrors(vec![ValidationError {
+            path: path.into(),
+            message: message.into(),
+        }])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ValidationError> {
+        self.0.iter()
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ValidationErrors {}
+
+struct FlowCall {
+    from: Option<String>,
+    to: String,
+    path: String,
+}
+
+/// Walks a target description and collects every problem it finds.
+/// Worktypes take part through `WorkType::validate`.
+pub struct Validator<'a> {
+    root: &'a Path,
+    description: &'a TargetDescription,
+    imported: &'a [ImportedFlow],
+    errors: Vec<ValidationError>,
+    scopes: Vec<Args>,
+    flows: Vec<String>,
+    guards: usize,
+    lenient: bool,
+    calls: Vec<FlowCall>,
+    visited: HashSet<(String, String)>,
+}
+
+impl<'a> Validator<'a> {
+    pub fn new(
+        root: &'a Path,
+        description: &'a TargetDescription,
+        imported: &'a [ImportedFlow],
+        args: Args,
+    ) -> Validator<'a> {
+        Validator {
+            root,
+            description,
+            imported,
+            errors: Vec::new(),
+            scopes: vec![args],
+            flows: Vec::new(),
+            guards: 0,
+            lenient: false,
+            calls: Vec::new(),
+            visited: HashSet::new(),
+        }
+    }
+
+    pub fn error<P: Into<String>, M: Into<String>>(&mut self, path: P, message: M) {
+        self.errors.push(ValidationError {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Every variable visible at the current position.
+    /// Variables whose value is only known at run time are `null`.
+    pub fn variables(&self) -> Args {
+        let mut args = Args::new();
+        for scope in &self.scopes {
+            for (k, v) in scope {
+                args.insert(k.clone(), v.clone());
+            }
+        }
+        args
+    }
+
+    pub fn has_variable(&self, name: &str) -> bool {
+        self.scopes.iter().any(|s| s.contains_key(name))
+    }
+
+    /// Runs `f` with additional variables in scope
+    pub fn with_variables<F: FnOnce(&mut Self)>(&mut self, variables: Args, f: F) {
+        self.scopes.push(variables);
+        f(self);
+        self.scopes.pop();
+    }
+
+    /// Runs `f` in a position which only executes conditionally,
+    /// so flow recursion from within it is considered terminating
+    pub fn guarded<F: FnOnce(&mut Self)>(&mut self, f: F) {
+        self.guards += 1;
+        f(self);
+        self.guards -= 1;
+    }
+
+    /// Checks that `text` parses and that every variable it needs is bound.
+    /// Returns the rendered text when all values are known up front.
+    pub fn check_template(&mut self, path: &str, text: &str) -> Option<String> {
+        let template = match Template::parse(text) {
+            Ok(t) => t,
+            Err(e) => {
+                self.error(path, e.to_string());
+                return None;
+            }
+        };
+
+        let unbound = template
+            .required_variables()
+            .filter(|v| !self.has_variable(v))
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>();
+        if !self.lenient {
+            for variable in &unbound {
+                self.error(path, format!("unbound variable \"{}\"", variable));
+            }
+        }
+
+        if !unbound.is_empty() {
+            return None;
+        }
+
+        template.render(&self.variables()).ok()
+    }
+
+    /// Checks that a file referenced by the description exists
+    pub fn check_file(&mut self, path: &str, file: &str) {
+        let file = match self.check_template(path, file) {
+            Some(f) => f,
+            None => return,
+        };
+
+        let root = self.root.to_str().unwrap_or("/");
+        let exists = match pathutils::resolve(root, &file) {
+            Ok(p) => Path::new(p.trim_start_matches("file://")).exists(),
+            Err(_) => false,
+        };
+
+        if !exists {
+            self.error(path, format!("file not found \"{}\"", file));
+        }
+    }
+
+    pub fn check_steps(&mut self, path: &str, steps: &[WorkDescription]) {
+        if steps.is_empty() {
+            self.error(path, "no steps defined");
+        }
+        for (i, step) in steps.iter().enumerate() {
+            step.validate(self, &format!("{}[{}]", path, i));
+        }
+    }
+
+    pub fn check_flow(&mut self, path: &str, name: &str, args: &Args) {
+        let description = self.description;
+        let namespace = self.flows.last().and_then(|f| flow_namespace(f));
+        let (name, flow) = match find_flow(&description.flows, self.imported, namespace, name) {
+            Some(f) => f,
+            None => {
+                self.error(path, format!("undefined flow \"{}\"", name));
+                return;
+            }
+        };
+        let name = name.as_str();
+
+        let flow_path = match description.flows.iter().position(|f| std::ptr::eq(f, flow)) {
+            Some(idx) => format!("flows[{}].work", idx),
+            None => format!("flows[{}].work", name),
+        };
+
+        if self.guards == 0 {
+            self.calls.push(FlowCall {
+                from: self.flows.last().cloned(),
+                to: name.to_string(),
+                path: path.to_string(),
+            });
+        }
+
+        // A flow is checked once for every distinct set of variables it is called with
+        let mut variables = self.variables();
+        for (k, v) in args {
+            variables.insert(k.clone(), v.clone());
+        }
+        let key = serde_json::to_string(&variables.into_iter().collect::<BTreeMap<_, _>>())
+            .unwrap_or_default();
+        if !self.visited.insert((name.to_string(), key)) {
+            return;
+        }
+
+        let guards = std::mem::replace(&mut self.guards, 0);
+        self.flows.push(name.to_string());
+        self.with_variables(args.clone(), |v| v.check_steps(&flow_path, &flow.work));
+        self.flows.pop();
+        self.guards = guards;
+    }
+
+    pub fn finish(mut self) -> Result<(), ValidationErrors> {
+        // Flows which are never called from the target are still checked,
+        // but their variables may be bound by a caller we cannot see
+        self.lenient = true;
+        let names = self.flow_names();
+        for name in &names {
+            if !self.visited.iter().any(|v| &v.0 == name) {
+                self.check_flow("", name, &Args::new());
+            }
+        }
+
+        for (path, cycle) in self.find_cycles() {
+            self.error(
+                path,
+                format!(
+                    "flow recursion without a termination guard ({})",
+                    cycle.join(" -> ")
+                ),
+            );
+        }
+
+        // The same flow can be checked with several sets of arguments
+        let mut errors: Vec<ValidationError> = Vec::with_capacity(self.errors.len());
+        for e in self.errors {
+            if !errors.contains(&e) {
+                errors.push(e);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors(errors))
+        }
+    }
+
+    fn flow_names(&self) -> Vec<String> {
+        self.description
+            .flows
+            .iter()
+            .map(|f| f.name.clone())
+            .chain(self.imported.iter().map(|f| f.name.clone()))
+            .collect()
+    }
+
+    fn find_cycles(&self) -> Vec<(String, Vec<String>)> {
+        fn visit(
+            calls: &[FlowCall],
+            stack: &mut Vec<String>,
+            done: &mut HashSet<String>,
+            out: &mut Vec<(String, Vec<String>)>,
+        ) {
+            let current = stack.last().unwrap().clone();
+            for call in calls.iter().filter(|c| c.from.as_ref() == Some(&current)) {
+                if let Some(pos) = stack.iter().position(|f| f == &call.to) {
+                    let mut cycle = stack[pos..].to_vec();
+                    cycle.push(call.to.clone());
+                    if !out.iter().any(|o| o.0 == call.path) {
+                        out.push((call.path.clone(), cycle));
+                    }
+                } else if !done.contains(&call.to) {
+                    stack.push(call.to.clone());
+                    visit(calls, stack, done, out);
+                    stack.pop();
+                }
+            }
+            done.insert(current);
+        }
+
+        let mut out = Vec::new();
+        let mut done = HashSet::new();
+        for name in self.flow_names() {
+            if !done.contains(&name) {
+                visit(&self.calls, &mut vec![name], &mut done, &mut out);
+            }
+        }
+        out
+    }
+}
+
+impl TargetDescription {
+    /// Validates the description without building it. `root` is the
+    /// directory relative files are resolved against and `args` the
+    /// arguments the target will be run with.
+    pub fn validate(&self, root: &Path, args: &Args) -> Result<(), ValidationErrors> {
+        let mut globals = args.clone();
+        globals.insert("targetName".to_string(), Value::String(self.name.clone()));
+
+        let (imported, import_error) = match load_imports(root, &self.imports) {
+            Ok(i) => (i, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+
+        let mut validator = Validator::new(root, self, &imported, globals);
+        if let Some(e) = import_error {
+            validator.error("imports", e.to_string());
+        }
+
+        if let Value::String(input) = &self.work.input {
+            validator.check_template("work.input", input);
+        }
+        validator.check_steps("work.steps", &self.work.steps);
+
+        validator.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::super::prelude::*;
+    use std::path::Path;
+
+    fn describe(yaml: &str) -> TargetDescription {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn paths(desc: &TargetDescription, args: &Args) -> Vec<String> {
+        desc.validate(Path::new("/"), args)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let desc = describe(
+            r#"
+name: Test
+work:
+  input: "${input}"
+  steps:
+  - type: Flow
+    flow_name: Missing
+  - type: WriteDirectory
+    path: "${output}"
+flows:
+- name: Empty
+  work: []
+"#,
+        );
+
+        assert_eq!(
+            paths(&desc, &Args::new()),
+            vec![
+                "work.input: unbound variable \"input\"",
+                "work.steps[0].flow_name: undefined flow \"Missing\"",
+                "work.steps[1].path: unbound variable \"output\"",
+                "flows[0].work: no steps defined",
+            ]
+        );
+
+        assert_eq!(
+            paths(&desc, &args! { "input" => "https://loppen.dk", "output" => "/" }),
+            vec![
+                "work.steps[0].flow_name: undefined flow \"Missing\"",
+                "flows[0].work: no steps defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn flow_arguments_and_recursion() {
+        let desc = describe(
+            r#"
+name: Test
+work:
+  input: https://loppen.dk
+  steps:
+  - type: Flow
+    flow_name: Crawl
+    arguments:
+      script: ./index.js
+flows:
+- name: Crawl
+  work:
+  - type: Http
+  - type: Duktape
+    script: "${script}"
+    then:
+      type: Flow
+      flow_name: Crawl
+      arguments:
+        script: ./concert.js
+- name: Loop
+  work:
+  - type: Flow
+    flow_name: Loop
+"#,
+        );
+
+        assert_eq!(
+            paths(&desc, &Args::new()),
+            vec![
+                "flows[0].work[1].script: file not found \"./index.js\"",
+                "flows[0].work[1].script: file not found \"./concert.js\"",
+                "flows[1].work[0].flow_name: flow recursion without a termination guard (Loop -> Loop)",
+            ]
+        );
+    }
+}
@@ -1,12 +1,23 @@
 use super::super::context::Context;
-use super::super::error::CrawlResult;
+use super::super::error::{CrawlError, CrawlErrorKind, CrawlResult};
+use super::super::events::Event;
+use super::super::metrics;
+use super::super::package::copy_of;
+use super::super::predicate::{self, Matcher, Predicate};
+use super::super::report::{millis, ErrorRecord};
+use super::super::secrets::Secrets;
 use super::super::traits::WorkType;
-use super::super::utils::station_fn_ctx2;
-use super::super::work::{WorkBox, WorkOutput, Worker};
+use super::super::utils::{station_fn_ctx2, WorkArcWrapper};
+use super::super::work::{on_then, Work, WorkBox, WorkOutput};
+use super::utils::chain_steps;
+use super::validate::Validator;
+use conveyor::futures::future::{self, Either};
 use conveyor::into_box;
 use conveyor_work::package::Package;
+use serde_json::Value;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Builder)]
 #[builder(pattern = "owned")]
@@ -19,6 +30,19 @@ pub struct WorkDescription {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(default = "None")]
     pub then: Option<Box<WorkType>>,
+    /// Packages not matching are passed on without running the step
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[builder(default = "None")]
+    pub when: Option<Predicate>,
+    /// Times a package is run through the step again when all its outputs are errors
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[builder(default = "None")]
+    pub retries: Option<u32>,
+    /// Steps receiving a JSON error record for every package the step failed.
+    /// Their outputs continue after the step like its own.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[builder(default = "None")]
+    pub on_error: Option<Vec<WorkDescription>>,
 }
 
 impl WorkDescription {
@@ -27,35 +51,257 @@ impl WorkDescription {
         WorkDescriptionBuilder::default().work(Box::new(work))
     }
 
+    pub fn validate(&self, validator: &mut Validator, path: &str) {
+        if let Some(when) = &self.when {
+            when.validate(validator, &format!("{}.when", path));
+        }
+        self.work.validate(validator, path);
+        if let Some(then) = &self.then {
+            // `then` only runs for packages a step explicitly hands to it
+            validator.guarded(|v| then.validate(v, &format!("{}.then", path)));
+        }
+        if let Some(on_error) = &self.on_error {
+            validator.guarded(|v| v.check_steps(&format!("{}.on_error", path), on_error));
+        }
+    }
+
     pub fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
-        let ret = (
-            self.work.request_station(ctx)?,
-            match &self.then {
-                Some(s) => Some(s.clone()),
+        let step = Step {
+            station: self.work.request_station(ctx)?,
+            worktype: worktype_name(&*self.work),
+            when: match &self.when {
+                Some(when) => Some(when.compile()?),
                 None => None,
             },
-            ctx.clone(),
-        );
+            then: self.then.clone(),
+            retries: self.retries.unwrap_or(0),
+            on_error: self.on_error.clone(),
+            ctx: ctx.clone(),
+            then_station: Mutex::new(None),
+            error_station: Mutex::new(None),
+        };
 
         Ok(into_box(station_fn_ctx2(
-            async move |pack: Package,
-                        ctx: Arc<(WorkBox<Package>, Option<Box<WorkType>>, Context)>| {
-                let mut ret = await!(ctx.0.execute(pack))?;
+            async move |mut pack: Package, ctx: Arc<Step>| {
+                let name = pack.name().to_string();
+                let path = ctx.ctx.path();
+                let target = ctx.ctx.target().description().name.as_str();
+
+                if let Some(when) = &ctx.when {
+                    let (p, content) = match await!(predicate::read_content(pack, when.needs_content())) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            let e = e.with_location(path, name.as_str()).with_step_info(target, ctx.worktype.as_str());
+                            return Ok(vec![WorkOutput::Result(Err(e))]);
+                        }
+                    };
+                    if !when.test(&p, content.as_ref()) {
+                        return Ok(vec![WorkOutput::Result(Ok(p))]);
+                    }
+                    pack = p;
+                }
+
+                let events = ctx.ctx.events();
+                let observed = events.is_observed();
+                if observed {
+                    events.emit(Event::StepStarted {
+                        target: target.to_string(),
+                        step: path.to_string(),
+                        package: name.clone(),
+                    });
+                }
+                let start = Instant::now();
+
+                let (mut ret, retried) = if ctx.retries == 0 {
+                    (await!(attempt(ctx.clone(), pack)), 0)
+                } else {
+                    // Every attempt needs the content of the package
+                    match await!(pack.read_content()) {
+                        Ok(body) => {
+                            let mut retried = 0;
+                            loop {
+                                let ret = await!(attempt(ctx.clone(), copy_of(&pack, body.clone())));
+                                if retried == ctx.retries || !failed(&ret) {
+                                    break (ret, retried);
+                                }
+                                retried += 1;
+                                info!(ctx.ctx.log(), "retrying step"; "package" => &name, "retry" => retried);
+                            }
+                        }
+                        Err(e) => (vec![WorkOutput::Result(Err(CrawlError::from(e)))], 0),
+                    }
+                };
 
                 if ret.iter().find(|m| m.is_then()).is_some() {
-                    if let Some(then) = &ctx.1 {
-                        let mut c = ctx.2.clone();
-                        let worker = Worker::new();
-                        let output =
-                            await!(worker
-                                .run_and(ret, Arc::new(then.request_station(&mut c).unwrap())));
-                        ret = output.into_iter().map(|m| WorkOutput::Result(m)).collect();
+                    if let Some(then) = &ctx.then {
+                        let station = match lazy_station(&ctx.then_station, || {
+                            then.request_station(&mut ctx.ctx.clone().with_path(format!("{}.then", path)))
+                        }) {
+                            Ok(s) => s,
+                            Err(e) => return Ok(vec![WorkOutput::Result(Err(e))]),
+                        };
+                        ret = on_then(ret, &station);
                     }
                 }
 
-                Ok(ret)
+                let elapsed = start.elapsed();
+                let m = ctx.ctx.env().metrics();
+                let secrets = ctx.ctx.env().secrets();
+
+                let mut errors = 0;
+                let ret = ret
+                    .into_iter()
+                    .map(|o| match o {
+                        WorkOutput::Result(Err(mut e)) => {
+                            errors += 1;
+                            if e.step().is_none() {
+                                m.inc(&metrics::ERRORS, &[target, e.kind().name(), path]);
+                                if observed {
+                                    events.emit(Event::Error {
+                                        target: target.to_string(),
+                                        step: path.to_string(),
+                                        package: name.clone(),
+                                        kind: e.kind().name().to_string(),
+                                        message: secrets.redact(&e.to_string()),
+                                    });
+                                }
+                                e = e
+                                    .with_location(path, name.as_str())
+                                    .with_step_info(target, ctx.worktype.as_str())
+                                    .with_retries(retried);
+                            }
+                            WorkOutput::Result(Err(e))
+                        }
+                        WorkOutput::Result(Ok(p)) => {
+                            if observed {
+                                events.emit(Event::PackageEmitted {
+                                    target: target.to_string(),
+                                    step: path.to_string(),
+                                    package: p.name().to_string(),
+                                });
+                            }
+                            WorkOutput::Result(Ok(p))
+                        }
+                        o => o,
+                    })
+                    .collect::<Vec<_>>();
+
+                let outputs = ret.len() - errors;
+                ctx.ctx.reporter().step(path, elapsed, outputs, errors);
+                m.add(&metrics::STEP_PACKAGES, &[target, path], outputs as f64);
+                m.observe(&metrics::STEP_DURATION, &[target, path], elapsed);
+                if observed {
+                    events.emit(Event::StepFinished {
+                        target: target.to_string(),
+                        step: path.to_string(),
+                        package: name.clone(),
+                        outputs,
+                        errors,
+                        duration_ms: millis(elapsed),
+                    });
+                }
+
+                let on_error = match &ctx.on_error {
+                    Some(steps) if errors > 0 => steps,
+                    _ => return Ok(ret),
+                };
+                let station = match lazy_station(&ctx.error_station, || {
+                    chain_steps(on_error, &ctx.ctx, &format!("{}.on_error", path))
+                }) {
+                    Ok(s) => s,
+                    Err(e) => return Ok(vec![WorkOutput::Result(Err(e))]),
+                };
+
+                // Handled errors are replaced by the work of the handler, queued
+                // like any other. Cancelled packages are left alone, so a
+                // resumed run retries them.
+                Ok(ret
+                    .into_iter()
+                    .map(|o| match o {
+                        WorkOutput::Result(Err(e)) => match e.kind() {
+                            CrawlErrorKind::Cancelled => WorkOutput::Result(Err(e)),
+                            _ => WorkOutput::Work(Work::new(
+                                error_package(&e, secrets),
+                                WorkArcWrapper::new(station.clone()),
+                            )),
+                        },
+                        o => o,
+                    })
+                    .collect())
             },
-            Arc::new(ret),
+            Arc::new(step),
         )))
     }
 }
+
+type LazyStation = Mutex<Option<Arc<WorkBox<Package>>>>;
+
+struct Step {
+    station: WorkBox<Package>,
+    worktype: String,
+    when: Option<Matcher>,
+    then: Option<Box<WorkType>>,
+    retries: u32,
+    on_error: Option<Vec<WorkDescription>>,
+    ctx: Context,
+    then_station: LazyStation,
+    error_station: LazyStation,
+}
+
+/// Runs a package through the station of a step once.
+/// Steps already running may finish until the grace period ends.
+async fn attempt(step: Arc<Step>, pack: Package) -> Vec<WorkOutput<Package>> {
+    let cancellation = step.ctx.cancellation();
+    if cancellation.is_cancelled() {
+        return vec![WorkOutput::Result(Err(CrawlErrorKind::Cancelled.into()))];
+    }
+    match await!(future::select(step.station.execute(pack), cancellation.aborted())) {
+        Either::Left((Ok(ret), _)) => ret,
+        Either::Left((Err(e), _)) => vec![WorkOutput::Result(Err(CrawlError::from(e)))],
+        Either::Right(_) => vec![WorkOutput::Result(Err(CrawlErrorKind::Cancelled.into()))],
+    }
+}
+
+/// Whether an attempt produced nothing but errors worth retrying
+fn failed(outputs: &[WorkOutput<Package>]) -> bool {
+    !outputs.is_empty()
+        && outputs.iter().all(|o| match o {
+            WorkOutput::Result(Err(e)) => match e.kind() {
+                CrawlErrorKind::Cancelled => false,
+                _ => true,
+            },
+            _ => false,
+        })
+}
+
+/// Builds a station the first time a package is handed to it
+fn lazy_station<F: FnOnce() -> CrawlResult<WorkBox<Package>>>(
+    built: &LazyStation,
+    build: F,
+) -> CrawlResult<Arc<WorkBox<Package>>> {
+    let mut built = built.lock().unwrap();
+    if let Some(station) = &*built {
+        return Ok(station.clone());
+    }
+    let station = Arc::new(build()?);
+    *built = Some(station.clone());
+    Ok(station)
+}
+
+/// The `type` a worktype is tagged with in descriptions
+fn worktype_name(work: &WorkType) -> String {
+    match serde_json::to_value(work) {
+        Ok(Value::Object(o)) => match o.get("type") {
+            Some(Value::String(s)) => s.clone(),
+            _ => "Unknown".to_string(),
+        },
+        _ => "Unknown".to_string(),
+    }
+}
+
+/// The package handed to `on_error` steps, named after the failed package
+fn error_package(error: &CrawlError, secrets: &Secrets) -> Package {
+    let record = ErrorRecord::new(error, secrets);
+    let name = record.package.clone().unwrap_or_else(|| "error".to_string());
+    Package::new(&name, serde_json::to_value(&record).unwrap_or(Value::Null))
+}
@@ -1,10 +1,13 @@
 use super::super::context::{Args, Context, ParentOrRoot, RootContext};
-use super::super::error::{CrawlErrorKind, CrawlResult};
+use super::super::error::CrawlResult;
+use super::super::package::detect_content_type;
 use super::super::utils::station_fn_ctx2;
 use super::super::utils::{WorkArcWrapper, WorkBoxWrapper};
-use super::super::work::{Work, WorkBox, WorkOutput, Worker};
+use super::super::work::{Work, WorkBox, WorkOutput};
 use super::flow_description::*;
+use super::imports::ImportDescription;
 use super::utils::compile_steps;
+use super::validate::ValidationErrors;
 use super::work_description::*;
 use conveyor::{into_box, Chain};
 use conveyor_work::package::Package;
@@ -16,6 +19,8 @@ use std::time::Instant;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TargetDescription {
     pub name: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub imports: Vec<ImportDescription>,
     pub work: WorkTargetDescription,
     pub flows: Vec<FlowDescription>,
 }
@@ -43,15 +48,14 @@ impl WorkTargetDescription {
         let mut ctx = Context::new(ParentOrRoot::Root(parent), None, None);
         let work = self.build(&mut ctx)?;
 
-        let worker = Worker::new();
-        let ret = await!(worker.run(vec![work]));
+        let ret = await!(ctx.scheduler().run(vec![work]));
 
         Ok(ret)
     }
 
     pub fn build(&self, ctx: &mut Context) -> CrawlResult<Work<Package>> {
         if self.steps.is_empty() {
-            return Err(CrawlErrorKind::NotFound("no steps defined".to_string()).into());
+            return Err(ValidationErrors::single("work.steps", "no steps defined").into());
         }
 
         // let start = Instant::now();
@@ -85,7 +89,7 @@ impl WorkTargetDescription {
         // info!(ctx.log(), "built target"; "time" => FnValue(move |_| format!("{:?}",start.elapsed())));
 
         let input = if self.input.is_string() {
-            Value::String(ctx.interpolate(self.input.as_str().unwrap()).unwrap())
+            Value::String(ctx.interpolate(self.input.as_str().unwrap())?)
         } else {
             self.input.clone()
         };
@@ -97,12 +101,14 @@ impl WorkTargetDescription {
             Some(args! {
                 "targetName" => name
             }),
-        );
+        )
+        .with_path("work");
 
         let work = compile_steps(&self.steps, &mut ctx)?;
 
+        let body = serde_json::to_vec(&input).unwrap_or_default();
         Ok(Work::new(
-            Package::new(&name, input),
+            detect_content_type(Package::new(&name, input), &body),
             WorkBoxWrapper::new(work),
             // station_fn_ctx2(
             //     async move |pack: Package, ctx: Arc<(Context, WorkBox<Package>)>| {
@@ -1,14 +1,124 @@
 use super::context::Args;
+use super::descriptions::read_description;
+use super::error::CrawlResult;
+use super::cancel::Cancellation;
+use super::events::Events;
+use super::metrics::Metrics;
+use super::scheduler::Order;
+use super::secrets::{self, RedactDrain, Secrets};
+use serde::Serialize;
+use serde_json::Value;
 use slog::Logger;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+pub const DEFAULT_ENV_PREFIX: &'static str = "CRAWLER_";
+pub const CONFIG_FILE_NAME: &'static str = "crawler.yaml";
+
+/// Settings read from `crawler.yaml` files
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cache_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub concurrency: Option<usize>,
+    /// Number of steps a target runs at once
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub in_flight: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub order: Option<Order>,
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub vars: Args,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> CrawlResult<Config> {
+        read_description(path)
+    }
+
+    /// Layers `other` on top of `self`
+    pub fn merge(&mut self, other: Config) {
+        if other.user_agent.is_some() {
+            self.user_agent = other.user_agent;
+        }
+        if other.cache_dir.is_some() {
+            self.cache_dir = other.cache_dir;
+        }
+        if other.concurrency.is_some() {
+            self.concurrency = other.concurrency;
+        }
+        if other.in_flight.is_some() {
+            self.in_flight = other.in_flight;
+        }
+        if other.order.is_some() {
+            self.order = other.order;
+        }
+        self.vars.extend(other.vars);
+    }
+
+    /// Locations searched for config files, lowest precedence first:
+    /// the user config directory and then the working directory
+    pub fn default_paths<P: AsRef<Path>>(cwd: P) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let user = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+        if let Some(dir) = user {
+            paths.push(dir.join("crawler").join(CONFIG_FILE_NAME));
+        }
+        paths.push(cwd.as_ref().join(CONFIG_FILE_NAME));
+        paths
+    }
+}
+
+/// Parses the `KEY=value` lines of a `.env` file
+pub fn parse_dotenv(input: &str) -> Vec<(String, String)> {
+    input
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| {
+            let l = if l.starts_with("export ") { &l[7..] } else { l };
+            let idx = l.find('=')?;
+            let key = l[..idx].trim();
+            let mut value = l[idx + 1..].trim();
+            if value.len() >= 2
+                && ((value.starts_with('"') && value.ends_with('"'))
+                    || (value.starts_with('\'') && value.ends_with('\'')))
+            {
+                value = &value[1..value.len() - 1];
+            }
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Builds an `Environment`. Variables are layered, later layers win:
+///
+/// 1. `vars` of the config files
+/// 2. prefixed variables from `.env` files
+/// 3. prefixed variables from the process environment
+/// 4. variables set with `var` and `vars`
+///
+/// Arguments given when running a target take precedence over all of them.
+///
+/// Secrets are kept apart from the variables and only read through
+/// `${secret:NAME}`, from the secrets file or `CRAWLER_SECRET_NAME` in the
+/// process environment or a `.env` file. The built logger redacts their values.
 pub struct EnvironmentBuilder {
     cwd: PathBuf,
     vars: Args,
     args: Vec<String>,
     log: Logger,
+    config: Config,
+    dotenv: Vec<(String, String)>,
+    env_prefix: Option<String>,
+    secrets: HashMap<String, String>,
 }
 
 impl EnvironmentBuilder {
@@ -18,14 +128,134 @@ impl EnvironmentBuilder {
             vars: Args::new(),
             args: Vec::new(),
             log: logger,
+            config: Config::default(),
+            dotenv: Vec::new(),
+            env_prefix: None,
+            secrets: HashMap::new(),
+        }
+    }
+
+    pub fn var<K: Into<String>, V: Serialize>(mut self, key: K, value: V) -> Self {
+        self.vars.insert(
+            key.into(),
+            serde_json::to_value(value).unwrap_or(Value::Null),
+        );
+        self
+    }
+
+    pub fn vars(mut self, vars: Args) -> Self {
+        self.vars.extend(vars);
+        self
+    }
+
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: Into<String>>(mut self, args: I) -> Self {
+        self.args.extend(args.into_iter().map(|m| m.into()));
+        self
+    }
+
+    /// Reads variables starting with `prefix` from the process environment
+    /// and `.env` files. `CRAWLER_OUTPUT` becomes the variable `output`.
+    pub fn env_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.env_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn dotenv<P: AsRef<Path>>(mut self, path: P) -> CrawlResult<Self> {
+        let content = fs::read_to_string(path)?;
+        self.dotenv.extend(parse_dotenv(&content));
+        Ok(self)
+    }
+
+    pub fn secret<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+        self.secrets.insert(name.into(), value.into());
+        self
+    }
+
+    /// Reads secrets from a JSON or YAML map, or from a file of `KEY=value` lines
+    pub fn secrets_file<P: AsRef<Path>>(mut self, path: P) -> CrawlResult<Self> {
+        let path = path.as_ref();
+        let secrets: HashMap<String, String> = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") | Some("yaml") | Some("yml") => read_description(path)?,
+            _ => parse_dotenv(&fs::read_to_string(path)?).into_iter().collect(),
+        };
+        self.secrets.extend(secrets);
+        Ok(self)
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config.merge(config);
+        self
+    }
+
+    pub fn config_file<P: AsRef<Path>>(self, path: P) -> CrawlResult<Self> {
+        let config = Config::load(path)?;
+        Ok(self.config(config))
+    }
+
+    /// Layers the config files found in `Config::default_paths`
+    pub fn default_config(mut self) -> CrawlResult<Self> {
+        for path in Config::default_paths(&self.cwd) {
+            if path.is_file() {
+                self = self.config_file(path)?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn env_vars(&self) -> Args {
+        let mut out = Args::new();
+        let prefix = match &self.env_prefix {
+            Some(p) => p,
+            None => return out,
+        };
+
+        let entries = self
+            .dotenv
+            .iter()
+            .cloned()
+            .chain(env::vars());
+        for (key, value) in entries {
+            // Secrets stay out of the variables even when they share the prefix
+            if key.starts_with(secrets::ENV_PREFIX) {
+                continue;
+            }
+            if key.starts_with(prefix.as_str()) && key.len() > prefix.len() {
+                out.insert(key[prefix.len()..].to_lowercase(), Value::String(value));
+            }
         }
+        out
     }
+
     pub fn build(self) -> Arc<Environment> {
+        let mut vars = self.config.vars.clone();
+        vars.extend(self.env_vars());
+        vars.extend(self.vars);
+
+        let mut secrets = HashMap::new();
+        for (key, value) in &self.dotenv {
+            if key.starts_with(secrets::ENV_PREFIX) && key.len() > secrets::ENV_PREFIX.len() {
+                secrets.insert(key[secrets::ENV_PREFIX.len()..].to_string(), value.clone());
+            }
+        }
+        secrets.extend(self.secrets);
+        let secrets = Secrets::new(secrets);
+        let logger = Logger::root(RedactDrain::new(self.log, secrets.clone()), o!());
+
         Arc::new(Environment {
             cwd: self.cwd,
-            vars: self.vars,
+            vars: vars,
             args: self.args,
-            logger: self.log,
+            logger: logger,
+            config: self.config,
+            secrets: secrets,
+            metrics: Metrics::new(),
+            events: Events::new(),
+            cancellation: Cancellation::new(),
         })
     }
 }
@@ -36,6 +266,11 @@ pub struct Environment {
     vars: Args,
     args: Vec<String>,
     logger: Logger,
+    config: Config,
+    secrets: Secrets,
+    metrics: Metrics,
+    events: Events,
+    cancellation: Cancellation,
 }
 
 impl Environment {
@@ -51,7 +286,109 @@ impl Environment {
         &self.vars
     }
 
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn secrets(&self) -> &Secrets {
+        &self.secrets
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Events of every target run in the environment
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// Cancels every target run in the environment
+    pub fn cancellation(&self) -> &Cancellation {
+        &self.cancellation
+    }
+
     pub fn log(&self) -> &Logger {
         &self.logger
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use slog::Discard;
+
+    #[test]
+    fn dotenv() {
+        let entries = parse_dotenv(
+            "# comment\nCRAWLER_OUTPUT=./out\nexport CRAWLER_TOKEN=\"abc def\"\n\nBROKEN\n",
+        );
+        assert_eq!(
+            entries,
+            vec![
+                ("CRAWLER_OUTPUT".to_string(), "./out".to_string()),
+                ("CRAWLER_TOKEN".to_string(), "abc def".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let mut config = Config::default();
+        config.concurrency = Some(2);
+        config.vars = args! { "output" => "config", "input" => "config" };
+
+        let mut builder = Environment::build("/", Logger::root(Discard, o!()))
+            .config(config)
+            .env_prefix("CRAWLER_TEST_PRECEDENCE_")
+            .var("input", "builder");
+        builder
+            .dotenv
+            .push(("CRAWLER_TEST_PRECEDENCE_OUTPUT".to_string(), "dotenv".to_string()));
+        builder
+            .dotenv
+            .push(("CRAWLER_TEST_PRECEDENCE_INPUT".to_string(), "dotenv".to_string()));
+
+        let env = builder.build();
+        assert_eq!(env.vars()["output"], "dotenv");
+        assert_eq!(env.vars()["input"], "builder");
+        assert_eq!(env.config().concurrency, Some(2));
+    }
+
+    #[test]
+    fn secrets() {
+        use super::super::secrets::SecretSource;
+
+        let env = Environment::build("/", Logger::root(Discard, o!()))
+            .secret("CRAWLER_TEST_ENV_SECRET", "hunter2")
+            .build();
+        assert!(!env.vars().contains_key("CRAWLER_TEST_ENV_SECRET"));
+        assert_eq!(
+            env.secrets().secret("CRAWLER_TEST_ENV_SECRET"),
+            Some("hunter2".to_string())
+        );
+        assert_eq!(env.secrets().redact("pw=hunter2"), "pw=[REDACTED]");
+    }
+
+    #[test]
+    fn dotenv_secrets() {
+        use super::super::secrets::SecretSource;
+
+        let mut builder = Environment::build("/", Logger::root(Discard, o!())).env_prefix("CRAWLER_");
+        builder.dotenv.extend(parse_dotenv(
+            "CRAWLER_SECRET_TEST_DOTENV_TOKEN=s3cret\nCRAWLER_SECRET_TEST_DOTENV_KEY=dotenv\n",
+        ));
+        let env = builder.secret("TEST_DOTENV_KEY", "file").build();
+
+        assert!(env.vars().keys().all(|k| !k.starts_with("secret_")));
+        assert_eq!(env.secrets().secret("TEST_DOTENV_TOKEN"), Some("s3cret".to_string()));
+        // Secrets given to the builder take precedence over `.env` files
+        assert_eq!(env.secrets().secret("TEST_DOTENV_KEY"), Some("file".to_string()));
+        assert_eq!(env.secrets().redact("token=s3cret"), "token=[REDACTED]");
+    }
+}
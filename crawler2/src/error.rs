@@ -3,27 +3,140 @@ use std::error::Error;
 use std::fmt;
 use std::result::Result;
 use std::path::PathBuf;
+use super::descriptions::ValidationErrors;
+use super::template::TemplateError;
 
 pub type CrawlResult<T> = Result<T, CrawlError>;
 
 #[derive(Debug)]
 pub enum CrawlErrorKind {
     Unknown,
-    Conveyor(String),
+    Conveyor(ConveyorError),
     Error(Box<dyn Error + Send + Sync>),
     NotFound(String),
     Io(std::io::Error),
-    InvalidDescriptionFile(PathBuf)
+    InvalidDescriptionFile(PathBuf),
+    Template(TemplateError),
+    Validation(ValidationErrors),
+    CyclicImport(Vec<PathBuf>),
+    /// The run was cancelled before the package was processed
+    Cancelled,
+}
+
+impl CrawlErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CrawlErrorKind::Unknown => "Unknown",
+            CrawlErrorKind::Conveyor(_) => "Conveyor",
+            CrawlErrorKind::Error(_) => "Error",
+            CrawlErrorKind::NotFound(_) => "NotFound",
+            CrawlErrorKind::Io(_) => "Io",
+            CrawlErrorKind::InvalidDescriptionFile(_) => "InvalidDescriptionFile",
+            CrawlErrorKind::Template(_) => "Template",
+            CrawlErrorKind::Validation(_) => "Validation",
+            CrawlErrorKind::CyclicImport(_) => "CyclicImport",
+            CrawlErrorKind::Cancelled => "Cancelled",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct CrawlError {
     kind: CrawlErrorKind,
+    target: Option<String>,
+    step: Option<String>,
+    worktype: Option<String>,
+    package: Option<String>,
+    retries: u32,
 }
 
 impl CrawlError {
     pub fn new(kind: CrawlErrorKind) -> CrawlError {
-        CrawlError { kind }
+        CrawlError {
+            kind,
+            target: None,
+            step: None,
+            worktype: None,
+            package: None,
+            retries: 0,
+        }
+    }
+
+    pub fn kind(&self) -> &CrawlErrorKind {
+        &self.kind
+    }
+
+    /// Path of the step where the error occurred
+    pub fn step(&self) -> Option<&str> {
+        self.step.as_ref().map(|s| s.as_str())
+    }
+
+    /// Name of the target where the error occurred
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_ref().map(|s| s.as_str())
+    }
+
+    /// Type of the step where the error occurred, e.g. `Http`
+    pub fn worktype(&self) -> Option<&str> {
+        self.worktype.as_ref().map(|s| s.as_str())
+    }
+
+    /// Name of the package being processed when the error occurred
+    pub fn package(&self) -> Option<&str> {
+        self.package.as_ref().map(|s| s.as_str())
+    }
+
+    /// Number of times the step was retried before giving up
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The full location of the error, e.g. `Loppen > flows[Crawl].work[0] (Http)`
+    pub fn path(&self) -> Option<String> {
+        let step = self.step.as_ref()?;
+        let mut path = match &self.target {
+            Some(target) => format!("{} > {}", target, step),
+            None => step.clone(),
+        };
+        if let Some(worktype) = &self.worktype {
+            path.push_str(&format!(" ({})", worktype));
+        }
+        Some(path)
+    }
+
+    /// Messages of the errors which caused this one, outermost first
+    pub fn causes(&self) -> Vec<String> {
+        let mut causes = Vec::new();
+        let mut source = self.source();
+        while let Some(e) = source {
+            causes.push(e.to_string());
+            source = e.source();
+        }
+        causes
+    }
+
+    /// Records where the error occurred, unless an inner step already did
+    pub fn with_location<S: Into<String>, P: Into<String>>(mut self, step: S, package: P) -> CrawlError {
+        if self.step.is_none() {
+            self.step = Some(step.into());
+            self.package = Some(package.into());
+        }
+        self
+    }
+
+    /// Records the target and type of the step where the error occurred,
+    /// unless an inner step already did
+    pub fn with_step_info<T: Into<String>, W: Into<String>>(mut self, target: T, worktype: W) -> CrawlError {
+        if self.target.is_none() {
+            self.target = Some(target.into());
+            self.worktype = Some(worktype.into());
+        }
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> CrawlError {
+        self.retries = retries;
+        self
     }
 }
 
@@ -31,15 +144,42 @@ impl fmt::Display for CrawlError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "CrawlError<")?;
         match &self.kind {
-            CrawlErrorKind::Conveyor(s) => write!(f, "Conveyor({})", s),
+            CrawlErrorKind::Unknown => write!(f, "Unknown"),
+            CrawlErrorKind::Conveyor(e) => write!(f, "Conveyor({})", e),
+            CrawlErrorKind::Error(e) => write!(f, "Error({})", e),
             CrawlErrorKind::NotFound(s) => write!(f, "NotFound({})", s),
-            _ => write!(f, "Unknown"),
+            CrawlErrorKind::Io(e) => write!(f, "Io({})", e),
+            CrawlErrorKind::InvalidDescriptionFile(p) => {
+                write!(f, "InvalidDescriptionFile({})", p.to_string_lossy())
+            }
+            CrawlErrorKind::Template(e) => write!(f, "Template({})", e),
+            CrawlErrorKind::Validation(e) => write!(f, "Validation({})", e),
+            CrawlErrorKind::CyclicImport(p) => write!(
+                f,
+                "CyclicImport({})",
+                p.iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ),
+            CrawlErrorKind::Cancelled => write!(f, "Cancelled"),
         }?;
         write!(f, ">")
     }
 }
 
-impl Error for CrawlError {}
+impl Error for CrawlError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            CrawlErrorKind::Conveyor(e) => Some(e),
+            CrawlErrorKind::Error(e) => Some(e.as_ref()),
+            CrawlErrorKind::Io(e) => Some(e),
+            CrawlErrorKind::Template(e) => Some(e),
+            CrawlErrorKind::Validation(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<CrawlErrorKind> for CrawlError {
     fn from(error: CrawlErrorKind) -> CrawlError {
@@ -49,7 +189,19 @@ impl From<CrawlErrorKind> for CrawlError {
 
 impl From<ConveyorError> for CrawlError {
     fn from(error: ConveyorError) -> CrawlError {
-        CrawlError::new(CrawlErrorKind::Conveyor(error.to_string()))
+        CrawlError::new(CrawlErrorKind::Conveyor(error))
+    }
+}
+
+impl From<TemplateError> for CrawlError {
+    fn from(error: TemplateError) -> CrawlError {
+        CrawlError::new(CrawlErrorKind::Template(error))
+    }
+}
+
+impl From<ValidationErrors> for CrawlError {
+    fn from(error: ValidationErrors) -> CrawlError {
+        CrawlError::new(CrawlErrorKind::Validation(error))
     }
 }
 
@@ -64,4 +216,26 @@ impl From<CrawlError> for ConveyorError {
     fn from(error: CrawlError) -> ConveyorError {
         ConveyorError::new(error)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn location_and_causes() {
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "connection reset");
+        let error = CrawlError::from(io)
+            .with_location("flows[Crawl].work[0]", "https://loppen.dk")
+            .with_step_info("Loppen", "Http")
+            .with_location("steps[0]", "Loppen")
+            .with_retries(2);
+
+        assert_eq!(error.to_string(), "CrawlError<Io(connection reset)>");
+        assert_eq!(error.path().unwrap(), "Loppen > flows[Crawl].work[0] (Http)");
+        assert_eq!(error.package(), Some("https://loppen.dk"));
+        assert_eq!(error.retries(), 2);
+        assert_eq!(error.causes(), vec!["connection reset"]);
+    }
+}
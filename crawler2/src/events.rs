@@ -0,0 +1,204 @@
+use super::context::Args;
+use conveyor::futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Progress of a running target
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TargetStarted {
+        target: String,
+    },
+    TargetFinished {
+        target: String,
+        outputs: usize,
+        errors: usize,
+    },
+    FlowEntered {
+        target: String,
+        flow: String,
+        args: Args,
+    },
+    StepStarted {
+        target: String,
+        step: String,
+        package: String,
+    },
+    StepFinished {
+        target: String,
+        step: String,
+        package: String,
+        outputs: usize,
+        errors: usize,
+        duration_ms: f64,
+    },
+    PackageEmitted {
+        target: String,
+        step: String,
+        package: String,
+    },
+    Error {
+        target: String,
+        step: String,
+        package: String,
+        kind: String,
+        message: String,
+    },
+}
+
+pub type EventStream = UnboundedReceiver<Event>;
+
+enum Subscriber {
+    Channel(UnboundedSender<Event>),
+    Callback(Arc<Fn(&Event) + Send + Sync>),
+}
+
+#[derive(Default)]
+struct EventsInner {
+    subscribers: Mutex<Vec<Subscriber>>,
+    parent: Option<Events>,
+}
+
+/// Dispatches events to subscribers. Events emitted on a child are
+/// also dispatched to the subscribers of its parent.
+#[derive(Clone, Default)]
+pub struct Events {
+    inner: Arc<EventsInner>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    pub fn child(&self) -> Events {
+        Events {
+            inner: Arc::new(EventsInner {
+                subscribers: Mutex::new(Vec::new()),
+                parent: Some(self.clone()),
+            }),
+        }
+    }
+
+    /// Returns a stream of all events emitted from now on
+    pub fn subscribe(&self) -> EventStream {
+        let (sender, receiver) = unbounded();
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber::Channel(sender));
+        receiver
+    }
+
+    /// Calls `observer` with every event emitted from now on
+    pub fn observe<F: Fn(&Event) + Send + Sync + 'static>(&self, observer: F) {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber::Callback(Arc::new(observer)));
+    }
+
+    /// Returns true when anyone listens, so callers can skip building events
+    pub fn is_observed(&self) -> bool {
+        !self.inner.subscribers.lock().unwrap().is_empty()
+            || self.inner.parent.as_ref().map_or(false, |p| p.is_observed())
+    }
+
+    /// Callbacks are called outside the lock, so they may emit events or
+    /// subscribe themselves
+    pub fn emit(&self, event: Event) {
+        let mut callbacks = Vec::new();
+        {
+            let mut subscribers = self.inner.subscribers.lock().unwrap();
+            // Streams which have been dropped are removed
+            subscribers.retain(|s| match s {
+                Subscriber::Channel(sender) => sender.unbounded_send(event.clone()).is_ok(),
+                Subscriber::Callback(callback) => {
+                    callbacks.push(callback.clone());
+                    true
+                }
+            });
+        }
+        for callback in callbacks {
+            callback(&event);
+        }
+        if let Some(parent) = &self.inner.parent {
+            parent.emit(event);
+        }
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Events({} subscribers)",
+            self.inner.subscribers.lock().unwrap().len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn started(target: &str) -> Event {
+        Event::TargetStarted {
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn subscribe() {
+        let events = Events::new();
+        let child = events.child();
+        assert!(!child.is_observed());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let s = seen.clone();
+        events.observe(move |e| s.lock().unwrap().push(e.clone()));
+        let mut stream = child.subscribe();
+        assert!(child.is_observed());
+
+        child.emit(started("Loppen"));
+        events.emit(started("Other"));
+
+        assert_eq!(*seen.lock().unwrap(), vec![started("Loppen"), started("Other")]);
+        assert_eq!(stream.try_next().unwrap(), Some(started("Loppen")));
+        assert!(stream.try_next().is_err());
+
+        drop(stream);
+        child.emit(started("Loppen"));
+        assert!(child.inner.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reentrant_callback() {
+        let events = Events::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (e, s) = (events.clone(), seen.clone());
+        events.observe(move |event| {
+            s.lock().unwrap().push(event.clone());
+            if let Event::TargetStarted { target } = event {
+                if target == "Loppen" {
+                    e.emit(started("Nested"));
+                }
+            }
+        });
+
+        events.emit(started("Loppen"));
+        assert_eq!(*seen.lock().unwrap(), vec![started("Loppen"), started("Nested")]);
+    }
+
+    #[test]
+    fn serialize() {
+        assert_eq!(
+            serde_json::to_value(started("Loppen")).unwrap(),
+            serde_json::json!({"event": "target_started", "target": "Loppen"})
+        );
+    }
+}
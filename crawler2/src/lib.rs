@@ -18,16 +18,28 @@ extern crate derive_builder;
 #[macro_use]
 #[macro_export]
 pub mod macros;
+pub mod cancel;
+pub mod checkpoint;
+pub mod content;
 pub mod context;
 pub mod descriptions;
 pub mod environment;
 pub mod error;
+pub mod events;
+pub mod metrics;
 pub mod target;
+pub mod template;
 pub mod traits;
 pub mod utils;
 mod work;
 pub mod worktypes;
 pub mod repository;
+pub mod scheduler;
+pub mod schema;
+pub mod secrets;
+pub mod package;
+pub mod predicate;
+pub mod report;
 
 pub mod prelude {
     pub use super::context::*;
@@ -38,6 +50,13 @@ pub mod prelude {
     pub use super::worktypes;
     pub use serde_json::Value;
     pub use super::repository::*;
+    pub use super::package::*;
+    pub use super::predicate::*;
+    pub use super::report::*;
+    pub use super::events::*;
+    pub use super::cancel::*;
+    pub use super::checkpoint::*;
+    pub use super::scheduler::*;
 }
 
 #[cfg(test)]
@@ -52,43 +71,39 @@ mod tests {
     fn it_works() {
         let desc = TargetDescription {
             name: "Loppen".to_string(),
+            imports: Vec::new(),
             work: WorkTargetDescription {
                 input: Value::String("https://loppen.dk".to_string()),
-                steps: vec![WorkDescription {
-                    name: Some("description".to_string()),
-                    work: Box::new(worktypes::Flow {
-                        flow_name: "Crawl".to_string(),
-
-                        arguments: Some(args! {
-                            "script" => "file://./index.js"
-                        }),
+                steps: vec![WorkDescription::new(worktypes::Flow {
+                    flow_name: "Crawl".to_string(),
+                    arguments: Some(args! {
+                        "script" => "file://./index.js"
                     }),
-
-                    then: None,
-                }],
+                })
+                .build()
+                .unwrap()],
             },
             flows: vec![FlowDescription {
                 name: "Crawl".to_string(),
                 work: vec![
-                    WorkDescription {
-                        name: None,
-                        work: Box::new(worktypes::Http {
-                            method: Some(Method::GET),
-                        }),
-                        then: None,
-                    },
-                    WorkDescription {
-                        name: None,
-                        work: Box::new(worktypes::Duktape {
-                            script: "$script".to_string(),
+                    WorkDescription::new(worktypes::Http {
+                        method: Some(Method::GET),
+                        headers: Default::default(),
+                        body: None,
+                    })
+                    .build()
+                    .unwrap(),
+                    WorkDescription::new(worktypes::Duktape {
+                        script: "$script".to_string(),
+                    })
+                    .then(Some(Box::new(worktypes::Flow {
+                        flow_name: "Crawl".to_string(),
+                        arguments: Some(args! {
+                            "script" => "file://./concert.js"
                         }),
-                        then: Some(Box::new(worktypes::Flow {
-                            flow_name: "Crawl".to_string(),
-                            arguments: Some(args! {
-                                "script" => "file://./concert.js"
-                            }),
-                        })),
-                    },
+                    }) as Box<WorkType>))
+                    .build()
+                    .unwrap(),
                 ],
             }],
         };
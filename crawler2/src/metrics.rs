@@ -0,0 +1,408 @@
+//! Prometheus style metrics for crawls.
+//!
+//! Every `Environment` owns a `Metrics` registry. It can be exported in the
+//! Prometheus text format with `MetricsExporter::file` or `MetricsExporter::serve`.
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Histogram,
+}
+
+#[derive(Debug)]
+pub struct Descriptor {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub labels: &'static [&'static str],
+}
+
+pub static HTTP_REQUESTS: Descriptor = Descriptor {
+    name: "crawler_http_requests_total",
+    help: "HTTP requests made",
+    kind: MetricKind::Counter,
+    labels: &["host"],
+};
+
+pub static HTTP_RESPONSES: Descriptor = Descriptor {
+    name: "crawler_http_responses_total",
+    help: "HTTP responses received by status code, `error` when no response was received",
+    kind: MetricKind::Counter,
+    labels: &["status"],
+};
+
+pub static HTTP_DURATION: Descriptor = Descriptor {
+    name: "crawler_http_response_seconds",
+    help: "Time until the response headers were received",
+    kind: MetricKind::Histogram,
+    labels: &["host"],
+};
+
+pub static HTTP_BYTES: Descriptor = Descriptor {
+    name: "crawler_http_response_bytes_total",
+    help: "Bytes downloaded",
+    kind: MetricKind::Counter,
+    labels: &["host"],
+};
+
+pub static STEP_PACKAGES: Descriptor = Descriptor {
+    name: "crawler_step_packages_total",
+    help: "Packages produced by a step",
+    kind: MetricKind::Counter,
+    labels: &["target", "step"],
+};
+
+pub static PACKAGES_DROPPED: Descriptor = Descriptor {
+    name: "crawler_packages_dropped_total",
+    help: "Packages dropped by filters",
+    kind: MetricKind::Counter,
+    labels: &["target", "step"],
+};
+
+pub static STEP_DURATION: Descriptor = Descriptor {
+    name: "crawler_step_seconds",
+    help: "Execution time of a step",
+    kind: MetricKind::Histogram,
+    labels: &["target", "step"],
+};
+
+pub static FLOW_DURATION: Descriptor = Descriptor {
+    name: "crawler_flow_seconds",
+    help: "Execution time of a flow",
+    kind: MetricKind::Histogram,
+    labels: &["target", "flow"],
+};
+
+pub static SCRIPT_DURATION: Descriptor = Descriptor {
+    name: "crawler_script_seconds",
+    help: "Execution time of scripts",
+    kind: MetricKind::Histogram,
+    labels: &["script"],
+};
+
+pub static ERRORS: Descriptor = Descriptor {
+    name: "crawler_errors_total",
+    help: "Errors by kind and step",
+    kind: MetricKind::Counter,
+    labels: &["target", "kind", "step"],
+};
+
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone)]
+enum Series {
+    Counter(f64),
+    Histogram {
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Family {
+    descriptor: &'static Descriptor,
+    series: BTreeMap<Vec<String>, Series>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    families: Arc<Mutex<BTreeMap<&'static str, Family>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    fn update<F: FnOnce(&mut Series)>(&self, descriptor: &'static Descriptor, labels: &[&str], f: F) {
+        debug_assert_eq!(descriptor.labels.len(), labels.len());
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(descriptor.name).or_insert_with(|| Family {
+            descriptor,
+            series: BTreeMap::new(),
+        });
+        let series = family
+            .series
+            .entry(labels.iter().map(|l| l.to_string()).collect())
+            .or_insert_with(|| match descriptor.kind {
+                MetricKind::Counter => Series::Counter(0.0),
+                MetricKind::Histogram => Series::Histogram {
+                    buckets: vec![0; BUCKETS.len()],
+                    sum: 0.0,
+                    count: 0,
+                },
+            });
+        f(series);
+    }
+
+    pub fn inc(&self, descriptor: &'static Descriptor, labels: &[&str]) {
+        self.add(descriptor, labels, 1.0);
+    }
+
+    pub fn add(&self, descriptor: &'static Descriptor, labels: &[&str], value: f64) {
+        self.update(descriptor, labels, |s| {
+            if let Series::Counter(c) = s {
+                *c += value;
+            }
+        });
+    }
+
+    pub fn observe(&self, descriptor: &'static Descriptor, labels: &[&str], duration: Duration) {
+        let value = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        self.update(descriptor, labels, |s| {
+            if let Series::Histogram {
+                buckets,
+                sum,
+                count,
+            } = s
+            {
+                for (i, le) in BUCKETS.iter().enumerate() {
+                    if value <= *le {
+                        buckets[i] += 1;
+                    }
+                }
+                *sum += value;
+                *count += 1;
+            }
+        });
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for family in families.values() {
+            let d = family.descriptor;
+            let kind = match d.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Histogram => "histogram",
+            };
+            writeln!(out, "# HELP {} {}", d.name, d.help).unwrap();
+            writeln!(out, "# TYPE {} {}", d.name, kind).unwrap();
+
+            for (values, series) in &family.series {
+                let labels = d
+                    .labels
+                    .iter()
+                    .zip(values)
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                    .collect::<Vec<_>>();
+                match series {
+                    Series::Counter(c) => {
+                        writeln!(out, "{}{} {}", d.name, braces(&labels), c).unwrap()
+                    }
+                    Series::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    } => {
+                        let les = BUCKETS
+                            .iter()
+                            .map(|b| b.to_string())
+                            .chain(Some("+Inf".to_string()));
+                        let counts = buckets.iter().chain(Some(count));
+                        for (le, n) in les.zip(counts) {
+                            let mut labels = labels.clone();
+                            labels.push(format!("le=\"{}\"", le));
+                            writeln!(out, "{}_bucket{} {}", d.name, braces(&labels), n).unwrap();
+                        }
+                        writeln!(out, "{}_sum{} {}", d.name, braces(&labels), sum).unwrap();
+                        writeln!(out, "{}_count{} {}", d.name, braces(&labels), count).unwrap();
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Writes the metrics to `path`, replacing the file atomically
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        // A suffix, so files differing only in extension do not share one
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.render())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+fn braces(labels: &[String]) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Exports metrics in the background until dropped
+pub struct MetricsExporter {
+    stop: Arc<AtomicBool>,
+    /// Dropped to wake the file exporter when stopping
+    wake: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+    addr: Option<SocketAddr>,
+    file: Option<PathBuf>,
+    metrics: Metrics,
+}
+
+impl MetricsExporter {
+    /// Writes the metrics to `path` every `interval`, and once more when dropped
+    pub fn file<P: Into<PathBuf>>(metrics: Metrics, path: P, interval: Duration) -> MetricsExporter {
+        let path = path.into();
+        let (wake, sleep) = mpsc::channel::<()>();
+
+        let (m, p) = (metrics.clone(), path.clone());
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = sleep.recv_timeout(interval) {
+                m.write_to(&p).ok();
+            }
+        });
+
+        MetricsExporter {
+            stop: Arc::new(AtomicBool::new(false)),
+            wake: Some(wake),
+            thread: Some(thread),
+            addr: None,
+            file: Some(path),
+            metrics,
+        }
+    }
+
+    /// Serves the metrics over HTTP on `addr`, e.g. `127.0.0.1:9090`
+    pub fn serve<A: Into<SocketAddr>>(metrics: Metrics, addr: A) -> io::Result<MetricsExporter> {
+        let listener = TcpListener::bind(addr.into())?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let (s, m) = (stop.clone(), metrics.clone());
+        let thread = thread::spawn(move || {
+            while !s.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((mut stream, _)) => {
+                        stream.set_nonblocking(false).ok();
+                        stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+                        let mut buf = [0; 1024];
+                        stream.read(&mut buf).ok();
+                        let body = m.render();
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                        .ok();
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(50))
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(MetricsExporter {
+            stop,
+            wake: None,
+            thread: Some(thread),
+            addr: Some(addr),
+            file: None,
+            metrics,
+        })
+    }
+
+    /// Address the metrics are served on
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+}
+
+impl Drop for MetricsExporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.wake.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        if let Some(file) = &self.file {
+            self.metrics.write_to(file).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.inc(&HTTP_REQUESTS, &["loppen.dk"]);
+        metrics.add(&HTTP_REQUESTS, &["loppen.dk"], 2.0);
+        metrics.observe(&SCRIPT_DURATION, &["a \"b\".js"], Duration::from_millis(30));
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE crawler_http_requests_total counter\n"));
+        assert!(out.contains("crawler_http_requests_total{host=\"loppen.dk\"} 3\n"));
+        assert!(out.contains("crawler_script_seconds_bucket{script=\"a \\\"b\\\".js\",le=\"0.025\"} 0\n"));
+        assert!(out.contains("crawler_script_seconds_bucket{script=\"a \\\"b\\\".js\",le=\"0.05\"} 1\n"));
+        assert!(out.contains("crawler_script_seconds_bucket{script=\"a \\\"b\\\".js\",le=\"+Inf\"} 1\n"));
+        assert!(out.contains("crawler_script_seconds_count{script=\"a \\\"b\\\".js\"} 1\n"));
+    }
+
+    #[test]
+    fn write_file() {
+        let dir = std::env::temp_dir().join(format!("crawler2-metrics-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let metrics = Metrics::new();
+        metrics.inc(&HTTP_REQUESTS, &["loppen.dk"]);
+
+        let exporter = MetricsExporter::file(metrics.clone(), dir.join("metrics.prom"), Duration::from_secs(60));
+        metrics.write_to(dir.join("metrics.txt")).unwrap();
+        drop(exporter);
+
+        let mut names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["metrics.prom", "metrics.txt"]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn serve() {
+        let metrics = Metrics::new();
+        metrics.inc(&HTTP_REQUESTS, &["loppen.dk"]);
+        let exporter = MetricsExporter::serve(metrics, ([127, 0, 0, 1], 0)).unwrap();
+
+        let mut stream = TcpStream::connect(exporter.addr().unwrap()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("crawler_http_requests_total{host=\"loppen.dk\"} 1"));
+    }
+}
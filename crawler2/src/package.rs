@@ -0,0 +1,107 @@
+use super::content;
+use conveyor_work::package::Package;
+use std::collections::BTreeMap;
+use typemap::Key;
+
+/// Metadata key holding the MIME type of a package's content
+pub struct ContentType;
+
+impl Key for ContentType {
+    type Value = String;
+}
+
+/// Metadata key holding the status code of the HTTP response a package came from
+pub struct HttpStatus;
+
+impl Key for HttpStatus {
+    type Value = u16;
+}
+
+/// Metadata key holding named values, which scripts set on their packages
+/// and `meta` predicates match
+pub struct Metadata;
+
+impl Key for Metadata {
+    type Value = BTreeMap<String, String>;
+}
+
+pub trait PackageExt {
+    fn content_type(&self) -> Option<&str>;
+    fn set_content_type<S: AsRef<str>>(&mut self, content_type: S);
+    fn with_content_type<S: AsRef<str>>(self, content_type: S) -> Self;
+    fn http_status(&self) -> Option<u16>;
+    fn with_http_status(self, status: u16) -> Self;
+    fn metadata(&self, key: &str) -> Option<&str>;
+    fn set_metadata<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V);
+    fn with_metadata<K: Into<String>, V: Into<String>>(self, key: K, value: V) -> Self;
+}
+
+impl PackageExt for Package {
+    fn content_type(&self) -> Option<&str> {
+        self.meta().get::<ContentType>().map(|m| m.as_str())
+    }
+
+    fn set_content_type<S: AsRef<str>>(&mut self, content_type: S) {
+        self.meta_mut()
+            .insert::<ContentType>(content_type.as_ref().to_string());
+    }
+
+    fn with_content_type<S: AsRef<str>>(mut self, content_type: S) -> Self {
+        self.set_content_type(content_type);
+        self
+    }
+
+    fn http_status(&self) -> Option<u16> {
+        self.meta().get::<HttpStatus>().cloned()
+    }
+
+    fn with_http_status(mut self, status: u16) -> Self {
+        self.meta_mut().insert::<HttpStatus>(status);
+        self
+    }
+
+    fn metadata(&self, key: &str) -> Option<&str> {
+        self.meta()
+            .get::<Metadata>()
+            .and_then(|m| m.get(key))
+            .map(|m| m.as_str())
+    }
+
+    fn set_metadata<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.meta_mut()
+            .entry::<Metadata>()
+            .or_insert_with(BTreeMap::new)
+            .insert(key.into(), value.into());
+    }
+
+    fn with_metadata<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.set_metadata(key, value);
+        self
+    }
+}
+
+/// Sets the content type detected from the name of `package` and its
+/// `body`, unless the package has one
+pub(crate) fn detect_content_type(mut package: Package, body: &[u8]) -> Package {
+    if package.content_type().is_none() {
+        if let Some(content_type) = content::detect(package.name(), None, Some(body)) {
+            package.set_content_type(content_type);
+        }
+    }
+    package
+}
+
+/// A package holding `body`, with the name and metadata of `from`
+pub(crate) fn copy_of(from: &Package, body: Vec<u8>) -> Package {
+    let mut package = Package::new(from.name(), body);
+    if let Some(content_type) = from.content_type() {
+        package.set_content_type(content_type);
+    }
+    if let Some(metadata) = from.meta().get::<Metadata>() {
+        package.meta_mut().insert::<Metadata>(metadata.clone());
+    }
+    match from.http_status() {
+        Some(status) => package.with_http_status(status),
+        None => package,
+    }
+}
@@ -0,0 +1,418 @@
+//! Conditions on packages, used by `when` on steps, `Switch` cases and `Filter`.
+use super::content;
+use super::descriptions::Validator;
+use super::error::{CrawlErrorKind, CrawlResult};
+use super::package::PackageExt;
+use conveyor_work::package::Package;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Conditions on a package. Every condition given must hold.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Predicate {
+    /// Regular expression the package name must match
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub name: Option<String>,
+    /// MIME type of the content without parameters, `text/*` matches any text
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub content_type: Option<String>,
+    /// Status of the HTTP response the package came from
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub status: Option<Status>,
+    /// Regular expression the content, read as text, must match
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub content: Option<String>,
+    /// Size of the content in bytes
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub size: Option<Size>,
+    /// A field of the JSON content
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub json: Option<JsonField>,
+    /// Regular expressions the metadata of the package must match, by key
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub meta: Option<BTreeMap<String, String>>,
+    /// Holds when any of the predicates holds
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub any: Option<Vec<Predicate>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub not: Option<Box<Predicate>>,
+}
+
+/// A status code (`404`) or a class of them (`2xx`)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Status {
+    Code(u16),
+    Class(String),
+}
+
+impl Status {
+    fn matches(&self, status: u16) -> bool {
+        match self {
+            Status::Code(code) => *code == status,
+            Status::Class(class) => match status_class(class) {
+                Some(c) => status / 100 == c,
+                None => class.parse::<u16>().ok() == Some(status),
+            },
+        }
+    }
+}
+
+fn status_class(class: &str) -> Option<u16> {
+    let class = class.to_ascii_lowercase();
+    if class.len() == 3 && class.ends_with("xx") {
+        class[..1].parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Inclusive bounds on the size of the content
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Size {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max: Option<usize>,
+}
+
+/// Tests the field at `pointer`, e.g. `/data/type`. Without a comparison
+/// the field only has to exist.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JsonField {
+    pub pointer: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub equals: Option<Value>,
+    /// Regular expression a string field must match
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub matches: Option<String>,
+    /// Exclusive bounds on a numeric field
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub greater_than: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub less_than: Option<f64>,
+}
+
+fn regex(pattern: &str) -> CrawlResult<Regex> {
+    Regex::new(pattern).map_err(|e| CrawlErrorKind::Error(Box::new(e)).into())
+}
+
+impl Predicate {
+    pub fn compile(&self) -> CrawlResult<Matcher> {
+        Ok(Matcher {
+            name: match &self.name {
+                Some(n) => Some(regex(n)?),
+                None => None,
+            },
+            content_type: self.content_type.as_ref().map(|c| c.to_ascii_lowercase()),
+            status: self.status.clone(),
+            content: match &self.content {
+                Some(c) => Some(regex(c)?),
+                None => None,
+            },
+            size: self.size,
+            json: match &self.json {
+                Some(j) => Some(JsonMatcher {
+                    pointer: j.pointer.clone(),
+                    equals: j.equals.clone(),
+                    matches: match &j.matches {
+                        Some(m) => Some(regex(m)?),
+                        None => None,
+                    },
+                    greater_than: j.greater_than,
+                    less_than: j.less_than,
+                }),
+                None => None,
+            },
+            meta: match &self.meta {
+                Some(meta) => meta
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), regex(v)?)))
+                    .collect::<CrawlResult<_>>()?,
+                None => Vec::new(),
+            },
+            any: match &self.any {
+                Some(any) => any.iter().map(|p| p.compile()).collect::<CrawlResult<_>>()?,
+                None => Vec::new(),
+            },
+            not: match &self.not {
+                Some(not) => Some(Box::new(not.compile()?)),
+                None => None,
+            },
+        })
+    }
+
+    pub fn validate(&self, validator: &mut Validator, path: &str) {
+        if let Some(name) = &self.name {
+            if let Err(e) = Regex::new(name) {
+                validator.error(format!("{}.name", path), e.to_string());
+            }
+        }
+        if let Some(Err(e)) = self.content.as_ref().map(|c| Regex::new(c)) {
+            validator.error(format!("{}.content", path), e.to_string());
+        }
+        if let Some(Size {
+            min: Some(min),
+            max: Some(max),
+        }) = self.size
+        {
+            if min > max {
+                validator.error(format!("{}.size", path), "min is larger than max");
+            }
+        }
+        if let Some(Status::Class(class)) = &self.status {
+            if status_class(class).is_none() && class.parse::<u16>().is_err() {
+                validator.error(format!("{}.status", path), format!("invalid status \"{}\"", class));
+            }
+        }
+        if let Some(json) = &self.json {
+            if !json.pointer.is_empty() && !json.pointer.starts_with('/') {
+                validator.error(format!("{}.json.pointer", path), "pointer must start with \"/\"");
+            }
+            if let Some(Err(e)) = json.matches.as_ref().map(|m| Regex::new(m)) {
+                validator.error(format!("{}.json.matches", path), e.to_string());
+            }
+        }
+        if let Some(meta) = &self.meta {
+            for (key, pattern) in meta {
+                if let Err(e) = Regex::new(pattern) {
+                    validator.error(format!("{}.meta.{}", path, key), e.to_string());
+                }
+            }
+        }
+        if let Some(any) = &self.any {
+            for (i, p) in any.iter().enumerate() {
+                p.validate(validator, &format!("{}.any[{}]", path, i));
+            }
+        }
+        if let Some(not) = &self.not {
+            not.validate(validator, &format!("{}.not", path));
+        }
+    }
+}
+
+struct JsonMatcher {
+    pointer: String,
+    equals: Option<Value>,
+    matches: Option<Regex>,
+    greater_than: Option<f64>,
+    less_than: Option<f64>,
+}
+
+impl JsonMatcher {
+    fn test(&self, json: Option<&Value>) -> bool {
+        let value = match json.and_then(|j| j.pointer(&self.pointer)) {
+            Some(v) => v,
+            None => return false,
+        };
+        if let Some(equals) = &self.equals {
+            if value != equals {
+                return false;
+            }
+        }
+        if let Some(matches) = &self.matches {
+            match value {
+                Value::String(s) if matches.is_match(s) => {}
+                _ => return false,
+            }
+        }
+        if self.greater_than.is_some() || self.less_than.is_some() {
+            let n = match value.as_f64() {
+                Some(n) => n,
+                None => return false,
+            };
+            if self.greater_than.map(|g| n <= g).unwrap_or(false)
+                || self.less_than.map(|l| n >= l).unwrap_or(false)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The content of a package, read for matchers which need it
+pub struct Content {
+    pub body: Vec<u8>,
+    /// The body decoded to UTF-8
+    pub text: String,
+    /// The body parsed as JSON, if it is valid JSON in any charset
+    pub json: Option<Value>,
+}
+
+impl Content {
+    pub fn new(body: Vec<u8>, content_type: Option<&str>) -> Content {
+        let text = content::decode(&body, content_type).into_owned();
+        let json = serde_json::from_str(&text).ok();
+        Content { body, text, json }
+    }
+}
+
+/// A compiled `Predicate`
+pub struct Matcher {
+    name: Option<Regex>,
+    content_type: Option<String>,
+    status: Option<Status>,
+    content: Option<Regex>,
+    size: Option<Size>,
+    json: Option<JsonMatcher>,
+    meta: Vec<(String, Regex)>,
+    any: Vec<Matcher>,
+    not: Option<Box<Matcher>>,
+}
+
+impl Matcher {
+    /// Whether testing a package needs its content
+    pub fn needs_content(&self) -> bool {
+        self.json.is_some()
+            || self.content.is_some()
+            || self.size.is_some()
+            || self.any.iter().any(|m| m.needs_content())
+            || self.not.as_ref().map(|m| m.needs_content()).unwrap_or(false)
+    }
+
+    /// Tests `package`, whose `content` is given when the matcher needs it
+    pub fn test(&self, package: &Package, content: Option<&Content>) -> bool {
+        if let Some(name) = &self.name {
+            if !name.is_match(package.name()) {
+                return false;
+            }
+        }
+        if let Some(expected) = &self.content_type {
+            let found = match package.content_type() {
+                Some(c) => c.split(';').next().unwrap_or("").trim().to_ascii_lowercase(),
+                None => return false,
+            };
+            let matched = if expected.ends_with("/*") {
+                found.starts_with(&expected[..expected.len() - 1])
+            } else {
+                &found == expected
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(status) = &self.status {
+            match package.http_status() {
+                Some(s) if status.matches(s) => {}
+                _ => return false,
+            }
+        }
+        if let Some(pattern) = &self.content {
+            match content {
+                Some(c) if pattern.is_match(&c.text) => {}
+                _ => return false,
+            }
+        }
+        if let Some(size) = &self.size {
+            let len = match content {
+                Some(c) => c.body.len(),
+                None => return false,
+            };
+            if size.min.map(|m| len < m).unwrap_or(false) || size.max.map(|m| len > m).unwrap_or(false) {
+                return false;
+            }
+        }
+        if let Some(field) = &self.json {
+            if !field.test(content.and_then(|c| c.json.as_ref())) {
+                return false;
+            }
+        }
+        for (key, pattern) in &self.meta {
+            match package.metadata(key) {
+                Some(value) if pattern.is_match(value) => {}
+                _ => return false,
+            }
+        }
+        if !self.any.is_empty() && !self.any.iter().any(|m| m.test(package, content)) {
+            return false;
+        }
+        if let Some(not) = &self.not {
+            if not.test(package, content) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Reads the content of `package` when `needed`, putting it back in the package
+pub(crate) async fn read_content(
+    mut package: Package,
+    needed: bool,
+) -> CrawlResult<(Package, Option<Content>)> {
+    if !needed {
+        return Ok((package, None));
+    }
+    let body = await!(package.read_content())?;
+    let content = Content::new(body.clone(), package.content_type());
+    Ok((package.set_value(body), Some(content)))
+}
+
+/// Compiles a predicate written in YAML, for tests
+#[cfg(test)]
+pub(crate) fn matcher(yaml: &str) -> Matcher {
+    serde_yaml::from_str::<Predicate>(yaml).unwrap().compile().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn conditions() {
+        let package = Package::new("https://loppen.dk/koncert/1", "")
+            .with_content_type("text/html; charset=utf-8")
+            .with_http_status(200);
+
+        assert!(matcher("name: /koncert/").test(&package, None));
+        assert!(!matcher("name: /nyhed/").test(&package, None));
+        assert!(matcher("content_type: text/*").test(&package, None));
+        assert!(!matcher("content_type: application/json").test(&package, None));
+        assert!(matcher("status: 2xx").test(&package, None));
+        assert!(!matcher("status: 404").test(&package, None));
+        assert!(matcher("{name: koncert, status: 200}").test(&package, None));
+        assert!(matcher("any: [{status: 404}, {name: koncert}]").test(&package, None));
+        assert!(!matcher("not: {name: koncert}").test(&package, None));
+    }
+
+    #[test]
+    fn meta() {
+        let package = Package::new("event.json", "").with_metadata("source", "loppen");
+        assert!(matcher("meta: {source: ^loppen$}").test(&package, None));
+        assert!(!matcher("meta: {source: vega}").test(&package, None));
+        assert!(!matcher("meta: {venue: .*}").test(&package, None));
+        assert!(!matcher("meta: {source: loppen}").needs_content());
+    }
+
+    #[test]
+    fn json_field() {
+        let package = Package::new("event.json", "");
+        let content = Content::new(br#"{"data": {"type": "concert", "id": 12}}"#.to_vec(), None);
+        let json = Some(&content);
+        let when = matcher("json: {pointer: /data/type, equals: concert}");
+        assert!(when.needs_content());
+        assert!(when.test(&package, json));
+        assert!(!when.test(&package, None));
+        assert!(matcher("json: {pointer: /data/id}").test(&package, json));
+        assert!(matcher("json: {pointer: /data/type, matches: ^con}").test(&package, json));
+        assert!(!matcher("json: {pointer: /data/venue}").test(&package, json));
+        assert!(matcher("json: {pointer: /data/id, greater_than: 10, less_than: 13}").test(&package, json));
+        assert!(!matcher("json: {pointer: /data/id, greater_than: 12}").test(&package, json));
+    }
+
+    #[test]
+    fn content() {
+        let package = Package::new("index.html", "");
+        let content = Content::new(b"<h1>Koncert</h1>".to_vec(), None);
+        assert!(matcher("content: <h1>").test(&package, Some(&content)));
+        assert!(!matcher("content: <h2>").test(&package, Some(&content)));
+        assert!(matcher("size: {min: 1, max: 16}").test(&package, Some(&content)));
+        assert!(!matcher("size: {max: 15}").test(&package, Some(&content)));
+
+        let latin1 = Content::new(b"Koncert p\xe5 Loppen".to_vec(), Some("text/plain; charset=iso-8859-1"));
+        assert!(matcher("content: på").test(&package, Some(&latin1)));
+    }
+}
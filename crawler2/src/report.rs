@@ -0,0 +1,330 @@
+use super::error::{CrawlError, CrawlResult};
+use super::secrets::Secrets;
+use chrono::{DateTime, Utc};
+use conveyor_work::package::Package;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of output names, and of errors of each kind, the report of a
+/// streamed run keeps
+pub const STREAM_SAMPLE_SIZE: usize = 100;
+
+pub(crate) fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+/// Execution counts and timings of a step or flow
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Timing {
+    pub count: u64,
+    pub outputs: u64,
+    pub errors: u64,
+    pub total_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Timing {
+    fn add(&mut self, duration: Duration, outputs: usize, errors: usize) {
+        let ms = millis(duration);
+        if self.count == 0 || ms < self.min_ms {
+            self.min_ms = ms;
+        }
+        if ms > self.max_ms {
+            self.max_ms = ms;
+        }
+        self.count += 1;
+        self.outputs += outputs as u64;
+        self.errors += errors as u64;
+        self.total_ms += ms;
+    }
+}
+
+/// A failed package, as written to reports and handed to `on_error` steps
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorRecord {
+    pub kind: String,
+    pub message: String,
+    /// Messages of the underlying errors, outermost first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worktype: Option<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub retries: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl ErrorRecord {
+    /// Secret values are redacted from the messages and package name
+    pub fn new(error: &CrawlError, secrets: &Secrets) -> ErrorRecord {
+        ErrorRecord {
+            kind: error.kind().name().to_string(),
+            message: secrets.redact(&error.to_string()),
+            causes: error.causes().iter().map(|c| secrets.redact(c)).collect(),
+            package: error.package().map(|p| secrets.redact(p)),
+            target: error.target().map(|t| t.to_string()),
+            step: error.step().map(|s| s.to_string()),
+            worktype: error.worktype().map(|w| w.to_string()),
+            retries: error.retries(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub target: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    pub duration_ms: f64,
+    pub success: bool,
+    /// The run was stopped before it completed
+    pub cancelled: bool,
+    /// Keyed by step path, e.g. `flows[Crawl].work[1]`
+    pub steps: BTreeMap<String, Timing>,
+    pub flows: BTreeMap<String, Timing>,
+    pub bytes_downloaded: u64,
+    /// Number of packages the target produced
+    pub output_count: u64,
+    /// Names of the packages the target produced, see `Reporter::sample`
+    pub outputs: Vec<String>,
+    /// Number of errors of each kind
+    pub error_counts: BTreeMap<String, u64>,
+    /// Errors grouped by kind, see `Reporter::sample`
+    pub errors: BTreeMap<String, Vec<ErrorRecord>>,
+    /// Packages dropped by filters, keyed by step path
+    pub dropped: BTreeMap<String, u64>,
+}
+
+impl RunReport {
+    fn new(target: &str) -> RunReport {
+        RunReport {
+            target: target.to_string(),
+            started: Utc::now(),
+            finished: None,
+            duration_ms: 0.0,
+            success: false,
+            cancelled: false,
+            steps: BTreeMap::new(),
+            flows: BTreeMap::new(),
+            bytes_downloaded: 0,
+            output_count: 0,
+            outputs: Vec::new(),
+            error_counts: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            dropped: BTreeMap::new(),
+        }
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.error_counts.values().sum::<u64>() as usize
+    }
+
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.values().sum()
+    }
+}
+
+/// Collects a `RunReport` while a target runs. Clones share the same report.
+#[derive(Debug, Clone)]
+pub struct Reporter {
+    report: Arc<Mutex<RunReport>>,
+    sample: Arc<AtomicUsize>,
+}
+
+impl Reporter {
+    pub fn new(target: &str) -> Reporter {
+        Reporter {
+            report: Arc::new(Mutex::new(RunReport::new(target))),
+            sample: Arc::new(AtomicUsize::new(usize::max_value())),
+        }
+    }
+
+    /// Keeps only the first `size` output names, and errors of each kind, in
+    /// the report. Every result is still counted.
+    pub fn sample(&self, size: usize) {
+        self.sample.store(size, Ordering::SeqCst);
+    }
+
+    pub fn start(&self) {
+        self.report.lock().unwrap().started = Utc::now();
+    }
+
+    pub fn step(&self, path: &str, duration: Duration, outputs: usize, errors: usize) {
+        let mut report = self.report.lock().unwrap();
+        report
+            .steps
+            .entry(path.to_string())
+            .or_insert_with(Timing::default)
+            .add(duration, outputs, errors);
+    }
+
+    pub fn flow(&self, name: &str, duration: Duration, outputs: usize, errors: usize) {
+        let mut report = self.report.lock().unwrap();
+        report
+            .flows
+            .entry(name.to_string())
+            .or_insert_with(Timing::default)
+            .add(duration, outputs, errors);
+    }
+
+    pub fn dropped(&self, path: &str) {
+        *self
+            .report
+            .lock()
+            .unwrap()
+            .dropped
+            .entry(path.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn bytes(&self, count: usize) {
+        self.report.lock().unwrap().bytes_downloaded += count as u64;
+    }
+
+    /// Completes the report with the final results of the run.
+    /// Secret values are redacted from error messages.
+    pub fn finish(&self, results: &[CrawlResult<Package>], secrets: &Secrets) -> RunReport {
+        {
+            let mut report = self.report.lock().unwrap();
+            report.output_count = 0;
+            report.outputs.clear();
+            report.error_counts.clear();
+            report.errors.clear();
+        }
+        for result in results {
+            self.result(result, secrets);
+        }
+        self.complete()
+    }
+
+    /// Records a final result of the run as it is produced
+    pub fn result(&self, result: &CrawlResult<Package>, secrets: &Secrets) {
+        let sample = self.sample.load(Ordering::SeqCst);
+        let mut report = self.report.lock().unwrap();
+        match result {
+            Ok(package) => {
+                report.output_count += 1;
+                if report.outputs.len() < sample {
+                    report.outputs.push(package.name().to_string());
+                }
+            }
+            Err(e) => {
+                let kind = e.kind().name();
+                *report.error_counts.entry(kind.to_string()).or_insert(0) += 1;
+                let errors = report.errors.entry(kind.to_string()).or_insert_with(Vec::new);
+                if errors.len() < sample {
+                    errors.push(ErrorRecord::new(e, secrets));
+                }
+            }
+        }
+    }
+
+    /// Completes the report once every result has been recorded with `result`
+    pub fn complete(&self) -> RunReport {
+        let mut report = self.report.lock().unwrap();
+        let now = Utc::now();
+        report.finished = Some(now);
+        report.duration_ms = millis(
+            now.signed_duration_since(report.started)
+                .to_std()
+                .unwrap_or_default(),
+        );
+        report.success = report.error_counts.is_empty();
+        report.clone()
+    }
+
+    pub fn report(&self) -> RunReport {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::error::CrawlErrorKind;
+    use super::*;
+
+    #[test]
+    fn timings() {
+        let reporter = Reporter::new("Loppen");
+        reporter.step("work.steps[0]", Duration::from_millis(20), 2, 0);
+        reporter.step("work.steps[0]", Duration::from_millis(10), 1, 1);
+        reporter.bytes(512);
+        reporter.bytes(512);
+        reporter.dropped("work.steps[1]");
+
+        let report = reporter.report();
+        let step = &report.steps["work.steps[0]"];
+        assert_eq!(step.count, 2);
+        assert_eq!(step.outputs, 3);
+        assert_eq!(step.errors, 1);
+        assert_eq!(step.min_ms, 10.0);
+        assert_eq!(step.max_ms, 20.0);
+        assert_eq!(step.total_ms, 30.0);
+        assert_eq!(report.bytes_downloaded, 1024);
+        assert_eq!(report.dropped_count(), 1);
+    }
+
+    #[test]
+    fn grouped_errors() {
+        let mut file = std::collections::HashMap::new();
+        file.insert("TOKEN".to_string(), "s3cr3t".to_string());
+        let secrets = Secrets::new(file);
+
+        let reporter = Reporter::new("Loppen");
+        let results = vec![
+            Ok(Package::new("index.json", "{}")),
+            Err(CrawlError::new(CrawlErrorKind::NotFound("s3cr3t".to_string()))
+                .with_location("flows[Crawl].work[0]", "https://loppen.dk")),
+            Err(CrawlErrorKind::Unknown.into()),
+        ];
+
+        let report = reporter.finish(&results, &secrets);
+        assert!(!report.success);
+        assert_eq!(report.outputs, vec!["index.json"]);
+        assert_eq!(report.error_count(), 2);
+        assert_eq!(
+            report.errors["NotFound"][0],
+            ErrorRecord {
+                kind: "NotFound".to_string(),
+                message: "CrawlError<NotFound([REDACTED])>".to_string(),
+                causes: Vec::new(),
+                package: Some("https://loppen.dk".to_string()),
+                target: None,
+                step: Some("flows[Crawl].work[0]".to_string()),
+                worktype: None,
+                retries: 0,
+            }
+        );
+        assert_eq!(report.errors["Unknown"].len(), 1);
+    }
+
+    #[test]
+    fn sample() {
+        let reporter = Reporter::new("Loppen");
+        reporter.sample(2);
+        for i in 0..5 {
+            reporter.result(&Ok(Package::new(&format!("{}.json", i), "{}")), &Secrets::default());
+            reporter.result(&Err(CrawlErrorKind::Unknown.into()), &Secrets::default());
+        }
+
+        let report = reporter.complete();
+        assert!(!report.success);
+        assert_eq!(report.output_count, 5);
+        assert_eq!(report.outputs, vec!["0.json", "1.json"]);
+        assert_eq!(report.error_count(), 5);
+        assert_eq!(report.errors["Unknown"].len(), 2);
+    }
+}
@@ -1,10 +1,51 @@
 use super::environment::*;
 use super::target::*;
-use super::error::CrawlResult;
+use super::error::{CrawlError, CrawlErrorKind, CrawlResult};
+use conveyor::futures::prelude::*;
+use conveyor::ConcurrentStream;
+use conveyor_work::package::Package;
 use std::sync::Arc;
-use std::path::Path;
-use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use super::context::Args;
+use super::cancel::Cancellation;
+use super::checkpoint::Checkpoint;
+use super::events::{Event, EventStream};
+use super::metrics::{Metrics, MetricsExporter};
+use super::report::RunReport;
+
+/// Result of running a single target
+#[derive(Debug)]
+pub struct TargetOutcome {
+    pub name: String,
+    pub outputs: Vec<Package>,
+    pub errors: Vec<CrawlError>,
+    pub report: RunReport,
+}
+
+impl TargetOutcome {
+    fn new(name: String, results: Vec<CrawlResult<Package>>, report: RunReport) -> TargetOutcome {
+        let mut outputs = Vec::new();
+        let mut errors = Vec::new();
+        for r in results {
+            match r {
+                Ok(p) => outputs.push(p),
+                Err(e) => errors.push(e),
+            }
+        }
+        TargetOutcome {
+            name,
+            outputs,
+            errors,
+            report,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
 
 #[derive(Debug)]
 pub struct Engine {
@@ -20,6 +61,39 @@ impl Engine {
         }
     }
 
+    pub fn env(&self) -> &Environment {
+        &self.env
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        self.env.metrics()
+    }
+
+    /// Serves the metrics of the engine over HTTP until the exporter is dropped
+    pub fn serve_metrics<A: Into<SocketAddr>>(&self, addr: A) -> CrawlResult<MetricsExporter> {
+        Ok(MetricsExporter::serve(self.metrics().clone(), addr)?)
+    }
+
+    /// Writes the metrics of the engine to `path` every `interval` until the exporter is dropped
+    pub fn write_metrics<P: Into<PathBuf>>(&self, path: P, interval: Duration) -> MetricsExporter {
+        MetricsExporter::file(self.metrics().clone(), path, interval)
+    }
+
+    /// Cancels all targets run by the engine, see `Cancellation`
+    pub fn cancellation(&self) -> &Cancellation {
+        self.env.cancellation()
+    }
+
+    /// Returns a stream of the events of all targets run by the engine
+    pub fn subscribe(&self) -> EventStream {
+        self.env.events().subscribe()
+    }
+
+    /// Calls `observer` with the events of all targets run by the engine
+    pub fn observe<F: Fn(&Event) + Send + Sync + 'static>(&self, observer: F) {
+        self.env.events().observe(observer)
+    }
+
     pub fn add_target(&mut self, target: Target) -> &mut Self {
         self.targets.push(target);
         self
@@ -31,17 +105,250 @@ impl Engine {
         Ok(self)
     }
 
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+
+    pub fn target_names(&self) -> Vec<&str> {
+        self.targets.iter().map(|m| m.description().name.as_str()).collect()
+    }
 
-    pub async fn run<S: AsRef<str> + 'static>(&self, name: S, args: Args) -> CrawlResult<()> {
+    pub fn target<S: AsRef<str>>(&self, name: S) -> CrawlResult<&Target> {
         let name = name.as_ref();
-        let p = match self.targets.iter().find(|m| m.description().name.as_str() == name) {
-            Some(p) => p.clone().build(args)?,
-            None => unimplemented!("not found")
-        };
+        match self.targets.iter().find(|m| m.description().name.as_str() == name) {
+            Some(t) => Ok(t),
+            None => Err(CrawlErrorKind::NotFound(format!("target: {}", name)).into()),
+        }
+    }
+
+    pub async fn run<S: AsRef<str> + 'static>(&self, name: S, args: Args) -> CrawlResult<TargetOutcome> {
+        let name = name.as_ref().to_string();
+        let runner = self.target(&name)?.clone().build(args)?;
+        await!(run_target(name, Ok(runner))).1
+    }
 
-        let ret = await!(p.run())?;
+    /// Runs the named target, yielding its results as they are produced,
+    /// see `TargetRunner::run_stream`
+    pub fn run_stream<S: AsRef<str>>(&self, name: S, args: Args, buffer: usize) -> CrawlResult<ResultStream> {
+        Ok(self.target(name)?.clone().build(args)?.run_stream(buffer))
+    }
+
+    /// Runs the named targets with at most `concurrency` targets at a time.
+    /// Each outcome is paired with the name of its target.
+    pub async fn run_many<S: AsRef<str>>(
+        &self,
+        names: &[S],
+        args: Args,
+        concurrency: usize,
+    ) -> Vec<(String, CrawlResult<TargetOutcome>)> {
+        await!(self.run_runners(names, args, concurrency, None))
+    }
+
+    /// Like `run_many`, but each target keeps a checkpoint in a directory
+    /// named after it inside `dir`. With `resume` the targets continue where
+    /// the previous run stopped.
+    pub async fn run_resumable<S: AsRef<str>>(
+        &self,
+        names: &[S],
+        args: Args,
+        concurrency: usize,
+        dir: &Path,
+        resume: bool,
+    ) -> Vec<(String, CrawlResult<TargetOutcome>)> {
+        await!(self.run_runners(names, args, concurrency, Some((dir, resume))))
+    }
+
+    async fn run_runners<S: AsRef<str>>(
+        &self,
+        names: &[S],
+        args: Args,
+        concurrency: usize,
+        checkpoint: Option<(&Path, bool)>,
+    ) -> Vec<(String, CrawlResult<TargetOutcome>)> {
+        let runners = names
+            .iter()
+            .map(|name| {
+                let name = name.as_ref().to_string();
+                let runner = self.target(&name).and_then(|t| match checkpoint {
+                    Some((dir, resume)) => {
+                        let checkpoint = Checkpoint::open(dir.join(&name), resume)?;
+                        t.clone().build_resumable(args.clone(), checkpoint)
+                    }
+                    None => t.clone().build(args.clone()),
+                });
+                (name, runner)
+            })
+            .collect::<Vec<_>>();
+
+        let stream = stream::iter(runners);
+        await!(ConcurrentStream::new(
+            stream.map(|(name, runner)| run_target(name, runner)),
+            std::cmp::max(concurrency, 1)
+        )
+        .collect())
+    }
 
-        Ok(())
+    pub async fn run_all(&self, args: Args, concurrency: usize) -> Vec<(String, CrawlResult<TargetOutcome>)> {
+        let names = self
+            .target_names()
+            .into_iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>();
+        await!(self.run_many(&names, args, concurrency))
     }
+}
+
+async fn run_target(
+    name: String,
+    runner: CrawlResult<TargetRunner>,
+) -> (String, CrawlResult<TargetOutcome>) {
+    let results = match runner {
+        Ok(r) => await!(r.run_report()),
+        Err(e) => Err(e),
+    };
+    let outcome = results.map(|(r, report)| TargetOutcome::new(name.clone(), r, report));
+    (name, outcome)
+}
+
+#[cfg(test)]
+mod tests {
 
+    use super::super::descriptions::{TargetDescription, WorkDescription, WorkTargetDescription};
+    use super::super::utils::station_fn_ctx2;
+    use super::super::work::{WorkBox, WorkOutput};
+    use super::super::worktypes;
+    use super::*;
+    use conveyor::into_box;
+    use slog::{Discard, Logger};
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use tokio::async_await::compat::forward::IntoAwaitable;
+    use tokio::timer::Delay;
+
+    /// Targets running at the same time, and the most seen at once
+    #[derive(Default)]
+    struct InFlight(Mutex<(usize, usize)>);
+
+    /// An engine with a target for each of `names`, which emit their input
+    /// after a short delay
+    fn engine(names: &[&str], in_flight: &Arc<InFlight>) -> Engine {
+        let env = Environment::build("/", Logger::root(Discard, o!())).build();
+        let mut engine = Engine::new(env.clone());
+        for name in names {
+            let service: WorkBox<Package> = into_box(station_fn_ctx2(
+                async move |package: Package, in_flight: Arc<InFlight>| {
+                    {
+                        let mut counts = in_flight.0.lock().unwrap();
+                        counts.0 += 1;
+                        counts.1 = std::cmp::max(counts.0, counts.1);
+                    }
+                    let delay = Delay::new(Instant::now() + Duration::from_millis(20));
+                    await!(delay.into_awaitable()).ok();
+                    in_flight.0.lock().unwrap().0 -= 1;
+                    Ok(vec![WorkOutput::Result(Ok(package))])
+                },
+                in_flight.clone(),
+            ));
+            let step = WorkDescription::new(worktypes::PassThrough {
+                service: Some(Arc::new(service)),
+            })
+            .build()
+            .unwrap();
+            let desc = TargetDescription {
+                name: name.to_string(),
+                imports: Vec::new(),
+                work: WorkTargetDescription {
+                    input: serde_json::Value::String("https://loppen.dk".to_string()),
+                    steps: vec![step],
+                },
+                flows: Vec::new(),
+            };
+            engine.add_target(Target::new("/", env.clone(), desc).unwrap());
+        }
+        engine
+    }
+
+    /// Runs `f` on the engine in a tokio runtime and returns its outcomes
+    fn outcomes<F, Fut>(engine: Engine, f: F) -> Vec<(String, CrawlResult<TargetOutcome>)>
+    where
+        F: FnOnce(Arc<Engine>) -> Fut + Send + 'static,
+        Fut: Future<Output = Vec<(String, CrawlResult<TargetOutcome>)>> + Send + 'static,
+    {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let o = out.clone();
+        let engine = Arc::new(engine);
+        tokio::run_async(async move {
+            let ret = await!(f(engine));
+            *o.lock().unwrap() = ret;
+        });
+        let mut out = out.lock().unwrap();
+        std::mem::replace(&mut *out, Vec::new())
+    }
+
+    /// Names of the outcomes, checking each holds the output of its own target
+    fn names(outcomes: &[(String, CrawlResult<TargetOutcome>)]) -> Vec<String> {
+        let mut names = outcomes
+            .iter()
+            .map(|(name, outcome)| {
+                if let Ok(outcome) = outcome {
+                    assert_eq!(&outcome.name, name);
+                    let outputs = outcome.outputs.iter().map(|p| p.name()).collect::<Vec<_>>();
+                    assert_eq!(outputs, vec![name.as_str()]);
+                }
+                name.clone()
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn target() {
+        let engine = engine(&["Loppen"], &Arc::new(InFlight::default()));
+        assert_eq!(engine.target_names(), vec!["Loppen"]);
+        assert!(engine.target("Loppen").is_ok());
+        match engine.target("Vega") {
+            Err(e) => match e.kind() {
+                CrawlErrorKind::NotFound(name) => assert_eq!(name, "target: Vega"),
+                _ => panic!("expected NotFound, got {}", e),
+            },
+            Ok(_) => panic!("Vega is not a target"),
+        }
+    }
+
+    #[test]
+    fn run() {
+        let engine = engine(&["Loppen"], &Arc::new(InFlight::default()));
+        let ret = outcomes(engine, async move |engine| {
+            vec![("Loppen".to_string(), await!(engine.run("Loppen", Args::new())))]
+        });
+        assert_eq!(names(&ret), vec!["Loppen"]);
+        assert!(ret[0].1.as_ref().unwrap().is_success());
+    }
+
+    #[test]
+    fn run_many() {
+        let in_flight = Arc::new(InFlight::default());
+        let engine = engine(&["A", "B", "C", "D"], &in_flight);
+        let ret = outcomes(engine, async move |engine| {
+            await!(engine.run_many(&["A", "B", "Missing", "C", "D"][..], Args::new(), 2))
+        });
+
+        // Outcomes come as targets finish, each paired with its target
+        assert_eq!(names(&ret), vec!["A", "B", "C", "D", "Missing"]);
+        let missing = ret.iter().find(|(name, _)| name == "Missing").unwrap();
+        assert!(missing.1.is_err());
+        assert_eq!(in_flight.0.lock().unwrap().1, 2);
+    }
+
+    #[test]
+    fn run_all() {
+        let in_flight = Arc::new(InFlight::default());
+        let engine = engine(&["A", "B", "C"], &in_flight);
+        let ret = outcomes(engine, async move |engine| await!(engine.run_all(Args::new(), 1)));
+
+        assert_eq!(names(&ret), vec!["A", "B", "C"]);
+        assert!(ret.iter().all(|(_, outcome)| outcome.as_ref().unwrap().is_success()));
+        assert_eq!(in_flight.0.lock().unwrap().1, 1);
+    }
 }
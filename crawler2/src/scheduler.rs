@@ -0,0 +1,430 @@
+use super::environment::Config;
+use super::error::{CrawlError, CrawlResult};
+use super::work::{route, Routed, Work};
+use conveyor::futures::channel::mpsc::Sender;
+use conveyor::futures::channel::oneshot;
+use conveyor::futures::future::{self, Either};
+use conveyor::futures::prelude::*;
+use conveyor::futures::stream::FuturesUnordered;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_IN_FLIGHT: usize = 4;
+
+/// The order in which queued work is started
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// Work is started in the order it was queued, finishing each level
+    /// of a crawl before going deeper
+    BreadthFirst,
+    /// The most recently queued work is started first, following each
+    /// branch of a crawl to the bottom before the next
+    DepthFirst,
+}
+
+impl Default for Order {
+    fn default() -> Order {
+        Order::BreadthFirst
+    }
+}
+
+#[derive(Default)]
+struct BudgetState {
+    available: usize,
+    /// Places given back by steps which stopped lending theirs early
+    owed: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// The places of running steps, shared by every queue of a scheduler
+#[derive(Default)]
+struct Budget {
+    state: Mutex<BudgetState>,
+}
+
+/// A place held by a running step, given back when dropped
+struct Permit {
+    budget: Option<Arc<Budget>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(budget) = self.budget.take() {
+            Budget::release(&budget);
+        }
+    }
+}
+
+impl Budget {
+    fn new(places: usize) -> Arc<Budget> {
+        Arc::new(Budget {
+            state: Mutex::new(BudgetState {
+                available: places,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Hands a place to the longest waiting queue, or makes it available
+    fn release(budget: &Arc<Budget>) {
+        let mut state = budget.state.lock().unwrap();
+        if state.owed > 0 {
+            state.owed -= 1;
+            return;
+        }
+        while let Some(waiter) = state.waiters.pop_front() {
+            let permit = Permit {
+                budget: Some(budget.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // The queue stopped waiting, the place goes to the next one
+                Err(mut permit) => {
+                    permit.budget.take();
+                }
+            }
+        }
+        state.available += 1;
+    }
+
+    fn try_acquire(budget: &Arc<Budget>) -> Option<Permit> {
+        let mut state = budget.state.lock().unwrap();
+        if state.available == 0 {
+            return None;
+        }
+        state.available -= 1;
+        Some(Permit {
+            budget: Some(budget.clone()),
+        })
+    }
+
+    fn acquire(budget: &Arc<Budget>) -> impl Future<Output = Permit> + Send {
+        let mut state = budget.state.lock().unwrap();
+        if state.available > 0 {
+            state.available -= 1;
+            return Either::Left(future::ready(Permit {
+                budget: Some(budget.clone()),
+            }));
+        }
+        let (sender, receiver) = oneshot::channel();
+        state.waiters.push_back(sender);
+        Either::Right(receiver.map(|permit| permit.unwrap_or(Permit { budget: None })))
+    }
+}
+
+/// Owes a place back to the budget if a step stops lending its place
+/// before taking it back
+struct Lent {
+    budget: Option<Arc<Budget>>,
+}
+
+impl Drop for Lent {
+    fn drop(&mut self) {
+        if let Some(budget) = self.budget.take() {
+            budget.state.lock().unwrap().owed += 1;
+        }
+    }
+}
+
+/// Runs work from a queue with at most `in_flight` steps running at once.
+/// Steps hand the work they produce back to the queue instead of running it
+/// themselves.
+///
+/// Clones share the in-flight limit, so steps which need the results of
+/// other work before they can finish, like `Concat`, run it with
+/// `run_nested` on the same budget.
+#[derive(Clone)]
+pub struct Scheduler {
+    in_flight: usize,
+    order: Order,
+    budget: Arc<Budget>,
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("in_flight", &self.in_flight)
+            .field("order", &self.order)
+            .finish()
+    }
+}
+
+enum Output<V> {
+    Collect(Vec<CrawlResult<V>>),
+    Send(Sender<CrawlResult<V>>),
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new(DEFAULT_IN_FLIGHT, Order::default())
+    }
+}
+
+impl Scheduler {
+    pub fn new(in_flight: usize, order: Order) -> Scheduler {
+        let in_flight = std::cmp::max(in_flight, 1);
+        Scheduler {
+            in_flight,
+            order,
+            budget: Budget::new(in_flight),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Scheduler {
+        Scheduler::new(
+            config.in_flight.unwrap_or(DEFAULT_IN_FLIGHT),
+            config.order.unwrap_or_default(),
+        )
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    pub fn order(&self) -> Order {
+        self.order
+    }
+
+    /// Runs `input` and all the work it produces, returning the results
+    pub fn run<V: 'static + Send + Sync>(
+        &self,
+        input: Vec<Work<V>>,
+    ) -> impl Future<Output = Vec<CrawlResult<V>>> + Send {
+        self.clone()
+            .drive(input, Output::Collect(Vec::new()))
+            .map(|output| match output {
+                Output::Collect(results) => results,
+                Output::Send(_) => unreachable!(),
+            })
+    }
+
+    /// Runs `input` from within a running step, which gives up its place
+    /// to the work until it is done. The work counts against the same
+    /// in-flight limit as the step's own queue.
+    pub fn run_nested<V: 'static + Send + Sync>(
+        &self,
+        input: Vec<Work<V>>,
+    ) -> impl Future<Output = Vec<CrawlResult<V>>> + Send {
+        self.lend(self.run(input))
+    }
+
+    /// Awaits `future` from within a running step, letting other steps
+    /// have the step's place meanwhile
+    pub fn lend<F: Future + Send>(&self, future: F) -> impl Future<Output = F::Output> + Send {
+        let budget = self.budget.clone();
+        async move {
+            Budget::release(&budget);
+            let mut lent = Lent {
+                budget: Some(budget.clone()),
+            };
+            let output = await!(future);
+            // The place is taken back for the step, whose own permit returns it
+            let mut permit = await!(Budget::acquire(&budget));
+            permit.budget.take();
+            lent.budget.take();
+            output
+        }
+    }
+
+    /// Runs `input` and sends the results to `sender` as they are produced.
+    /// No new work is started while the channel is full, and the run stops
+    /// when the receiver is dropped.
+    pub fn run_into<V: 'static + Send + Sync>(
+        &self,
+        input: Vec<Work<V>>,
+        sender: Sender<CrawlResult<V>>,
+    ) -> impl Future<Output = ()> + Send {
+        self.clone().drive(input, Output::Send(sender)).map(|_| ())
+    }
+
+    async fn drive<V: 'static + Send + Sync>(self, input: Vec<Work<V>>, mut output: Output<V>) -> Output<V> {
+        let mut queue = input.into_iter().collect::<VecDeque<_>>();
+        let mut running = FuturesUnordered::new();
+
+        loop {
+            let mut finished = None;
+            if !queue.is_empty() {
+                let permit = match Budget::try_acquire(&self.budget) {
+                    Some(permit) => Some(permit),
+                    None if running.is_empty() => Some(await!(Budget::acquire(&self.budget))),
+                    // Running work is polled while waiting for a place, as it may lend its own
+                    None => match await!(future::select(running.next(), Budget::acquire(&self.budget))) {
+                        Either::Left((f, _)) => {
+                            finished = f;
+                            None
+                        }
+                        Either::Right((permit, _)) => Some(permit),
+                    },
+                };
+                if let Some(permit) = permit {
+                    let work = match self.order {
+                        Order::BreadthFirst => queue.pop_front(),
+                        Order::DepthFirst => queue.pop_back(),
+                    };
+                    if let Some(work) = work {
+                        running.push(work.execute().map(move |ret| (ret, permit)));
+                    }
+                    continue;
+                }
+            }
+
+            let finished = match finished {
+                Some(f) => f,
+                None => match await!(running.next()) {
+                    Some(f) => f,
+                    None => break,
+                },
+            };
+            let ((ret, next, completions), permit) = finished;
+
+            let mut queued = Vec::new();
+            let mut done = Vec::new();
+            match ret {
+                Ok(outputs) => {
+                    for o in outputs {
+                        match route(o, &next, &completions) {
+                            Routed::Done(r) => done.push(r),
+                            Routed::Queued(w) => queued.push(w),
+                        }
+                    }
+                }
+                Err(e) => {
+                    for c in &completions {
+                        c.fail();
+                    }
+                    done.push(Err(CrawlError::from(e)));
+                }
+            }
+            drop(permit);
+
+            match &mut output {
+                Output::Collect(results) => results.extend(done),
+                Output::Send(sender) => {
+                    for r in done {
+                        if await!(sender.send(r)).is_err() {
+                            return output;
+                        }
+                    }
+                }
+            }
+
+            // Keep siblings in the order they were produced
+            match self.order {
+                Order::BreadthFirst => queue.extend(queued),
+                Order::DepthFirst => queue.extend(queued.into_iter().rev()),
+            }
+
+            for c in &completions {
+                c.release();
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::utils::station_fn_ctx2;
+    use super::super::work::WorkOutput;
+    use super::*;
+    use conveyor::futures::channel::mpsc::channel;
+    use conveyor::futures::executor::block_on;
+    use conveyor::futures::future;
+    use conveyor::station_fn;
+
+    fn tree(name: String) -> Work<String> {
+        Work::new(
+            name,
+            station_fn(async move |name: String| {
+                let mut out = vec![WorkOutput::Result(Ok(name.clone()))];
+                if name.len() < 3 {
+                    out.push(WorkOutput::Work(tree(format!("{}a", name))));
+                    out.push(WorkOutput::Work(tree(format!("{}b", name))));
+                }
+                Ok(out)
+            }),
+        )
+    }
+
+    fn names(results: Vec<CrawlResult<String>>) -> Vec<String> {
+        results.into_iter().map(|m| m.unwrap()).collect()
+    }
+
+    #[test]
+    fn breadth_first() {
+        let scheduler = Scheduler::new(1, Order::BreadthFirst);
+        let ret = names(block_on(scheduler.run(vec![tree("r".to_string())])));
+        assert_eq!(ret, vec!["r", "ra", "rb", "raa", "rab", "rba", "rbb"]);
+    }
+
+    #[test]
+    fn run_into() {
+        let (sender, receiver) = channel(1);
+        let scheduler = Scheduler::new(2, Order::BreadthFirst);
+        let run = scheduler.run_into(vec![tree("r".to_string())], sender);
+        let (_, results) = block_on(future::join(run, receiver.collect::<Vec<_>>()));
+        assert_eq!(names(results).len(), 7);
+    }
+
+    #[test]
+    fn nested_runs_share_budget() {
+        // The step gives its only place to the nested run instead of
+        // waiting for a place of its own
+        let scheduler = Scheduler::new(1, Order::BreadthFirst);
+        let work = Work::new(
+            "r".to_string(),
+            station_fn_ctx2(
+                async move |name: String, scheduler: Arc<Scheduler>| {
+                    let ret = await!(scheduler.run_nested(vec![tree(format!("{}a", name))]));
+                    Ok(ret.into_iter().map(WorkOutput::Result).collect())
+                },
+                Arc::new(scheduler.clone()),
+            ),
+        );
+        let ret = names(block_on(scheduler.run(vec![work])));
+        assert_eq!(ret, vec!["ra", "raa", "rab"]);
+        assert_eq!(scheduler.budget.state.lock().unwrap().available, 1);
+    }
+
+    #[test]
+    fn lent_place_starts_queued_work() {
+        // "a" waits for "b", which can only start on the place "a" lends
+        let (sender, receiver) = oneshot::channel::<()>();
+        let scheduler = Scheduler::new(1, Order::BreadthFirst);
+        let a = Work::new(
+            "a".to_string(),
+            station_fn_ctx2(
+                async move |name: String, ctx: Arc<(Scheduler, Mutex<Option<oneshot::Receiver<()>>>)>| {
+                    let receiver = ctx.1.lock().unwrap().take().unwrap();
+                    await!(ctx.0.lend(receiver)).ok();
+                    Ok(vec![WorkOutput::Result(Ok(name))])
+                },
+                Arc::new((scheduler.clone(), Mutex::new(Some(receiver)))),
+            ),
+        );
+        let b = Work::new(
+            "b".to_string(),
+            station_fn_ctx2(
+                async move |name: String, sender: Arc<Mutex<Option<oneshot::Sender<()>>>>| {
+                    sender.lock().unwrap().take().unwrap().send(()).ok();
+                    Ok(vec![WorkOutput::Result(Ok(name))])
+                },
+                Arc::new(Mutex::new(Some(sender))),
+            ),
+        );
+        let ret = names(block_on(scheduler.run(vec![a, b])));
+        assert_eq!(ret, vec!["b", "a"]);
+        assert_eq!(scheduler.budget.state.lock().unwrap().available, 1);
+    }
+
+    #[test]
+    fn depth_first() {
+        let scheduler = Scheduler::new(1, Order::DepthFirst);
+        let ret = names(block_on(scheduler.run(vec![tree("r".to_string())])));
+        assert_eq!(ret, vec!["r", "ra", "raa", "rab", "rb", "rba", "rbb"]);
+    }
+}
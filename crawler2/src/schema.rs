@@ -0,0 +1,436 @@
+//! JSON Schema for target description files.
+//!
+//! Worktypes are registered with typetag, which has no way of describing
+//! them, so each worktype contributes a schema fragment for its own fields.
+//! Crates defining their own worktypes add theirs with [`register_worktype`].
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref WORKTYPES: RwLock<BTreeMap<String, Value>> = RwLock::new(builtin_worktypes());
+}
+
+/// Registers the schema of a worktype. `schema` describes the fields of the
+/// worktype as an object schema, the `type` tag is added automatically.
+pub fn register_worktype<S: Into<String>>(name: S, schema: Value) {
+    WORKTYPES.write().unwrap().insert(name.into(), schema);
+}
+
+/// Names of all worktypes with a registered schema
+pub fn worktypes() -> Vec<String> {
+    WORKTYPES.read().unwrap().keys().cloned().collect()
+}
+
+fn steps() -> Value {
+    json!({
+        "type": "array",
+        "items": { "$ref": "#/definitions/WorkDescription" }
+    })
+}
+
+fn string(description: &str) -> Value {
+    json!({ "type": "string", "description": description })
+}
+
+fn failure_policy() -> Value {
+    json!({
+        "type": "string",
+        "enum": ["fail", "skip", "forward"],
+        "default": "fail",
+        "description": "What to do when one of the inner steps fails"
+    })
+}
+
+fn format() -> Value {
+    json!({ "type": "string", "enum": ["json", "yaml", "csv", "toml", "xml"] })
+}
+
+fn object(properties: Value, required: &[&str]) -> Value {
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+fn builtin_worktypes() -> BTreeMap<String, Value> {
+    let mut m = BTreeMap::new();
+    m.insert(
+        "Flow".to_string(),
+        object(
+            json!({
+                "flow_name": string("Name of the flow to run"),
+                "arguments": { "type": "object", "description": "Arguments passed to the flow" }
+            }),
+            &["flow_name"],
+        ),
+    );
+    m.insert(
+        "Http".to_string(),
+        object(
+            json!({
+                "method": {
+                    "type": "string",
+                    "enum": ["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"]
+                },
+                "headers": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                    "description": "Request headers, values may use ${secret:NAME}"
+                },
+                "body": string("Request body")
+            }),
+            &[],
+        ),
+    );
+    m.insert(
+        "Concat".to_string(),
+        object(
+            json!({
+                "name": string("Name of the concatenated package"),
+                "steps": steps(),
+                "on_failure": failure_policy()
+            }),
+            &["name", "steps"],
+        ),
+    );
+    m.insert(
+        "ConcatJson".to_string(),
+        object(
+            json!({
+                "name": string("Name of the aggregated package"),
+                "steps": steps(),
+                "key": string("Template for the object key of each result, using name, index and value"),
+                "on_failure": failure_policy()
+            }),
+            &["name", "steps"],
+        ),
+    );
+    m.insert("ToJson".to_string(), object(json!({}), &[]));
+    m.insert(
+        "Convert".to_string(),
+        object(
+            json!({
+                "from": format(),
+                "to": format()
+            }),
+            &["to"],
+        ),
+    );
+    m.insert(
+        "Duktape".to_string(),
+        object(
+            json!({ "script": string("Path of the script, relative to the description") }),
+            &["script"],
+        ),
+    );
+    m.insert(
+        "WriteDirectory".to_string(),
+        object(
+            json!({ "path": string("Directory packages are written to") }),
+            &["path"],
+        ),
+    );
+    m.insert(
+        "ChildProcess".to_string(),
+        object(
+            json!({
+                "command": string("Command to run"),
+                "args": { "type": "array", "items": { "type": "string" } }
+            }),
+            &["command"],
+        ),
+    );
+    m.insert("PassThrough".to_string(), object(json!({}), &[]));
+    m.insert(
+        "Split".to_string(),
+        object(
+            json!({
+                "format": { "type": "string", "enum": ["json", "lines"], "default": "json" },
+                "pointer": string("JSON pointer of the array to split"),
+                "name": string("Template for the element names, using name, index and value")
+            }),
+            &[],
+        ),
+    );
+    m.insert(
+        "Batch".to_string(),
+        object(
+            json!({
+                "name": string("Name of the batch packages, using index"),
+                "size": { "type": "integer", "minimum": 1 },
+                "window_ms": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "description": "Time to wait for a batch to fill after its first package"
+                }
+            }),
+            &["name", "size"],
+        ),
+    );
+    m.insert(
+        "Fork".to_string(),
+        object(
+            json!({
+                "branches": {
+                    "type": "object",
+                    "additionalProperties": steps(),
+                    "description": "Named step lists each package is copied to"
+                },
+                "mode": { "type": "string", "enum": ["each", "merge"], "default": "each" },
+                "name": string("Name of the merged package"),
+                "on_failure": failure_policy()
+            }),
+            &["branches"],
+        ),
+    );
+    m.insert(
+        "Filter".to_string(),
+        object(
+            json!({
+                "keep": { "$ref": "#/definitions/Predicate", "description": "Packages to pass on" },
+                "drop": { "$ref": "#/definitions/Predicate", "description": "Packages to drop" }
+            }),
+            &[],
+        ),
+    );
+    m.insert(
+        "Switch".to_string(),
+        object(
+            json!({
+                "cases": {
+                    "type": "array",
+                    "items": object(
+                        json!({
+                            "when": { "$ref": "#/definitions/Predicate" },
+                            "steps": steps()
+                        }),
+                        &["when", "steps"],
+                    )
+                },
+                "default": steps()
+            }),
+            &["cases"],
+        ),
+    );
+    m
+}
+
+fn predicate() -> Value {
+    json!({
+        "type": "object",
+        "description": "Conditions on a package, all of which must hold",
+        "properties": {
+            "name": string("Regular expression the package name must match"),
+            "content_type": string("MIME type of the content, `text/*` matches any text"),
+            "content": string("Regular expression the content must match"),
+            "size": object(
+                json!({
+                    "min": { "type": "integer", "minimum": 0 },
+                    "max": { "type": "integer", "minimum": 0 }
+                }),
+                &[],
+            ),
+            "status": {
+                "oneOf": [
+                    { "type": "integer" },
+                    { "type": "string", "pattern": "^[1-5]([0-9]{2}|xx)$" }
+                ],
+                "description": "HTTP status code, or a class like 2xx"
+            },
+            "json": object(
+                json!({
+                    "pointer": string("JSON pointer of the field, e.g. /data/type"),
+                    "equals": { "description": "Value the field must equal" },
+                    "matches": string("Regular expression a string field must match"),
+                    "greater_than": { "type": "number" },
+                    "less_than": { "type": "number" }
+                }),
+                &["pointer"],
+            ),
+            "meta": {
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+                "description": "Regular expressions the metadata of the package must match, by key"
+            },
+            "any": {
+                "type": "array",
+                "items": { "$ref": "#/definitions/Predicate" }
+            },
+            "not": { "$ref": "#/definitions/Predicate" }
+        }
+    })
+}
+
+/// Adds the `type` discriminator to a worktype fragment
+fn tagged(name: &str, fragment: &Value) -> Value {
+    let mut schema = match fragment {
+        Value::Object(o) => o.clone(),
+        _ => Map::new(),
+    };
+
+    let mut properties = match schema.remove("properties") {
+        Some(Value::Object(o)) => o,
+        _ => Map::new(),
+    };
+    properties.insert("type".to_string(), json!({ "const": name }));
+
+    let mut required = match schema.remove("required") {
+        Some(Value::Array(a)) => a,
+        _ => Vec::new(),
+    };
+    required.insert(0, Value::String("type".to_string()));
+
+    schema.insert("type".to_string(), Value::String("object".to_string()));
+    schema.insert("properties".to_string(), Value::Object(properties));
+    schema.insert("required".to_string(), Value::Array(required));
+    Value::Object(schema)
+}
+
+/// Builds the JSON Schema (draft 07) of a `TargetDescription`
+pub fn target_schema() -> Value {
+    let worktypes = WORKTYPES.read().unwrap();
+
+    let mut definitions = Map::new();
+    for (name, fragment) in worktypes.iter() {
+        definitions.insert(format!("worktype.{}", name), tagged(name, fragment));
+    }
+
+    let refs = worktypes
+        .keys()
+        .map(|name| json!({ "$ref": format!("#/definitions/worktype.{}", name) }))
+        .collect::<Vec<_>>();
+
+    definitions.insert(
+        "WorkType".to_string(),
+        json!({
+            "type": "object",
+            "required": ["type"],
+            "properties": {
+                "type": { "type": "string", "enum": worktypes.keys().collect::<Vec<_>>() }
+            },
+            "oneOf": refs
+        }),
+    );
+    definitions.insert(
+        "WorkDescription".to_string(),
+        json!({
+            "allOf": [
+                { "$ref": "#/definitions/WorkType" },
+                {
+                    "properties": {
+                        "then": {
+                            "$ref": "#/definitions/WorkType",
+                            "description": "Step receiving the packages a step emits with `then`"
+                        },
+                        "when": {
+                            "$ref": "#/definitions/Predicate",
+                            "description": "Packages not matching skip the step"
+                        },
+                        "retries": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "description": "Times a package is retried when the step only produces errors"
+                        },
+                        "on_error": {
+                            "type": "array",
+                            "items": { "$ref": "#/definitions/WorkDescription" },
+                            "description": "Steps receiving a JSON error record for each failed package, whose outputs continue after the step"
+                        }
+                    }
+                }
+            ]
+        }),
+    );
+    definitions.insert("Predicate".to_string(), predicate());
+    definitions.insert(
+        "FlowDescription".to_string(),
+        object(
+            json!({
+                "name": string("Name flows are referenced by"),
+                "work": steps()
+            }),
+            &["name", "work"],
+        ),
+    );
+    definitions.insert(
+        "ImportDescription".to_string(),
+        object(
+            json!({
+                "path": string("File with shared flows, relative to the importing file"),
+                "as": string("Namespace of the imported flows, defaults to the file name")
+            }),
+            &["path"],
+        ),
+    );
+    definitions.insert(
+        "WorkTargetDescription".to_string(),
+        object(
+            json!({
+                "input": { "description": "Content of the initial package" },
+                "steps": steps()
+            }),
+            &["input", "steps"],
+        ),
+    );
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "TargetDescription",
+        "type": "object",
+        "properties": {
+            "name": string("Name of the target"),
+            "imports": {
+                "type": "array",
+                "items": { "$ref": "#/definitions/ImportDescription" }
+            },
+            "work": { "$ref": "#/definitions/WorkTargetDescription" },
+            "flows": {
+                "type": "array",
+                "items": { "$ref": "#/definitions/FlowDescription" }
+            }
+        },
+        "required": ["name", "work", "flows"],
+        "definitions": definitions
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn builtins() {
+        let schema = target_schema();
+        let definitions = schema["definitions"].as_object().unwrap();
+        for name in &["Flow", "Http", "Concat", "ConcatJson", "Convert", "Duktape"] {
+            let worktype = &definitions[&format!("worktype.{}", name)];
+            assert_eq!(worktype["properties"]["type"]["const"], *name);
+            assert_eq!(worktype["required"][0], "type");
+        }
+        assert_eq!(definitions["worktype.Flow"]["required"][1], "flow_name");
+    }
+
+    #[test]
+    fn register() {
+        register_worktype(
+            "Custom",
+            json!({
+                "type": "object",
+                "properties": { "level": { "type": "integer" } }
+            }),
+        );
+        assert!(worktypes().contains(&"Custom".to_string()));
+        let schema = target_schema();
+        assert_eq!(
+            schema["definitions"]["worktype.Custom"]["properties"]["level"]["type"],
+            "integer"
+        );
+        assert!(schema["definitions"]["WorkType"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "$ref": "#/definitions/worktype.Custom" })));
+    }
+}
@@ -0,0 +1,253 @@
+//! Secret variables, referenced in templates as `${secret:NAME}`.
+//!
+//! `${secret:NAME}` is looked up as the environment variable
+//! `CRAWLER_SECRET_NAME`, and then as `NAME` in the secrets file of the
+//! `Environment`. Other environment variables can not be read as secrets.
+//! Every secret value which has been loaded is
+//! redacted from log output by `RedactDrain` and from reports with `redact`.
+use serde_json::Value;
+use slog::{Drain, Key, OwnedKVList, Record, RecordStatic, Serializer, KV};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+pub const REDACTED: &'static str = "[REDACTED]";
+
+/// Prefix of the environment variables holding secrets
+pub const ENV_PREFIX: &'static str = "CRAWLER_SECRET_";
+
+pub trait SecretSource {
+    fn secret(&self, name: &str) -> Option<String>;
+}
+
+/// Used where no secrets are available
+pub struct NoSecrets;
+
+impl SecretSource for NoSecrets {
+    fn secret(&self, _name: &str) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+struct SecretsInner {
+    file: HashMap<String, String>,
+    revealed: RwLock<HashSet<String>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Secrets {
+    inner: Arc<SecretsInner>,
+}
+
+impl Secrets {
+    pub fn new(file: HashMap<String, String>) -> Secrets {
+        let revealed = file.values().filter(|v| !v.is_empty()).cloned().collect();
+        Secrets {
+            inner: Arc::new(SecretsInner {
+                file,
+                revealed: RwLock::new(revealed),
+            }),
+        }
+    }
+
+    fn reveal(&self, value: &str) {
+        if value.is_empty() {
+            return;
+        }
+        let contains = self.inner.revealed.read().unwrap().contains(value);
+        if !contains {
+            self.inner.revealed.write().unwrap().insert(value.to_string());
+        }
+    }
+
+    /// Replaces every loaded secret value in `input`
+    pub fn redact(&self, input: &str) -> String {
+        let revealed = self.inner.revealed.read().unwrap();
+        // Longest first, so a secret containing another is fully replaced
+        let mut values = revealed.iter().collect::<Vec<_>>();
+        values.sort_by(|a, b| b.len().cmp(&a.len()));
+        values
+            .into_iter()
+            .fold(input.to_string(), |out, v| out.replace(v.as_str(), REDACTED))
+    }
+
+    pub fn redact_value(&self, value: &Value) -> Value {
+        match value {
+            Value::String(s) => Value::String(self.redact(s)),
+            Value::Array(a) => Value::Array(a.iter().map(|v| self.redact_value(v)).collect()),
+            Value::Object(o) => Value::Object(
+                o.iter()
+                    .map(|(k, v)| (k.clone(), self.redact_value(v)))
+                    .collect(),
+            ),
+            v => v.clone(),
+        }
+    }
+}
+
+impl SecretSource for Secrets {
+    fn secret(&self, name: &str) -> Option<String> {
+        let value = env::var(format!("{}{}", ENV_PREFIX, name))
+            .ok()
+            .or_else(|| self.inner.file.get(name).cloned())?;
+        self.reveal(&value);
+        Some(value)
+    }
+}
+
+/// A drain redacting secrets from messages and values before passing
+/// records on to `inner`
+pub struct RedactDrain<D> {
+    inner: D,
+    secrets: Secrets,
+}
+
+impl<D> RedactDrain<D> {
+    pub fn new(inner: D, secrets: Secrets) -> RedactDrain<D> {
+        RedactDrain { inner, secrets }
+    }
+}
+
+impl<D: Drain> Drain for RedactDrain<D> {
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let msg = self.secrets.redact(&record.msg().to_string());
+        let kv = (
+            Redacted(values, &self.secrets),
+            Redacted(&record.kv(), &self.secrets),
+        );
+        let rs = RecordStatic {
+            location: record.location(),
+            level: record.level(),
+            tag: record.tag(),
+        };
+        self.inner.log(
+            &Record::new(&rs, &format_args!("{}", msg), slog::BorrowedKV(&kv)),
+            &OwnedKVList::from(o!()),
+        )
+    }
+}
+
+struct Redacted<'a, T: ?Sized>(&'a T, &'a Secrets);
+
+impl<'a, T: KV + ?Sized> KV for Redacted<'a, T> {
+    fn serialize(&self, record: &Record, serializer: &mut Serializer) -> slog::Result {
+        self.0.serialize(
+            record,
+            &mut RedactSerializer {
+                inner: serializer,
+                secrets: self.1,
+            },
+        )
+    }
+}
+
+struct RedactSerializer<'a> {
+    inner: &'a mut Serializer,
+    secrets: &'a Secrets,
+}
+
+macro_rules! pass_through {
+    ($($name:ident: $ty:ty),*) => {
+        $(
+            fn $name(&mut self, key: Key, val: $ty) -> slog::Result {
+                self.inner.$name(key, val)
+            }
+        )*
+    };
+}
+
+impl<'a> Serializer for RedactSerializer<'a> {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.inner.emit_str(key, &self.secrets.redact(&val.to_string()))
+    }
+
+    fn emit_str(&mut self, key: Key, val: &str) -> slog::Result {
+        self.inner.emit_str(key, &self.secrets.redact(val))
+    }
+
+    fn emit_unit(&mut self, key: Key) -> slog::Result {
+        self.inner.emit_unit(key)
+    }
+
+    fn emit_none(&mut self, key: Key) -> slog::Result {
+        self.inner.emit_none(key)
+    }
+
+    pass_through!(
+        emit_usize: usize, emit_isize: isize, emit_bool: bool, emit_char: char,
+        emit_u8: u8, emit_i8: i8, emit_u16: u16, emit_i16: i16,
+        emit_u32: u32, emit_i32: i32, emit_u64: u64, emit_i64: i64,
+        emit_f32: f32, emit_f64: f64
+    );
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use slog::Logger;
+    use std::sync::Mutex;
+
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Drain for Capture {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+            struct Collect(String);
+            impl Serializer for Collect {
+                fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+                    self.0.push_str(&format!(" {}={}", key, val));
+                    Ok(())
+                }
+            }
+            let mut out = Collect(record.msg().to_string());
+            values.serialize(record, &mut out).unwrap();
+            record.kv().serialize(record, &mut out).unwrap();
+            self.0.lock().unwrap().push(out.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn redacts_logs() {
+        let mut file = HashMap::new();
+        file.insert("API_TOKEN".to_string(), "s3cr3t".to_string());
+        let secrets = Secrets::new(file);
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = Logger::root(
+            RedactDrain::new(Capture(lines.clone()), secrets.clone()),
+            o!("token" => "s3cr3t"),
+        );
+
+        info!(log, "using s3cr3t"; "header" => "Bearer s3cr3t", "count" => 1);
+
+        assert_eq!(
+            lines.lock().unwrap()[0],
+            "using [REDACTED] token=[REDACTED] header=Bearer [REDACTED] count=1"
+        );
+    }
+
+    #[test]
+    fn lookup() {
+        let secrets = Secrets::new(HashMap::new());
+        assert_eq!(secrets.secret("CRAWLER_TEST_MISSING_SECRET"), None);
+
+        env::set_var("CRAWLER_SECRET_TEST_TOKEN", "from-env");
+        assert_eq!(secrets.secret("TEST_TOKEN"), Some("from-env".to_string()));
+        // Only prefixed variables are secrets
+        env::set_var("CRAWLER_TEST_PLAIN", "plain");
+        assert_eq!(secrets.secret("CRAWLER_TEST_PLAIN"), None);
+        assert_eq!(
+            secrets.redact_value(&serde_json::json!({"url": "https://x?key=from-env"})),
+            serde_json::json!({"url": "https://x?key=[REDACTED]"})
+        );
+    }
+}
@@ -2,11 +2,20 @@ use super::context::*;
 use super::descriptions::*;
 use super::environment::Environment;
 use super::error::{CrawlResult, CrawlErrorKind, CrawlError};
+use super::cancel::Cancellation;
+use super::checkpoint::Checkpoint;
+use super::scheduler::Scheduler;
+use super::events::{Event, EventStream, Events};
+use super::report::{Reporter, RunReport, STREAM_SAMPLE_SIZE};
 use super::work::*;
+use conveyor::futures::channel::mpsc::channel;
+use conveyor::futures::future;
+use conveyor::futures::prelude::*;
 use conveyor_work::package::Package;
 use pathutils;
 use slog::Logger;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
 use serde_json;
 use std::fs;
@@ -16,6 +25,7 @@ pub struct Target {
     e: Arc<Environment>,
     p: PathBuf,
     d: Arc<TargetDescription>,
+    f: Arc<Vec<ImportedFlow>>,
 }
 
 impl Target {
@@ -30,37 +40,22 @@ impl Target {
             path.as_ref().to_path_buf()
         };
 
+        let imported = load_imports(&path, &description.imports)?;
+
         Ok(Target {
             e: env,
             p: path,
             d: Arc::new(description),
+            f: Arc::new(imported),
         })
     }
 
     pub fn from_file<P: AsRef<Path>>(path: P, env: Arc<Environment>) -> CrawlResult<Target> {
-
-        if !path.as_ref().exists() || path.as_ref().is_dir() {
-            return Err(CrawlErrorKind::InvalidDescriptionFile(path.as_ref().to_path_buf()).into());
-        }
-
-        let ext = match path.as_ref().extension() {
-            Some(e) => e.to_str().unwrap(),
-            None => return Err(CrawlErrorKind::InvalidDescriptionFile(path.as_ref().to_path_buf()).into())
-        };
+        let desc = read_description(&path)?;
 
         let parent = path.as_ref().parent().unwrap_or(Path::new("/"));
 
-        let mut file = fs::File::open(&path)?;
-
-        let desc = match ext {
-            "json" => serde_json::from_reader(file).map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e)))),
-            "yaml" | "yml" => serde_yaml::from_reader(file).map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e)))),
-            _ => Err(CrawlError::new(CrawlErrorKind::InvalidDescriptionFile(path.as_ref().to_path_buf()))),
-        }?;
-
-        
         Target::new(parent, env, desc)
-        
     }
 
     pub fn env(&self) -> &Environment {
@@ -75,30 +70,312 @@ impl Target {
         &self.d
     }
 
+    /// Flows loaded through the `imports` of the description
+    pub fn imported_flows(&self) -> &[ImportedFlow] {
+        &self.f
+    }
+
+    /// Finds a local or imported flow, see `find_flow`
+    pub fn flow(&self, namespace: Option<&str>, name: &str) -> Option<(String, &FlowDescription)> {
+        find_flow(&self.d.flows, &self.f, namespace, name)
+    }
+
+    /// Validates the description against the environment and `args`
+    pub fn validate(&self, args: &Args) -> CrawlResult<()> {
+        let mut all = self.e.vars().clone();
+        for (k, v) in args {
+            all.insert(k.clone(), v.clone());
+        }
+        self.d.validate(&self.p, &all)?;
+        Ok(())
+    }
+
     pub fn build(self, args: Args) -> CrawlResult<TargetRunner> {
-        
+        self.build_with(args, None)
+    }
+
+    /// Builds a runner which records its progress in `checkpoint`, and skips
+    /// the flow invocations the checkpoint has seen complete
+    pub fn build_resumable(self, args: Args, checkpoint: Checkpoint) -> CrawlResult<TargetRunner> {
+        self.build_with(args, Some(Arc::new(checkpoint)))
+    }
+
+    fn build_with(self, args: Args, checkpoint: Option<Arc<Checkpoint>>) -> CrawlResult<TargetRunner> {
         let desc = self.d.clone();
-        
+        let env = self.e.clone();
+        let root = RootContext::with_checkpoint(self, args, checkpoint.clone());
+        let reporter = root.reporter().clone();
+        let events = root.events().clone();
+        let cancellation = root.cancellation().clone();
+        let scheduler = root.scheduler().clone();
+        let flows = root.flows().clone();
+
         let mut ctx = Context::new(
-            ParentOrRoot::Root(RootContext::new(self, args)),
+            ParentOrRoot::Root(root),
             None,
             None,
         );
 
-        Ok(TargetRunner{
-            work: desc.work.build(&mut ctx)?
+        // Created before building, so the flow cache is cleared when the build fails too
+        let state = RunState {
+            reporter: reporter,
+            events: events,
+            cancellation: cancellation,
+            checkpoint: checkpoint,
+            scheduler: scheduler,
+            flows: flows,
+            env: env,
+        };
+
+        Ok(TargetRunner {
+            work: desc.work.build(&mut ctx)?,
+            state: state,
         })
     }
 }
 
+/// Results of a run as they are produced, see `TargetRunner::run_stream`
+pub type ResultStream = Pin<Box<Stream<Item = CrawlResult<Package>> + Send>>;
+
+struct RunState {
+    reporter: Reporter,
+    events: Events,
+    cancellation: Cancellation,
+    checkpoint: Option<Arc<Checkpoint>>,
+    scheduler: Scheduler,
+    flows: FlowCache,
+    env: Arc<Environment>,
+}
+
+impl Drop for RunState {
+    fn drop(&mut self) {
+        self.flows.clear();
+    }
+}
+
+impl RunState {
+    fn start(&self) -> String {
+        let target = self.reporter.report().target;
+        self.reporter.start();
+        if let Some(checkpoint) = &self.checkpoint {
+            info!(self.env.log(), "using checkpoint";
+                "target" => &target,
+                "dir" => checkpoint.dir().to_string_lossy().to_string(),
+                "completed" => checkpoint.completed(),
+                "pending" => checkpoint.pending().len());
+        }
+        self.events.emit(Event::TargetStarted {
+            target: target.clone(),
+        });
+        target
+    }
+
+    fn record(&self, result: &CrawlResult<Package>) {
+        self.reporter.result(result, self.env.secrets());
+    }
+
+    fn finish(&self, target: String) -> CrawlResult<RunReport> {
+        let mut report = self.reporter.complete();
+        report.cancelled = self.cancellation.is_cancelled();
+        if let Some(checkpoint) = &self.checkpoint {
+            if !report.cancelled {
+                checkpoint.finish()?;
+            }
+        }
+
+        self.events.emit(Event::TargetFinished {
+            target,
+            outputs: report.output_count as usize,
+            errors: report.error_count(),
+        });
+        Ok(report)
+    }
+}
+
 pub struct TargetRunner {
     work: Work<Package>,
+    state: RunState,
 }
 
 impl TargetRunner {
+    /// Collects the report of the run, see `run_report`
+    pub fn reporter(&self) -> &Reporter {
+        &self.state.reporter
+    }
+
+    /// Handle to stop the run. A cancelled run returns the results produced
+    /// until then along with its report.
+    pub fn cancellation(&self) -> &Cancellation {
+        &self.state.cancellation
+    }
+
+    /// Returns a stream of the events of the run
+    pub fn subscribe(&self) -> EventStream {
+        self.state.events.subscribe()
+    }
+
+    /// Calls `observer` with every event of the run
+    pub fn observe<F: Fn(&Event) + Send + Sync + 'static>(&self, observer: F) {
+        self.state.events.observe(observer)
+    }
+
     pub async fn run(self) -> CrawlResult<Vec<CrawlResult<Package>>> {
-        let worker = Worker::new();
-        let ret = await!(worker.run(vec![self.work]));
+        let (ret, _) = await!(self.run_report())?;
         Ok(ret)
     }
+
+    /// Runs the target and returns its results along with a report of the run
+    pub async fn run_report(self) -> CrawlResult<(Vec<CrawlResult<Package>>, RunReport)> {
+        let TargetRunner { work, state } = self;
+        let target = state.start();
+        let ret = await!(state.scheduler.run(vec![work]));
+        for r in &ret {
+            state.record(r);
+        }
+        let report = state.finish(target)?;
+        Ok((ret, report))
+    }
+
+    /// Runs the target, yielding its results as they are produced. At most
+    /// `buffer` results wait to be consumed before the run pauses. The
+    /// report is complete once the stream has ended, see `reporter`. It
+    /// counts every result, but only keeps the first `STREAM_SAMPLE_SIZE`
+    /// output names and errors of each kind.
+    pub fn run_stream(self, buffer: usize) -> ResultStream {
+        let TargetRunner { work, state } = self;
+        state.reporter.sample(STREAM_SAMPLE_SIZE);
+        let state = Arc::new(state);
+        let (sender, receiver) = channel(buffer);
+
+        let target = state.start();
+        let run = state
+            .scheduler
+            .run_into(vec![work], sender)
+            .map(|_| None)
+            .into_stream();
+
+        let s = state.clone();
+        let finish = future::lazy(move |_| {
+            if let Err(e) = s.finish(target) {
+                warn!(s.env.log(), "could not finish run"; "error" => e.to_string());
+            }
+            None
+        })
+        .into_stream();
+
+        Box::pin(
+            receiver
+                .map(Some)
+                .select(run)
+                .chain(finish)
+                .filter_map(future::ready)
+                .inspect(move |r| state.record(r)),
+        )
+    }
+}
+
+/// Runs steps through a whole target, for the tests of the worktypes
+#[cfg(test)]
+pub(crate) mod testing {
+
+    use super::super::worktypes;
+    use super::*;
+    use conveyor::futures::executor::block_on;
+    use conveyor::{into_box, station_fn};
+    use slog::Discard;
+
+    /// A step emitting the outputs `f` returns for the name of its input
+    pub(crate) fn step(f: fn(&str) -> Vec<WorkOutput<Package>>) -> WorkDescription {
+        let service: WorkBox<Package> =
+            into_box(station_fn(async move |package: Package| Ok(f(package.name()))));
+        WorkDescription::new(worktypes::PassThrough {
+            service: Some(Arc::new(service)),
+        })
+        .build()
+        .unwrap()
+    }
+
+    /// Runs `steps` as the work of a target, returning its outputs sorted by
+    /// name along with the run report
+    pub(crate) fn run_steps(steps: Vec<WorkDescription>) -> (Vec<Package>, RunReport) {
+        let env = Environment::build("/", Logger::root(Discard, o!())).build();
+        let desc = TargetDescription {
+            name: "Test".to_string(),
+            imports: Vec::new(),
+            work: WorkTargetDescription {
+                input: serde_json::Value::String("https://loppen.dk".to_string()),
+                steps,
+            },
+            flows: Vec::new(),
+        };
+        let runner = Target::new("/", env, desc).unwrap().build(Args::new()).unwrap();
+        let reporter = runner.reporter().clone();
+
+        let mut packages = block_on(runner.run())
+            .unwrap()
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        packages.sort_by(|a, b| a.name().cmp(b.name()));
+        (packages, reporter.report())
+    }
+
+    pub(crate) fn names(packages: &[Package]) -> Vec<&str> {
+        packages.iter().map(|p| p.name()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::error::CrawlErrorKind;
+    use super::super::worktypes;
+    use super::*;
+    use conveyor::futures::executor::block_on;
+    use conveyor::{into_box, station_fn};
+    use slog::Discard;
+
+    #[test]
+    fn run_stream() {
+        let count = STREAM_SAMPLE_SIZE * 3;
+        let service: WorkBox<Package> = into_box(station_fn(async move |_: Package| {
+            Ok((0..count)
+                .flat_map(|i| {
+                    vec![
+                        WorkOutput::Result(Ok(Package::new(&format!("{}.json", i), "{}"))),
+                        WorkOutput::Result(Err(CrawlErrorKind::NotFound(i.to_string()).into())),
+                    ]
+                })
+                .collect::<Vec<_>>())
+        }));
+        let step = WorkDescription::new(worktypes::PassThrough {
+            service: Some(Arc::new(service)),
+        })
+        .build()
+        .unwrap();
+
+        let env = Environment::build("/", Logger::root(Discard, o!())).build();
+        let desc = TargetDescription {
+            name: "Test".to_string(),
+            imports: Vec::new(),
+            work: WorkTargetDescription {
+                input: serde_json::Value::String("https://loppen.dk".to_string()),
+                steps: vec![step],
+            },
+            flows: Vec::new(),
+        };
+        let runner = Target::new("/", env, desc).unwrap().build(Args::new()).unwrap();
+        let reporter = runner.reporter().clone();
+
+        let results = block_on(runner.run_stream(4).collect::<Vec<_>>());
+        assert_eq!(results.len(), count * 2);
+
+        // Every result is counted, but the report keeps a bounded sample
+        let report = reporter.report();
+        assert_eq!(report.output_count, count as u64);
+        assert_eq!(report.outputs.len(), STREAM_SAMPLE_SIZE);
+        assert_eq!(report.error_count(), count);
+        assert_eq!(report.errors["NotFound"].len(), STREAM_SAMPLE_SIZE);
+        assert!(report.finished.is_some());
+    }
 }
@@ -0,0 +1,459 @@
+//! A small template language used for `${}` interpolation in descriptions.
+//!
+//! ```text
+//! ${name}                 value of `name`
+//! ${item.url}             dotted path into a JSON argument
+//! ${items.0.title}        array indices work as path segments
+//! ${output:-./out}        default when `output` is missing or null
+//! ${title | slug}         filters: slug, urlencode, lower, upper, trim, json
+//! ${secret:API_TOKEN}     secret, see `secrets`
+//! $${literal}             renders as `${literal}`
+//! ```
+use super::context::Args;
+use super::secrets::{NoSecrets, SecretSource};
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    /// The template itself could not be parsed
+    Syntax { template: String, message: String },
+    /// A variable without a default was not found in the arguments
+    Missing { template: String, variable: String },
+    UnknownFilter(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::Syntax { template, message } => {
+                write!(f, "invalid template \"{}\": {}", template, message)
+            }
+            TemplateError::Missing { template, variable } => write!(
+                f,
+                "missing variable \"{}\" in template \"{}\"",
+                variable, template
+            ),
+            TemplateError::UnknownFilter(name) => write!(f, "unknown filter \"{}\"", name),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Slug,
+    UrlEncode,
+    Lower,
+    Upper,
+    Trim,
+    Json,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Result<Filter, TemplateError> {
+        let filter = match name {
+            "slug" => Filter::Slug,
+            "urlencode" => Filter::UrlEncode,
+            "lower" => Filter::Lower,
+            "upper" => Filter::Upper,
+            "trim" => Filter::Trim,
+            "json" => Filter::Json,
+            _ => return Err(TemplateError::UnknownFilter(name.to_string())),
+        };
+        Ok(filter)
+    }
+
+    fn apply(self, value: Value) -> Value {
+        match self {
+            Filter::Json => Value::String(value.to_string()),
+            Filter::Slug => Value::String(slug(&to_text(&value))),
+            Filter::UrlEncode => Value::String(urlencode(&to_text(&value))),
+            Filter::Lower => Value::String(to_text(&value).to_lowercase()),
+            Filter::Upper => Value::String(to_text(&value).to_uppercase()),
+            Filter::Trim => Value::String(to_text(&value).trim().to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub path: Vec<String>,
+    pub default: Option<String>,
+    pub filters: Vec<Filter>,
+    /// Reads the secret `path[0]` instead of an argument
+    pub secret: bool,
+}
+
+impl Expression {
+    /// The name of the argument, or secret, the expression reads from
+    pub fn variable(&self) -> &str {
+        &self.path[0]
+    }
+
+    fn name(&self) -> String {
+        if self.secret {
+            format!("secret:{}", self.path[0])
+        } else {
+            self.path.join(".")
+        }
+    }
+
+    pub fn lookup<'a>(&self, args: &'a Args) -> Option<&'a Value> {
+        let mut current = args.get(&self.path[0])?;
+        for segment in self.path.iter().skip(1) {
+            current = match current {
+                Value::Object(o) => o.get(segment)?,
+                Value::Array(a) => a.get(segment.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        match current {
+            Value::Null => None,
+            v => Some(v),
+        }
+    }
+
+    fn parse(template: &str, input: &str) -> Result<Expression, TemplateError> {
+        let syntax = |message: &str| TemplateError::Syntax {
+            template: template.to_string(),
+            message: message.to_string(),
+        };
+
+        let mut parts = input.split('|');
+        let head = parts.next().unwrap_or("");
+        let filters = parts
+            .map(|f| Filter::from_name(f.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (path, default) = match head.find(":-") {
+            Some(idx) => (&head[..idx], Some(head[idx + 2..].trim().to_string())),
+            None => (head, None),
+        };
+
+        let path = path.trim();
+        let (secret, path) = if path.starts_with("secret:") {
+            (true, &path[7..])
+        } else {
+            (false, path)
+        };
+
+        let path = path
+            .split('.')
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+
+        if path.iter().any(|s| {
+            s.is_empty()
+                || !s
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        }) {
+            return Err(syntax(&format!("invalid variable \"{}\"", head.trim())));
+        }
+        if secret && path.len() > 1 {
+            return Err(syntax(&format!("invalid secret \"{}\"", head.trim())));
+        }
+
+        Ok(Expression {
+            path,
+            default,
+            filters,
+            secret,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Text(String),
+    Expression(Expression),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(input: &str) -> Result<Template, TemplateError> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut rest = input;
+
+        while let Some(idx) = rest.find('$') {
+            text.push_str(&rest[..idx]);
+            rest = &rest[idx..];
+            if rest.starts_with("$${") {
+                text.push_str("${");
+                rest = &rest[3..];
+            } else if rest.starts_with("${") {
+                let end = match rest.find('}') {
+                    Some(end) => end,
+                    None => {
+                        return Err(TemplateError::Syntax {
+                            template: input.to_string(),
+                            message: "unterminated \"${\"".to_string(),
+                        })
+                    }
+                };
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::replace(&mut text, String::new())));
+                }
+                segments.push(Segment::Expression(Expression::parse(
+                    input,
+                    &rest[2..end],
+                )?));
+                rest = &rest[end + 1..];
+            } else {
+                text.push('$');
+                rest = &rest[1..];
+            }
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        Ok(Template {
+            source: input.to_string(),
+            segments,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn expressions(&self) -> impl Iterator<Item = &Expression> {
+        self.segments.iter().filter_map(|s| match s {
+            Segment::Expression(e) => Some(e),
+            _ => None,
+        })
+    }
+
+    /// Returns true when the template contains at least one expression
+    pub fn is_dynamic(&self) -> bool {
+        self.expressions().next().is_some()
+    }
+
+    /// Variables which must be present in the arguments for the template to render
+    pub fn required_variables(&self) -> impl Iterator<Item = &str> {
+        self.expressions()
+            .filter(|e| e.default.is_none() && !e.secret)
+            .map(|e| e.variable())
+    }
+
+    /// Names of the secrets the template reads
+    pub fn secrets(&self) -> impl Iterator<Item = &str> {
+        self.expressions()
+            .filter(|e| e.secret)
+            .map(|e| e.variable())
+    }
+
+    pub fn render(&self, args: &Args) -> Result<String, TemplateError> {
+        self.render_secrets(args, &NoSecrets)
+    }
+
+    /// Renders the template, reading `${secret:NAME}` expressions from `secrets`
+    pub fn render_secrets(
+        &self,
+        args: &Args,
+        secrets: &dyn SecretSource,
+    ) -> Result<String, TemplateError> {
+        self.render_with(args, secrets, |e| {
+            Err(TemplateError::Missing {
+                template: self.source.clone(),
+                variable: e.name(),
+            })
+        })
+    }
+
+    /// Renders the template, leaving expressions which cannot be resolved untouched
+    pub fn render_lenient(&self, args: &Args) -> String {
+        self.render_with(args, &NoSecrets, |e| Ok(format!("${{{}}}", e.name())))
+            .unwrap_or_else(|_| self.source.clone())
+    }
+
+    fn render_with<F>(
+        &self,
+        args: &Args,
+        secrets: &dyn SecretSource,
+        missing: F,
+    ) -> Result<String, TemplateError>
+    where
+        F: Fn(&Expression) -> Result<String, TemplateError>,
+    {
+        let mut out = String::with_capacity(self.source.len());
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(t) => out.push_str(t),
+                Segment::Expression(e) => {
+                    let found = if e.secret {
+                        secrets.secret(e.variable()).map(Value::String)
+                    } else {
+                        e.lookup(args).cloned()
+                    };
+                    let value = match (found, &e.default) {
+                        (Some(v), _) => v,
+                        (None, Some(d)) => Value::String(d.clone()),
+                        (None, None) => {
+                            out.push_str(&missing(e)?);
+                            continue;
+                        }
+                    };
+                    let value = e.filters.iter().fold(value, |v, f| f.apply(v));
+                    out.push_str(&to_text(&value));
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        v => v.to_string(),
+    }
+}
+
+fn slug(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.to_lowercase().chars() {
+        match c {
+            'a'..='z' | '0'..='9' => out.push(c),
+            'æ' => out.push_str("ae"),
+            'ø' | 'ö' => out.push_str("oe"),
+            'å' => out.push_str("aa"),
+            'ä' => out.push_str("ae"),
+            'ü' => out.push_str("ue"),
+            'é' | 'è' | 'ê' => out.push('e'),
+            _ => {
+                if !out.is_empty() && !out.ends_with('-') {
+                    out.push('-');
+                }
+            }
+        }
+    }
+    out.trim_end_matches('-').to_string()
+}
+
+fn urlencode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn render(template: &str, args: &Args) -> Result<String, TemplateError> {
+        Template::parse(template)?.render(args)
+    }
+
+    #[test]
+    fn paths_and_values() {
+        let args = args! {
+            "item" => serde_json::json!({"url": "https://loppen.dk", "tags": ["rock", "jazz"]}),
+            "count" => 4,
+            "live" => true
+        };
+        assert_eq!(render("${item.url}/", &args).unwrap(), "https://loppen.dk/");
+        assert_eq!(render("${item.tags.1}", &args).unwrap(), "jazz");
+        assert_eq!(render("${count} ${live}", &args).unwrap(), "4 true");
+        assert_eq!(render("${item.tags}", &args).unwrap(), r#"["rock","jazz"]"#);
+    }
+
+    #[test]
+    fn defaults_and_missing() {
+        let args = args! { "name" => "Loppen" };
+        assert_eq!(render("${output:-./out}", &args).unwrap(), "./out");
+        assert_eq!(render("${name:-other}", &args).unwrap(), "Loppen");
+        assert_eq!(
+            render("${output}/x", &args),
+            Err(TemplateError::Missing {
+                template: "${output}/x".to_string(),
+                variable: "output".to_string()
+            })
+        );
+        assert_eq!(
+            Template::parse("${output}/${name}").unwrap().render_lenient(&args),
+            "${output}/Loppen"
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let args = args! { "title" => " Spillestedet Ærø & Venner! " };
+        assert_eq!(
+            render("${title | slug}", &args).unwrap(),
+            "spillestedet-aeroe-venner"
+        );
+        assert_eq!(
+            render("${title|trim|lower}", &args).unwrap(),
+            "spillestedet ærø & venner!"
+        );
+        assert_eq!(render("${q:-a b&c | urlencode}", &args).unwrap(), "a%20b%26c");
+        assert_eq!(render("${q:-x | json}", &args).unwrap(), "\"x\"");
+        assert!(Template::parse("${title | shout}").is_err());
+    }
+
+    #[test]
+    fn escaping_and_syntax() {
+        let args = args! { "a" => "b" };
+        assert_eq!(render("$${a} ${a} $5", &args).unwrap(), "${a} b $5");
+        assert!(Template::parse("${a").is_err());
+        assert!(Template::parse("${}").is_err());
+        assert!(!Template::parse("$${a}").unwrap().is_dynamic());
+    }
+
+    struct Vault;
+
+    impl SecretSource for Vault {
+        fn secret(&self, name: &str) -> Option<String> {
+            match name {
+                "API_TOKEN" => Some("s3cr3t".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn secrets() {
+        let args = args! { "page" => 2 };
+        let template = Template::parse("Bearer ${secret:API_TOKEN} ${page}").unwrap();
+        assert_eq!(template.required_variables().collect::<Vec<_>>(), vec!["page"]);
+        assert_eq!(template.secrets().collect::<Vec<_>>(), vec!["API_TOKEN"]);
+        assert_eq!(
+            template.render_secrets(&args, &Vault).unwrap(),
+            "Bearer s3cr3t 2"
+        );
+        assert_eq!(
+            template.render(&args),
+            Err(TemplateError::Missing {
+                template: "Bearer ${secret:API_TOKEN} ${page}".to_string(),
+                variable: "secret:API_TOKEN".to_string()
+            })
+        );
+        assert_eq!(template.render_lenient(&args), "Bearer ${secret:API_TOKEN} 2");
+        assert!(Template::parse("${secret:a.b}").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use super::context::Context;
+use super::descriptions::Validator;
 use super::error::CrawlResult;
 use super::work::*;
 use conveyor_work::package::Package;
@@ -7,6 +8,9 @@ use std::fmt;
 #[typetag::serde(tag = "type")]
 pub trait WorkType: fmt::Debug + Send + Sync {
     fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>>;
+    /// Reports configuration problems without building the station.
+    /// `path` is the location of the step in the description.
+    fn validate(&self, _validator: &mut Validator, _path: &str) {}
     fn box_clone(&self) -> Box<WorkType>;
 }
 
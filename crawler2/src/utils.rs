@@ -1,4 +1,7 @@
 use super::context::Args;
+use super::error::CrawlResult;
+use super::secrets::SecretSource;
+use super::template::Template;
 use super::work;
 use conveyor::futures::prelude::*;
 use conveyor::{Result, Station};
@@ -80,27 +83,33 @@ where
     }
 }
 
-use regex;
-
-lazy_static! {
-    static ref TEST: regex::Regex = regex::Regex::new(r"\$\{(\w+)\}").unwrap();
-}
-
 pub fn is_interpolated(input: &str) -> bool {
-    TEST.is_match(input)
+    match Template::parse(input) {
+        Ok(t) => t.is_dynamic(),
+        Err(_) => false,
+    }
 }
 
+/// Interpolates `input`, leaving variables which cannot be resolved as they are.
+/// Use [`try_interpolate`] when missing variables are an error.
 pub fn interpolate(input: &str, args: &Args) -> String {
-    let mut output = input.to_string();
-    for cap in TEST.captures_iter(input) {
-        if args.contains_key(&cap[1]) {
-            output = output.replace(
-                &format!("${{{}}}", &cap[1]),
-                args.get(&cap[1]).unwrap().as_str().unwrap(),
-            );
-        }
+    match Template::parse(input) {
+        Ok(t) => t.render_lenient(args),
+        Err(_) => input.to_string(),
     }
-    output
+}
+
+pub fn try_interpolate(input: &str, args: &Args) -> CrawlResult<String> {
+    Ok(Template::parse(input)?.render(args)?)
+}
+
+/// Like [`try_interpolate`], also resolving `${secret:NAME}` from `secrets`
+pub fn try_interpolate_secrets(
+    input: &str,
+    args: &Args,
+    secrets: &dyn SecretSource,
+) -> CrawlResult<String> {
+    Ok(Template::parse(input)?.render_secrets(args, secrets)?)
 }
 
 #[cfg(test)]
@@ -135,4 +144,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn interpolate_values() {
+        let args = args! {
+            "page" => 2,
+            "item" => serde_json::json!({"url": "https://loppen.dk"})
+        };
+
+        assert_eq!(interpolate("${item.url}?page=${page}", &args), "https://loppen.dk?page=2");
+        assert_eq!(interpolate("${missing}/${page}", &args), "${missing}/2");
+        assert!(try_interpolate("${missing}/${page}", &args).is_err());
+    }
+
 }
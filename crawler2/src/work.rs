@@ -1,12 +1,24 @@
 use super::error::{CrawlErrorKind, CrawlResult};
-use super::utils::{station_fn_ctx2, WorkArcWrapper, WorkBoxWrapper};
+use super::utils::WorkArcWrapper;
 use conveyor::futures::prelude::*;
-use conveyor::Chain;
-use conveyor::ConcurrentStream;
-use conveyor::{into_box, Result, Station};
+use conveyor::{into_box, station_fn, Result, Station};
 use std::pin::Pin;
-use std::sync::Arc;
-
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// An output of a step.
+///
+/// A `Result` goes to the next step. At the end of a flow it becomes an
+/// output of the step which called the flow, and at the end of the target
+/// a result of the run. Results pass by the `then` of every step.
+///
+/// A `Then` travels the same way, but is received by whichever comes first
+/// of a following step and a `then`. A step without a `then` passes it to
+/// the next step like a result, while a `Then` from the last step of a flow
+/// reaches the `then` of the step which called the flow. With neither left
+/// it becomes a result.
+///
+/// `Work` is queued, and its outputs continue where the step's would have.
 pub enum WorkOutput<V: 'static + Send> {
     Result(CrawlResult<V>),
     Work(Work<V>),
@@ -22,6 +34,29 @@ impl<V: 'static + Send> WorkOutput<V> {
     }
 }
 
+impl<V: 'static + Send + Sync> WorkOutput<V> {
+    /// Holds `completion` until this output, and the work it is handed on
+    /// to, has run. Packages are queued as work which passes them on.
+    pub fn complete_with(self, completion: &Arc<Completion>) -> WorkOutput<V> {
+        match self {
+            WorkOutput::Work(w) => WorkOutput::Work(w.complete_with(completion)),
+            WorkOutput::Result(Ok(v)) => WorkOutput::Work(
+                Work::new(v, station_fn(async move |v| Ok(vec![WorkOutput::Result(Ok(v))])))
+                    .complete_with(completion),
+            ),
+            WorkOutput::Then(v) => WorkOutput::Work(
+                Work::new(v, station_fn(async move |v| Ok(vec![WorkOutput::Then(v)]))).complete_with(completion),
+            ),
+            WorkOutput::Result(Err(e)) => {
+                if let CrawlErrorKind::Cancelled = e.kind() {
+                    completion.fail();
+                }
+                WorkOutput::Result(Err(e))
+            }
+        }
+    }
+}
+
 pub type WorkBox<V> = Box<
     Station<
             Input = V,
@@ -31,9 +66,83 @@ pub type WorkBox<V> = Box<
         + Sync,
 >;
 
+/// Where the outputs of a piece of work go once it has run
+pub(crate) enum Continuation<V: 'static + Send> {
+    /// The next step, which receives results and `Then` packages
+    Next(Arc<WorkBox<V>>),
+    /// The `then` of a step, which only receives `Then` packages
+    Then(Arc<WorkBox<V>>),
+}
+
+impl<V: 'static + Send> Continuation<V> {
+    fn station(&self) -> &Arc<WorkBox<V>> {
+        match self {
+            Continuation::Next(s) => s,
+            Continuation::Then(s) => s,
+        }
+    }
+
+    fn is_next(&self) -> bool {
+        match self {
+            Continuation::Next(_) => true,
+            Continuation::Then(_) => false,
+        }
+    }
+}
+
+impl<V: 'static + Send> Clone for Continuation<V> {
+    fn clone(&self) -> Self {
+        match self {
+            Continuation::Next(s) => Continuation::Next(s.clone()),
+            Continuation::Then(s) => Continuation::Then(s.clone()),
+        }
+    }
+}
+
+/// Calls a callback once a package and all the work derived from it has run,
+/// and its results have left the end of the target.
+///
+/// Every queued piece of work holding the completion counts as outstanding,
+/// including the later steps of the flows which its results pass through.
+/// The callback gets `false` when some of the work was cancelled or failed.
+pub struct Completion {
+    outstanding: AtomicUsize,
+    failed: AtomicBool,
+    callback: Mutex<Option<Box<Fn(bool) + Send>>>,
+}
+
+impl Completion {
+    /// Creates a completion held once by the caller, who must `release` it
+    pub fn new<F: Fn(bool) + Send + 'static>(callback: F) -> Arc<Completion> {
+        Arc::new(Completion {
+            outstanding: AtomicUsize::new(1),
+            failed: AtomicBool::new(false),
+            callback: Mutex::new(Some(Box::new(callback))),
+        })
+    }
+
+    pub fn hold(&self) {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn release(&self) {
+        if self.outstanding.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Some(callback) = self.callback.lock().unwrap().take() {
+                callback(!self.failed.load(Ordering::SeqCst));
+            }
+        }
+    }
+
+    pub fn fail(&self) {
+        self.failed.store(true, Ordering::SeqCst);
+    }
+}
+
 pub struct Work<V: 'static + Send> {
     data: V,
     work: WorkBox<V>,
+    next: Vec<Continuation<V>>,
+    completions: Vec<Arc<Completion>>,
 }
 
 impl<V: Send + Sync> Work<V> {
@@ -47,9 +156,12 @@ impl<V: Send + Sync> Work<V> {
         Work {
             data,
             work: into_box(work),
+            next: Vec::new(),
+            completions: Vec::new(),
         }
     }
 
+    /// Hands the results of this work to `next` once it has run
     pub fn chain<
         W: Station<Input = V, Output = Vec<WorkOutput<V>>, Future = F> + 'static + Send + Sync,
         F: Future<Output = Result<Vec<WorkOutput<V>>>> + Send,
@@ -57,286 +169,138 @@ impl<V: Send + Sync> Work<V> {
         self,
         next: W,
     ) -> Work<V> {
-        Work {
-            data: self.data,
-            work: into_box(WorkBoxWrapper::new(self.work).pipe(station_fn_ctx2(
-                async move |pack: Vec<WorkOutput<V>>, ctx: Arc<WorkBox<V>>| {
-                    let (mut ret, w, mut thens) = Worker::split(pack);
-
-                    let worker = Worker::new();
-                    let out = await!(worker.run(w));
-                    ret.extend(out);
-
-                    let (mut ret, w) = Worker::split2(ret, ctx.clone());
-                    let out = await!(worker.run(w));
-                    ret.extend(out);
-                    let mut out = ret
-                        .into_iter()
-                        .map(|m| WorkOutput::Result(m))
-                        .collect::<Vec<_>>();
-                    out.extend(
-                        thens
-                            .into_iter()
-                            .map(|m| WorkOutput::Then(m))
-                            .collect::<Vec<_>>(),
-                    );
-
-                    Ok(out)
-                },
-                Arc::new(into_box(next)),
-            ))),
-        }
+        self.next(Arc::new(into_box(next)))
     }
-}
-
-pub struct Worker;
 
-impl Worker {
-    pub fn new() -> Worker {
-        Worker
+    pub(crate) fn next(mut self, next: Arc<WorkBox<V>>) -> Work<V> {
+        self.next.push(Continuation::Next(next));
+        self
     }
 
-    fn _run<'a, V: 'static + Send>(
-        &'a self,
-        input: Vec<Work<V>>,
-    ) -> impl Future<Output = Vec<Result<Vec<WorkOutput<V>>>>> {
-        let stream = stream::iter(input);
-        ConcurrentStream::new(stream.map(|work| work.work.execute(work.data)), 4).collect()
+    pub(crate) fn then(mut self, then: Arc<WorkBox<V>>) -> Work<V> {
+        self.next.push(Continuation::Then(then));
+        self
     }
 
-    pub async fn run<V: 'static + Send>(&self, input: Vec<Work<V>>) -> Vec<CrawlResult<V>> {
-        let mut ret = input;
-        let mut output = Vec::new();
-        loop {
-            ret = await!(self._run(ret))
-                .into_iter()
-                .filter_map(|m| match m {
-                    Ok(s) => Some(
-                        s.into_iter()
-                            .filter_map(|m| match m {
-                                WorkOutput::Result(r) => {
-                                    output.push(r);
-                                    None
-                                }
-                                WorkOutput::Work(w) => Some(w),
-                                WorkOutput::Then(_) => unimplemented!("cannot chain"),
-                            })
-                            .collect::<Vec<_>>(),
-                    ),
-                    Err(e) => {
-                        output.push(Err(CrawlErrorKind::Conveyor(e.to_string()).into()));
-                        None
-                    }
-                })
-                .flatten()
-                .collect();
-
-            if ret.is_empty() {
-                break;
-            }
-        }
-        output
+    /// Holds `completion` until this work and the work it hands on has run
+    pub fn complete_with(mut self, completion: &Arc<Completion>) -> Work<V> {
+        completion.hold();
+        self.completions.push(completion.clone());
+        self
     }
 
-    // pub async fn run2<V: 'static + Send>(&self, input: Vec<WorkOutput<V>>) -> Vec<CrawlResult<V>> {
-    //     let (mut output, mut ret) = Worker::split(input);
-    //     //let mut output = Vec::new();
-    //     loop {
-    //         ret = await!(self._run(ret))
-    //             .into_iter()
-    //             .filter_map(|m| match m {
-    //                 Ok(s) => Some(
-    //                     s.into_iter()
-    //                         .filter_map(|m| match m {
-    //                             WorkOutput::Result(r) => {
-    //                                 output.push(r);
-    //                                 None
-    //                             }
-    //                             WorkOutput::Work(w) => Some(w),
-    //                         })
-    //                         .collect::<Vec<_>>(),
-    //                 ),
-    //                 Err(e) => {
-    //                     output.push(Err(CrawlErrorKind::Unknown.into()));
-    //                     None
-    //                 }
-    //             })
-    //             .flatten()
-    //             .collect();
-
-    //         if ret.is_empty() {
-    //             break;
-    //         }
-    //     }
-    //     output
-    // }
-
-    pub async fn run_and<V: 'static + Send + Sync>(
-        &self,
-        input: Vec<WorkOutput<V>>,
-        work: Arc<WorkBox<V>>,
-    ) -> Vec<CrawlResult<V>> {
-        let (mut loutput, mut ret, mut thens) = Worker::split(input);
-        loop {
-            ret = await!(self._run(ret))
-                .into_iter()
-                .filter_map(|m| match m {
-                    Ok(s) => Some(
-                        s.into_iter()
-                            .filter_map(|m| match m {
-                                WorkOutput::Result(r) => {
-                                    loutput.push(r);
-                                    None
-                                }
-                                WorkOutput::Work(w) => Some(w),
-                                WorkOutput::Then(r) => {
-                                    thens.push(r);
-                                    None
-                                }
-                            })
-                            .collect::<Vec<_>>(),
-                    ),
-                    Err(e) => {
-                        loutput.push(Err(CrawlErrorKind::Conveyor(e.to_string()).into()));
-                        None
-                    }
-                })
-                .flatten()
-                .collect();
-
-            if ret.is_empty() {
-                break;
-            }
-        }
-
-        let (mut output, ret) = Worker::split2(thens.into_iter().map(|m| Ok(m)).collect(), work);
-
-        let ret = await!(self.run(ret));
-        output.extend(ret);
-        loutput.extend(output);
-
-        loutput
+    pub(crate) fn execute(
+        self,
+    ) -> Pin<
+        Box<
+            Future<Output = (Result<Vec<WorkOutput<V>>>, Vec<Continuation<V>>, Vec<Arc<Completion>>)>
+                + Send,
+        >,
+    > {
+        let Work {
+            data,
+            work,
+            next,
+            completions,
+        } = self;
+        Box::pin(work.execute(data).map(move |ret| (ret, next, completions)))
     }
+}
 
-    pub async fn run_chain<V: 'static + Send + Sync>(
-        &self,
-        input: Vec<WorkOutput<V>>,
-        work: Arc<WorkBox<V>>,
-    ) -> Vec<CrawlResult<V>> {
-        let (mut output, mut ret, _) = Worker::split(input);
-        loop {
-            ret = await!(self._run(ret))
-                .into_iter()
-                .filter_map(|m| match m {
-                    Ok(s) => Some(
-                        s.into_iter()
-                            .filter_map(|m| match m {
-                                WorkOutput::Result(r) => {
-                                    output.push(r);
-                                    None
-                                }
-                                WorkOutput::Work(w) => Some(w),
-                                WorkOutput::Then(r) => {
-                                    output.push(Ok(r));
-                                    None
-                                }
-                            })
-                            .collect::<Vec<_>>(),
-                    ),
-                    Err(e) => {
-                        output.push(Err(CrawlErrorKind::Conveyor(e.to_string()).into()));
-                        None
-                    }
-                })
-                .flatten()
-                .collect();
-
-            if ret.is_empty() {
-                break;
+/// Sends the outputs of a step on to `next`
+pub fn and_then<V: Send + Sync>(
+    outputs: Vec<WorkOutput<V>>,
+    next: &Arc<WorkBox<V>>,
+) -> Vec<WorkOutput<V>> {
+    outputs
+        .into_iter()
+        .map(|o| match o {
+            WorkOutput::Result(Ok(v)) | WorkOutput::Then(v) => {
+                WorkOutput::Work(Work::new(v, WorkArcWrapper::new(next.clone())))
             }
-        }
-
-        let (mut output, ret) = Worker::split2(output, work);
+            WorkOutput::Work(w) => WorkOutput::Work(w.next(next.clone())),
+            o => o,
+        })
+        .collect()
+}
 
-        let ret = await!(self.run(ret));
-        output.extend(ret);
+/// Sends the `Then` packages among the outputs of a step, and those of the
+/// work it handed on, to `then`
+pub fn on_then<V: Send + Sync>(
+    outputs: Vec<WorkOutput<V>>,
+    then: &Arc<WorkBox<V>>,
+) -> Vec<WorkOutput<V>> {
+    outputs
+        .into_iter()
+        .map(|o| match o {
+            WorkOutput::Then(v) => WorkOutput::Work(Work::new(v, WorkArcWrapper::new(then.clone()))),
+            WorkOutput::Work(w) => WorkOutput::Work(w.then(then.clone())),
+            o => o,
+        })
+        .collect()
+}
 
-        output
-    }
+pub(crate) enum Routed<V: 'static + Send> {
+    Done(CrawlResult<V>),
+    Queued(Work<V>),
+}
 
-    pub fn split<V: 'static + Send>(
-        input: Vec<WorkOutput<V>>,
-    ) -> (Vec<CrawlResult<V>>, Vec<Work<V>>, Vec<V>) {
-        let mut output = Vec::new();
-        let mut thens = Vec::new();
-        let ret = input
-            .into_iter()
-            .filter_map(|m| match m {
-                WorkOutput::Work(w) => Some(w),
-                WorkOutput::Result(ret) => {
-                    output.push(ret);
-                    None
+/// Decides what happens to an output of work which had the continuations
+/// `next` and held `completions`
+pub(crate) fn route<V: Send + Sync>(
+    output: WorkOutput<V>,
+    next: &[Continuation<V>],
+    completions: &[Arc<Completion>],
+) -> Routed<V> {
+    match output {
+        WorkOutput::Result(Err(e)) => {
+            if let CrawlErrorKind::Cancelled = e.kind() {
+                for c in completions {
+                    c.fail();
                 }
-                WorkOutput::Then(ret) => {
-                    thens.push(ret);
-                    None
-                }
-            })
-            .collect();
-
-        (output, ret, thens)
+            }
+            Routed::Done(Err(e))
+        }
+        // Results skip the `then` of steps they did not come from
+        WorkOutput::Result(Ok(v)) => continue_with(v, next.iter().position(|c| c.is_next()), next, completions),
+        WorkOutput::Then(v) => continue_with(v, if next.is_empty() { None } else { Some(0) }, next, completions),
+        WorkOutput::Work(mut w) => {
+            w.next.extend(next.iter().cloned());
+            for c in completions {
+                c.hold();
+                w.completions.push(c.clone());
+            }
+            Routed::Queued(w)
+        }
     }
+}
 
-    pub fn split2<V: 'static + Send + Sync>(
-        input: Vec<CrawlResult<V>>,
-        work: Arc<WorkBox<V>>,
-    ) -> (Vec<CrawlResult<V>>, Vec<Work<V>>) {
-        let mut output = Vec::new();
-        let ret = input
-            .into_iter()
-            .filter_map(|m| match m {
-                Ok(m) => Some(Work::new(m, WorkArcWrapper::new(work.clone()))),
-                Err(e) => {
-                    output.push(Err(e));
-                    None
-                }
-            })
-            .collect();
-
-        (output, ret)
+fn continue_with<V: Send + Sync>(
+    data: V,
+    index: Option<usize>,
+    next: &[Continuation<V>],
+    completions: &[Arc<Completion>],
+) -> Routed<V> {
+    let index = match index {
+        Some(i) => i,
+        None => return Routed::Done(Ok(data)),
+    };
+    let mut work = Work::new(data, WorkArcWrapper::new(next[index].station().clone()));
+    work.next = next[index + 1..].to_vec();
+    for c in completions {
+        c.hold();
+        work.completions.push(c.clone());
     }
-
-    // pub async fn split_ret<V: 'static + Send>(
-    //     input: Vec<Result<WorkOutput<V>>>,
-    // ) -> (Vec<CrawlResult<V>>, Vec<Work<V>>) {
-    //     let mut output = Vec::new();
-    //     let ret = input
-    //         .into_iter()
-    //         .filter_map(|m| match m {
-    //             Ok(s) => match s {
-    //                 WorkOutput::Result(ret) => {
-    //                     output.push(ret);
-    //                     None
-    //                 }
-    //                 WorkOutput::Work(w) => Some(w),
-    //             },
-    //             Err(e) => {
-    //                 output.push(Err(CrawlErrorKind::Unknown.into()));
-    //                 None
-    //             }
-    //         })
-    //         .collect();
-
-    //     (output, ret)
-    // }
+    Routed::Queued(work)
 }
 
 #[cfg(test)]
 mod tests {
 
+    use super::super::scheduler::Scheduler;
     use super::*;
     use conveyor::futures;
-    use conveyor::{station_fn, Station};
+    use conveyor::station_fn;
 
     #[test]
     fn it_works() {
@@ -345,9 +309,9 @@ mod tests {
             station_fn(async move |val| Ok(vec![WorkOutput::Result(Ok(val))])),
         );
 
-        let worker = Worker::new();
+        let scheduler = Scheduler::default();
 
-        let ret = futures::executor::block_on(worker.run(vec![work]));
+        let ret = futures::executor::block_on(scheduler.run(vec![work]));
 
         assert_eq!(ret.len(), 1);
         assert!(ret[0].is_ok());
@@ -369,40 +333,54 @@ mod tests {
             }),
         );
 
-        let worker = Worker::new();
+        let scheduler = Scheduler::default();
 
-        let ret = futures::executor::block_on(worker.run(vec![work]));
+        let ret = futures::executor::block_on(scheduler.run(vec![work]));
 
         assert_eq!(ret.len(), 2);
         //assert_eq!(&ret[0].unwrap(), String::from("Value, baby!"));
     }
 
-    // #[test]
-    // fn tokio_work() {
-    //     tokio::run_async(
-    //         async {
-    //             let http = Http::new();
-
-    //             let worker = Worker::new();
-
-    //             let ret = await!(worker.run(vec![Work::new(
-    //                 "https://distrowatch.com".to_string(),
-    //                 station_fn(async move |val: String| {
-    //                     let url = Url::parse(val.as_str()).unwrap();
-    //                     let req = Request::new(Method::GET, url);
-    //                     let result = await!(Http::new()
-    //                         .chain(HttpResponseReader)
-    //                         .chain(utils::to_string())
-    //                         .execute(req))?;
-    //                     Ok(vec![WorkOutput::Result(result)])
-    //                 }),
-    //             )]));
-
-    //             for r in ret {
-    //                 println!("{:?}", r);
-    //             }
-    //         },
-    //     );
-    // }
+    #[test]
+    fn chained() {
+        let work = Work::new(
+            String::from("a"),
+            station_fn(async move |val: String| {
+                Ok(vec![WorkOutput::Result(Ok(val.clone())), WorkOutput::Then(val)])
+            }),
+        )
+        .chain(station_fn(async move |val: String| {
+            Ok(vec![WorkOutput::Result(Ok(format!("{}b", val)))])
+        }));
+
+        let ret = futures::executor::block_on(Scheduler::default().run(vec![work]));
+
+        let mut ret = ret.into_iter().map(|m| m.unwrap()).collect::<Vec<_>>();
+        ret.sort();
+        assert_eq!(ret, vec!["ab", "ab"]);
+    }
+
+    #[test]
+    fn completion() {
+        let done = Arc::new(Mutex::new(None));
+        let d = done.clone();
+        let completion = Completion::new(move |ok| *d.lock().unwrap() = Some(ok));
+
+        let inner = Work::new(
+            String::from("a"),
+            station_fn(async move |val: String| {
+                Ok(vec![WorkOutput::Work(Work::new(
+                    val,
+                    station_fn(async move |val| Ok(vec![WorkOutput::Result(Ok(val))])),
+                ))])
+            }),
+        )
+        .complete_with(&completion);
+        completion.release();
+        assert_eq!(*done.lock().unwrap(), None);
 
+        let ret = futures::executor::block_on(Scheduler::default().run(vec![inner]));
+        assert_eq!(ret.len(), 1);
+        assert_eq!(*done.lock().unwrap(), Some(true));
+    }
 }
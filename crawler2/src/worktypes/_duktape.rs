@@ -26,7 +26,7 @@ impl WorkType for Duktape {
 
         info!(log, "request duktape station");
 
-        let script = ctx.interpolate(self.script.as_str()).unwrap();
+        let script = ctx.interpolate(self.script.as_str())?;
 
         info!(log, "using script"; "script" => script);
 
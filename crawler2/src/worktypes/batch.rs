@@ -0,0 +1,198 @@
+use super::super::context::Context;
+use super::super::descriptions::{ValidationErrors, Validator};
+use super::super::error::*;
+use super::super::package::PackageExt;
+use super::super::traits::WorkType;
+use super::super::utils::station_fn_ctx2;
+use super::super::work::{WorkBox, WorkOutput};
+use super::concat_json::to_json_value;
+use conveyor::futures::channel::oneshot;
+use conveyor::futures::future;
+use conveyor::into_box;
+use conveyor_work::package::Package;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::async_await::compat::forward::IntoAwaitable;
+use tokio::timer::Delay;
+
+pub const DEFAULT_BATCH_WINDOW_MS: u64 = 1000;
+
+/// Groups packages into JSON arrays of `size` elements. Content which is
+/// not JSON becomes a string. A batch is emitted once it is full, or
+/// `window_ms` after its first package arrived.
+///
+/// The step receiving the first package of a batch waits for it to fill,
+/// giving its place among the in-flight steps to the packages filling it.
+/// The window is timed by the tokio runtime.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Batch {
+    /// Name of the batch packages, which can refer to `index`
+    pub name: String,
+    pub size: usize,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub window_ms: Option<u64>,
+}
+
+/// What became of a value added to the pending batch
+enum Added {
+    /// The value filled the batch
+    Full(usize, Vec<Value>),
+    /// The value started batch `index`, which is emitted when `full` fires
+    /// or the window ends
+    First(usize, oneshot::Receiver<()>),
+    /// The value joined a batch another step waits for
+    Joined,
+}
+
+#[derive(Default)]
+struct Pending {
+    values: Vec<Value>,
+    /// Wakes the step waiting for the batch once another step filled it
+    full: Option<oneshot::Sender<()>>,
+    /// Number of batches emitted so far
+    index: usize,
+}
+
+impl Pending {
+    fn add(&mut self, value: Value, size: usize) -> Added {
+        self.values.push(value);
+        if self.values.len() >= size {
+            if let Some(full) = self.full.take() {
+                full.send(()).ok();
+            }
+            let (index, values) = self.take();
+            return Added::Full(index, values);
+        }
+        if self.values.len() > 1 {
+            return Added::Joined;
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.full = Some(sender);
+        Added::First(self.index, receiver)
+    }
+
+    /// Takes batch `index`, unless it was emitted already
+    fn flush(&mut self, index: usize) -> Option<(usize, Vec<Value>)> {
+        if self.index != index {
+            return None;
+        }
+        Some(self.take())
+    }
+
+    fn take(&mut self) -> (usize, Vec<Value>) {
+        let index = self.index;
+        self.index += 1;
+        self.full = None;
+        (index, std::mem::replace(&mut self.values, Vec::new()))
+    }
+}
+
+struct BatchState {
+    ctx: Context,
+    name: String,
+    size: usize,
+    window: Duration,
+    pending: Mutex<Pending>,
+}
+
+impl BatchState {
+    fn package(&self, (index, values): (usize, Vec<Value>)) -> CrawlResult<Vec<WorkOutput<Package>>> {
+        let name = self.ctx.interpolate_with(&self.name, &args! { "index" => index })?;
+        let package = Package::new(&name, Value::Array(values)).with_content_type("application/json");
+        Ok(vec![WorkOutput::Result(Ok(package))])
+    }
+}
+
+#[typetag::serde]
+impl WorkType for Batch {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        info!(ctx.log().new(o!("worktype" => "batch")), "request batch station");
+
+        if self.size == 0 {
+            return Err(ValidationErrors::single(format!("{}.size", ctx.path()), "size must be at least 1").into());
+        }
+
+        Ok(into_box(station_fn_ctx2(
+            async move |mut package: Package, ctx: Arc<BatchState>| {
+                let body = await!(package.read_content())?;
+                let value = to_json_value(&body)?;
+
+                let added = ctx.pending.lock().unwrap().add(value, ctx.size);
+                let (index, full) = match added {
+                    Added::Full(index, values) => return Ok(ctx.package((index, values))?),
+                    Added::Joined => return Ok(Vec::new()),
+                    Added::First(index, full) => (index, full),
+                };
+
+                let window = Delay::new(Instant::now() + ctx.window).into_awaitable();
+                if let future::Either::Right((Err(e), _)) =
+                    await!(ctx.ctx.scheduler().lend(future::select(full, window)))
+                {
+                    warn!(ctx.ctx.log(), "batch timer failed, emitting the batch"; "error" => e.to_string());
+                }
+
+                let batch = ctx.pending.lock().unwrap().flush(index);
+                match batch {
+                    Some(batch) => Ok(ctx.package(batch)?),
+                    // Another step emitted the batch when it filled it
+                    None => Ok(Vec::new()),
+                }
+            },
+            Arc::new(BatchState {
+                ctx: ctx.clone(),
+                name: self.name.clone(),
+                size: self.size,
+                window: Duration::from_millis(self.window_ms.unwrap_or(DEFAULT_BATCH_WINDOW_MS)),
+                pending: Mutex::new(Pending::default()),
+            }),
+        )))
+    }
+
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        if self.size == 0 {
+            validator.error(format!("{}.size", path), "size must be at least 1");
+        }
+        validator.with_variables(args! { "index" => Value::Null }, |v| {
+            v.check_template(&format!("{}.name", path), &self.name);
+        });
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn pending_batches() {
+        let mut pending = Pending::default();
+        let mut full = match pending.add(Value::from("a"), 2) {
+            Added::First(0, full) => full,
+            _ => panic!("first value should start batch 0"),
+        };
+        match pending.add(Value::from("b"), 2) {
+            Added::Full(0, values) => assert_eq!(values, vec![Value::from("a"), Value::from("b")]),
+            _ => panic!("second value should fill batch 0"),
+        }
+        // The waiting step is woken, and finds its batch emitted
+        assert_eq!(full.try_recv(), Ok(Some(())));
+        assert!(pending.flush(0).is_none());
+
+        match pending.add(Value::from("c"), 3) {
+            Added::First(1, _) => {}
+            _ => panic!("third value should start batch 1"),
+        }
+        match pending.add(Value::from("d"), 3) {
+            Added::Joined => {}
+            _ => panic!("fourth value should join batch 1"),
+        }
+        // The window of batch 1 ends before it is full
+        assert_eq!(pending.flush(1), Some((1, vec![Value::from("c"), Value::from("d")])));
+        assert!(pending.values.is_empty());
+    }
+}
@@ -1,5 +1,7 @@
 use super::super::context::Context;
+use super::super::descriptions::Validator;
 use super::super::error::*;
+use super::super::package::detect_content_type;
 use super::super::traits::WorkType;
 use super::super::utils::station_fn_ctx2;
 use super::super::work::{WorkBox, WorkOutput};
@@ -28,16 +30,20 @@ impl WorkType for ChildProcess {
 
         info!(log, "request child-process station");
 
-        let command = ctx.interpolate(self.command.as_str()).unwrap();
+        let command = ctx.interpolate(self.command.as_str())?;
 
         info!(log, "using script"; "script" => command);
 
         Ok(into_box(station_fn(async move |mut package: Package| {
             let body = await!(package.read_content())?;
-            Ok(vec![WorkOutput::Result(Ok(package))])
+            Ok(vec![WorkOutput::Result(Ok(detect_content_type(package, &body).set_value(body)))])
         })))
     }
 
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        validator.check_template(&format!("{}.command", path), &self.command);
+    }
+
     fn box_clone(&self) -> Box<WorkType> {
         Box::new(self.clone())
     }
@@ -1,20 +1,64 @@
 use super::super::context::{Context, ParentOrRoot};
-use super::super::descriptions::{compile_steps, WorkDescription};
+use super::super::descriptions::{compile_steps, Validator, WorkDescription};
 use super::super::error::*;
 use super::super::traits::WorkType;
 use super::super::utils::{station_fn_ctx2, WorkArcWrapper, WorkBoxWrapper};
-use super::super::work::{Work, WorkBox, WorkOutput, Worker};
+use super::super::work::{Work, WorkBox, WorkOutput};
 use conveyor::futures::prelude::*;
 use conveyor::{into_box, station_fn};
 use conveyor_work::package::{ConcatStream, Package};
+use slog::Logger;
 use std::fmt;
 use std::sync::Arc;
 
+/// What an aggregating worktype does when one of its inner steps fails
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Fail the aggregate with the first error
+    Fail,
+    /// Leave failed results out of the aggregate
+    Skip,
+    /// Build the aggregate from the successful results and pass the errors on
+    Forward,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> FailurePolicy {
+        FailurePolicy::Fail
+    }
+}
+
+impl FailurePolicy {
+    pub(crate) fn partition<T>(
+        self,
+        results: Vec<CrawlResult<T>>,
+        log: &Logger,
+    ) -> CrawlResult<(Vec<T>, Vec<CrawlError>)> {
+        let mut oks = Vec::with_capacity(results.len());
+        let mut errors = Vec::new();
+        for result in results {
+            match result {
+                Ok(o) => oks.push(o),
+                Err(e) => match self {
+                    FailurePolicy::Fail => return Err(e),
+                    FailurePolicy::Skip => {
+                        warn!(log, "skipping failed result"; "error" => e.to_string());
+                    }
+                    FailurePolicy::Forward => errors.push(e),
+                },
+            }
+        }
+        Ok((oks, errors))
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Concat {
     pub name: String,
     pub steps: Vec<WorkDescription>,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
 }
 
 #[typetag::serde]
@@ -30,30 +74,39 @@ impl WorkType for Concat {
 
         Ok(into_box(station_fn_ctx2(
             async move |mut package: Package,
-                        ctx: Arc<(Context, Arc<WorkBox<Package>>, String)>| {
+                        ctx: Arc<(Context, Arc<WorkBox<Package>>, String, FailurePolicy)>| {
                 info!(ctx.0.log(), "running concat");
-                let worker = Worker::new();
-                let ret = await!(
-                    worker.run(vec![Work::new(package, WorkArcWrapper::new(ctx.1.clone()))])
-                );
+                // All the results are needed here, so the steps run while this one waits
+                let ret = await!(ctx
+                    .0
+                    .scheduler()
+                    .run_nested(vec![Work::new(package, WorkArcWrapper::new(ctx.1.clone()))]));
 
-                // if ret.iter().find(|m| m.is_err()).is_some() {
-                //     return Err(CrawlErrorKind::Unknown.into());
-                // }
+                let (packages, errors) = match ctx.3.partition(ret, ctx.0.log()) {
+                    Ok(s) => s,
+                    Err(e) => return Ok(vec![WorkOutput::Result(Err(e))]),
+                };
 
-                let name = ctx.0.interpolate(&ctx.2).unwrap();
+                let name = ctx.0.interpolate(&ctx.2)?;
 
                 let s = await!(ConcatStream::new(
-                    stream::iter(ret)
-                        .then(async move |mut m| await!(m.as_mut().unwrap().read_content()))
+                    stream::iter(packages)
+                        .then(async move |mut m| await!(m.read_content()))
                 ))?;
 
-                Ok(vec![WorkOutput::Result(Ok(Package::new(&name, s)))])
+                let mut out = vec![WorkOutput::Result(Ok(Package::new(&name, s)))];
+                out.extend(errors.into_iter().map(|e| WorkOutput::Result(Err(e))));
+                Ok(out)
             },
-            Arc::new((ctx, Arc::new(work), self.name.clone())),
+            Arc::new((ctx, Arc::new(work), self.name.clone(), self.on_failure)),
         )))
     }
 
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        validator.check_template(&format!("{}.name", path), &self.name);
+        validator.check_steps(&format!("{}.steps", path), &self.steps);
+    }
+
     fn box_clone(&self) -> Box<WorkType> {
         Box::new(self.clone())
     }
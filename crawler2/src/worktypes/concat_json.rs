@@ -0,0 +1,188 @@
+use super::super::context::{Context, ParentOrRoot};
+use super::super::descriptions::{compile_steps, Validator, WorkDescription};
+use super::super::error::*;
+use super::super::traits::WorkType;
+use super::super::utils::{station_fn_ctx2, WorkArcWrapper};
+use super::super::work::{Work, WorkBox, WorkOutput};
+use super::concat::FailurePolicy;
+use conveyor::into_box;
+use conveyor::ConveyorError;
+use conveyor_work::package::Package;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// Collects the JSON packages produced by `steps` into a single package.
+/// Without a `key` the result is an array, otherwise an object keyed by
+/// the `key` template, which can refer to `name`, `index` and `value`
+/// (e.g. `${value.id}`).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConcatJson {
+    pub name: String,
+    pub steps: Vec<WorkDescription>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+}
+
+#[typetag::serde]
+impl WorkType for ConcatJson {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        let log = ctx.log().new(o!("worktype" => "concat-json"));
+
+        info!(log, "request concat-json station");
+
+        let mut ctx = Context::new(ParentOrRoot::Parent(Box::new(ctx.clone())), None, Some(log));
+
+        let work = compile_steps(&self.steps, &mut ctx)?;
+
+        Ok(into_box(station_fn_ctx2(
+            async move |package: Package, ctx: Arc<(Context, Arc<WorkBox<Package>>, ConcatJson)>| {
+                info!(ctx.0.log(), "running concat-json");
+                // All the results are needed here, so the steps run while this one waits
+                let ret = await!(ctx
+                    .0
+                    .scheduler()
+                    .run_nested(vec![Work::new(package, WorkArcWrapper::new(ctx.1.clone()))]));
+
+                let mut values = Vec::with_capacity(ret.len());
+                for result in ret {
+                    values.push(match result {
+                        Ok(mut p) => match await!(p.read_content()) {
+                            Ok(body) => serde_json::from_slice::<Value>(&body)
+                                .map(|v| (p.name().to_string(), v))
+                                .map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e)))),
+                            Err(e) => Err(e.into()),
+                        },
+                        Err(e) => Err(e),
+                    });
+                }
+
+                let (values, errors) = match ctx.2.on_failure.partition(values, ctx.0.log()) {
+                    Ok(s) => s,
+                    Err(e) => return Ok(vec![WorkOutput::Result(Err(e))]),
+                };
+
+                let value = match &ctx.2.key {
+                    None => Value::Array(values.into_iter().map(|m| m.1).collect()),
+                    Some(key) => {
+                        let mut map = Map::new();
+                        for (index, (name, value)) in values.into_iter().enumerate() {
+                            let key = ctx.0.interpolate_with(
+                                key,
+                                &args! {
+                                    "name" => name,
+                                    "index" => index,
+                                    "value" => value
+                                },
+                            )?;
+                            if map.contains_key(&key) {
+                                warn!(ctx.0.log(), "duplicate key in concat-json"; "key" => &key);
+                            }
+                            map.insert(key, value);
+                        }
+                        Value::Object(map)
+                    }
+                };
+
+                let name = ctx.0.interpolate(&ctx.2.name)?;
+
+                let mut out = vec![WorkOutput::Result(Ok(Package::new(&name, value)))];
+                out.extend(errors.into_iter().map(|e| WorkOutput::Result(Err(e))));
+                Ok(out)
+            },
+            Arc::new((ctx, Arc::new(work), self.clone())),
+        )))
+    }
+
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        validator.check_template(&format!("{}.name", path), &self.name);
+        validator.check_steps(&format!("{}.steps", path), &self.steps);
+        if let Some(key) = &self.key {
+            let variables = args! {
+                "name" => Value::Null,
+                "index" => Value::Null,
+                "value" => Value::Null
+            };
+            validator.with_variables(variables, |v| {
+                v.check_template(&format!("{}.key", path), key);
+            });
+        }
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+/// Turns the content of a package into JSON. Content which already is valid
+/// JSON is kept as is, anything else becomes a JSON string.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ToJson {}
+
+pub(crate) fn to_json_value(body: &[u8]) -> Result<Value, ConveyorError> {
+    if let Ok(v) = serde_json::from_slice::<Value>(body) {
+        return Ok(v);
+    }
+    let text = std::str::from_utf8(body).map_err(|e| ConveyorError::new(e))?;
+    Ok(Value::String(text.to_string()))
+}
+
+#[typetag::serde]
+impl WorkType for ToJson {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        info!(ctx.log().new(o!("worktype" => "to-json")), "request to-json station");
+
+        Ok(into_box(conveyor::station_fn(async move |mut package: Package| {
+            let body = await!(package.read_content())?;
+            let value = to_json_value(&body)?;
+            Ok(vec![WorkOutput::Result(Ok(Package::new(package.name(), value)))])
+        })))
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn to_json() {
+        assert_eq!(
+            to_json_value(br#"{"title": "Concert"}"#).unwrap(),
+            serde_json::json!({"title": "Concert"})
+        );
+        assert_eq!(
+            to_json_value(b"plain text").unwrap(),
+            Value::String("plain text".to_string())
+        );
+        assert!(to_json_value(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn failure_policy() {
+        use slog::{Discard, Logger};
+        let log = Logger::root(Discard, o!());
+        let results = || {
+            vec![
+                Ok(1),
+                Err(CrawlError::new(CrawlErrorKind::NotFound("page".to_string()))),
+                Ok(2),
+            ]
+        };
+
+        assert!(FailurePolicy::Fail.partition(results(), &log).is_err());
+
+        let (oks, errors) = FailurePolicy::Skip.partition(results(), &log).unwrap();
+        assert_eq!(oks, vec![1, 2]);
+        assert!(errors.is_empty());
+
+        let (oks, errors) = FailurePolicy::Forward.partition(results(), &log).unwrap();
+        assert_eq!(oks, vec![1, 2]);
+        assert_eq!(errors.len(), 1);
+    }
+}
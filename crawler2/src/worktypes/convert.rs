@@ -0,0 +1,443 @@
+use super::super::content::{self, extension};
+use super::super::context::Context;
+use super::super::error::*;
+use super::super::package::PackageExt;
+use super::super::traits::WorkType;
+use super::super::utils::station_fn_ctx2;
+use super::super::work::{WorkBox, WorkOutput};
+use conveyor::into_box;
+use conveyor_work::package::Package;
+use serde_json::{Map, Value};
+use slog::Logger;
+use std::error::Error;
+use std::sync::Arc;
+use xml::reader::{EventReader, XmlEvent as ReadEvent};
+use xml::writer::{EmitterConfig, XmlEvent as WriteEvent};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Yaml,
+    Csv,
+    Toml,
+    Xml,
+}
+
+impl Format {
+    pub fn from_mime(mime: &str) -> Option<Format> {
+        let mime = mime.split(';').next().unwrap_or("").trim().to_lowercase();
+        let format = match mime.as_str() {
+            "application/json" | "text/json" => Format::Json,
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => {
+                Format::Yaml
+            }
+            "text/csv" | "application/csv" => Format::Csv,
+            "application/toml" | "text/toml" => Format::Toml,
+            "application/xml" | "text/xml" => Format::Xml,
+            m if m.ends_with("+json") => Format::Json,
+            m if m.ends_with("+xml") => Format::Xml,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        content::extension_mime(ext).and_then(Format::from_mime)
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Yaml => "application/yaml",
+            Format::Csv => "text/csv",
+            Format::Toml => "application/toml",
+            Format::Xml => "application/xml",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Yaml => "yaml",
+            Format::Csv => "csv",
+            Format::Toml => "toml",
+            Format::Xml => "xml",
+        }
+    }
+
+    /// Figures out the format of a package from its content type, falling
+    /// back to the extension of its name
+    pub fn detect(package: &Package, body: Option<&[u8]>) -> Option<Format> {
+        if let Some(format) = package.content_type().and_then(Format::from_mime) {
+            return Some(format);
+        }
+        content::detect(package.name(), None, body).and_then(|c| Format::from_mime(&c))
+    }
+
+    pub fn decode(self, body: &[u8]) -> CrawlResult<Value> {
+        match self {
+            Format::Json => serde_json::from_slice(body).map_err(error),
+            Format::Yaml => serde_yaml::from_slice(body).map_err(error),
+            Format::Toml => toml::from_slice(body).map_err(error),
+            Format::Csv => decode_csv(body),
+            Format::Xml => decode_xml(body),
+        }
+    }
+
+    pub fn encode(self, value: &Value) -> CrawlResult<Vec<u8>> {
+        match self {
+            Format::Json => serde_json::to_vec_pretty(value).map_err(error),
+            Format::Yaml => serde_yaml::to_vec(value).map_err(error),
+            Format::Toml => toml::to_vec(value).map_err(error),
+            Format::Csv => encode_csv(value),
+            Format::Xml => encode_xml(value),
+        }
+    }
+}
+
+/// Converts package content between JSON, YAML, CSV, TOML and XML.
+/// When `from` is omitted the format is detected from the package.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Convert {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub from: Option<Format>,
+    pub to: Format,
+}
+
+#[typetag::serde]
+impl WorkType for Convert {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        let log = ctx
+            .log()
+            .new(o!("worktype" => "convert", "to" => format!("{:?}", self.to)));
+
+        info!(log, "request convert station");
+
+        Ok(into_box(station_fn_ctx2(
+            async move |mut package: Package, ctx: Arc<(Convert, Logger)>| {
+                let body = await!(package.read_content())?;
+                let from = match ctx.0.from.or_else(|| Format::detect(&package, Some(&body))) {
+                    Some(f) => f,
+                    None => {
+                        return Ok(vec![WorkOutput::Result(Err(CrawlErrorKind::NotFound(
+                            format!("could not detect format of package: {}", package.name()),
+                        )
+                        .into()))])
+                    }
+                };
+                let to = ctx.0.to;
+
+                debug!(ctx.1, "converting package"; "name" => package.name(), "from" => format!("{:?}", from));
+
+                let name = rename(package.name(), to);
+                let converted = from.decode(&body).and_then(|value| {
+                    Ok(match to {
+                        Format::Json => Package::new(&name, value),
+                        _ => Package::new(&name, to.encode(&value)?),
+                    })
+                });
+
+                Ok(vec![WorkOutput::Result(
+                    converted.map(|p| p.with_content_type(to.mime())),
+                )])
+            },
+            Arc::new((self.clone(), log)),
+        )))
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+fn error<E: Error + Send + Sync + 'static>(e: E) -> CrawlError {
+    CrawlError::new(CrawlErrorKind::Error(Box::new(e)))
+}
+
+/// Swaps a known format extension for the one of `to`
+fn rename(name: &str, to: Format) -> String {
+    match extension(name) {
+        Some(ext) if Format::from_extension(ext).is_some() => {
+            format!("{}.{}", &name[..name.len() - ext.len() - 1], to.extension())
+        }
+        _ => name.to_string(),
+    }
+}
+
+fn decode_csv(body: &[u8]) -> CrawlResult<Value> {
+    let mut reader = csv::Reader::from_reader(body);
+    let headers = reader.headers().map_err(error)?.clone();
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(error)?;
+        let row = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+            .collect::<Map<_, _>>();
+        rows.push(Value::Object(row));
+    }
+    Ok(Value::Array(rows))
+}
+
+fn csv_cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Bool(_) | Value::Number(_) => value.to_string(),
+        _ => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
+
+fn encode_csv(value: &Value) -> CrawlResult<Vec<u8>> {
+    let rows = match value {
+        Value::Array(rows) => rows.iter().collect::<Vec<_>>(),
+        Value::Object(_) => vec![value],
+        _ => {
+            return Err(CrawlErrorKind::Error(
+                "csv output requires an array of objects".into(),
+            )
+            .into())
+        }
+    };
+
+    // Columns are the union of all keys, in the order they first appear
+    let mut columns: Vec<&str> = Vec::new();
+    for row in &rows {
+        if let Value::Object(o) = row {
+            for key in o.keys() {
+                if !columns.contains(&key.as_str()) {
+                    columns.push(key.as_str());
+                }
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    if !columns.is_empty() {
+        writer.write_record(&columns).map_err(error)?;
+    }
+    for row in rows {
+        let record = match row {
+            Value::Object(o) => columns
+                .iter()
+                .map(|c| o.get(*c).map(csv_cell).unwrap_or_default())
+                .collect::<Vec<_>>(),
+            other => vec![csv_cell(other)],
+        };
+        writer.write_record(&record).map_err(error)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| CrawlError::new(CrawlErrorKind::Error(e.to_string().into())))
+}
+
+// XML elements map to objects: attributes become "@name" keys, text becomes
+// "#text" (or the value itself when an element holds nothing else) and
+// repeated child elements become arrays.
+
+fn insert_child(map: &mut Map<String, Value>, name: String, value: Value) {
+    match map.remove(&name) {
+        None => {
+            map.insert(name, value);
+        }
+        Some(Value::Array(mut a)) => {
+            a.push(value);
+            map.insert(name, Value::Array(a));
+        }
+        Some(existing) => {
+            map.insert(name, Value::Array(vec![existing, value]));
+        }
+    }
+}
+
+fn finish_element(map: Map<String, Value>, text: String) -> Value {
+    let text = text.trim();
+    if map.is_empty() {
+        if text.is_empty() {
+            Value::Null
+        } else {
+            Value::String(text.to_string())
+        }
+    } else {
+        let mut map = map;
+        if !text.is_empty() {
+            map.insert("#text".to_string(), Value::String(text.to_string()));
+        }
+        Value::Object(map)
+    }
+}
+
+fn decode_xml(body: &[u8]) -> CrawlResult<Value> {
+    let mut stack: Vec<(String, Map<String, Value>, String)> =
+        vec![(String::new(), Map::new(), String::new())];
+
+    for event in EventReader::new(body) {
+        match event.map_err(error)? {
+            ReadEvent::StartElement {
+                name, attributes, ..
+            } => {
+                let mut map = Map::new();
+                for attr in attributes {
+                    map.insert(
+                        format!("@{}", attr.name.local_name),
+                        Value::String(attr.value),
+                    );
+                }
+                stack.push((name.local_name, map, String::new()));
+            }
+            ReadEvent::EndElement { .. } => {
+                let (name, map, text) = stack.pop().unwrap();
+                let value = finish_element(map, text);
+                insert_child(&mut stack.last_mut().unwrap().1, name, value);
+            }
+            ReadEvent::Characters(s) | ReadEvent::CData(s) => {
+                stack.last_mut().unwrap().2.push_str(&s);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Value::Object(stack.pop().unwrap().1))
+}
+
+fn write_xml_value<W: std::io::Write>(
+    writer: &mut xml::EventWriter<W>,
+    name: &str,
+    value: &Value,
+) -> xml::writer::Result<()> {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                write_xml_value(writer, name, item)?;
+            }
+            return Ok(());
+        }
+        Value::Object(o) => {
+            let mut start = WriteEvent::start_element(name);
+            for (k, v) in o.iter().filter(|(k, _)| k.starts_with('@')) {
+                if let Value::String(s) = v {
+                    start = start.attr(&k[1..], s);
+                }
+            }
+            writer.write(start)?;
+            for (k, v) in o.iter() {
+                if k == "#text" {
+                    writer.write(WriteEvent::characters(&csv_cell(v)))?;
+                } else if !k.starts_with('@') {
+                    write_xml_value(writer, k, v)?;
+                }
+            }
+        }
+        Value::Null => {
+            writer.write(WriteEvent::start_element(name))?;
+        }
+        other => {
+            writer.write(WriteEvent::start_element(name))?;
+            writer.write(WriteEvent::characters(&csv_cell(other)))?;
+        }
+    }
+    writer.write(WriteEvent::end_element())
+}
+
+fn encode_xml(value: &Value) -> CrawlResult<Vec<u8>> {
+    let mut out = Vec::new();
+    {
+        let mut writer = EmitterConfig::new()
+            .perform_indent(true)
+            .create_writer(&mut out);
+        match value {
+            Value::Object(o) if o.len() == 1 && !o.values().next().unwrap().is_array() => {
+                let (name, value) = o.iter().next().unwrap();
+                write_xml_value(&mut writer, name, value)
+            }
+            Value::Array(_) => {
+                let mut root = Map::new();
+                root.insert("item".to_string(), value.clone());
+                write_xml_value(&mut writer, "root", &Value::Object(root))
+            }
+            _ => write_xml_value(&mut writer, "root", value),
+        }
+        .map_err(error)?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(
+            Format::from_mime("application/json; charset=utf-8"),
+            Some(Format::Json)
+        );
+        assert_eq!(Format::from_mime("application/rss+xml"), Some(Format::Xml));
+        assert_eq!(Format::from_mime("text/html"), None);
+        assert_eq!(rename("loppen.json", Format::Yaml), "loppen.yaml");
+        assert_eq!(rename("loppen", Format::Yaml), "loppen");
+        assert_eq!(Format::detect(&Package::new("loppen.yml", ""), None), Some(Format::Yaml));
+        assert_eq!(Format::detect(&Package::new("events", ""), Some(b"[1, 2]")), Some(Format::Json));
+        assert_eq!(
+            Format::detect(&Package::new("loppen.json", "").with_content_type("application/xml"), None),
+            Some(Format::Xml)
+        );
+    }
+
+    #[test]
+    fn csv_roundtrip() {
+        let value = json!([
+            {"title": "Concert", "price": 100},
+            {"title": "Quiz", "tags": ["fun"]}
+        ]);
+        let out = Format::Csv.encode(&value).unwrap();
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "price,title,tags\n100,Concert,\n,Quiz,\"[\"\"fun\"\"]\"\n"
+        );
+        assert_eq!(
+            Format::Csv.decode(&out).unwrap(),
+            json!([
+                {"title": "Concert", "price": "100", "tags": ""},
+                {"title": "Quiz", "price": "", "tags": "[\"fun\"]"}
+            ])
+        );
+    }
+
+    #[test]
+    fn xml_to_json() {
+        let value = Format::Xml
+            .decode(
+                br#"<events><event id="1">Concert</event><event id="2"><title>Quiz</title></event></events>"#,
+            )
+            .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "events": {
+                    "event": [
+                        {"@id": "1", "#text": "Concert"},
+                        {"@id": "2", "title": "Quiz"}
+                    ]
+                }
+            })
+        );
+
+        let xml = Format::Xml.encode(&value).unwrap();
+        assert_eq!(Format::Xml.decode(&xml).unwrap(), value);
+    }
+
+    #[test]
+    fn yaml_and_toml() {
+        let value = json!({"name": "Loppen", "port": 80});
+        let yaml = Format::Yaml.encode(&value).unwrap();
+        assert_eq!(Format::Yaml.decode(&yaml).unwrap(), value);
+        let toml = Format::Toml.encode(&value).unwrap();
+        assert_eq!(Format::Toml.decode(&toml).unwrap(), value);
+    }
+}
@@ -52,7 +52,25 @@
         function Package(name, content) {
             this.name = name;
             this.content = content;
+            // String values kept as metadata of the returned package
+            this.meta = {};
         }
+        // The text content parsed as JSON, when first used
+        Object.defineProperty(Package.prototype, 'json', {
+            get: function () {
+                if (this._json === undefined) {
+                    var text = this.text !== undefined ? this.text : this.content;
+                    var json;
+                    try {
+                        json = JSON.parse(text);
+                    } catch (e) {
+                        throw new TypeError('content of ' + this.name + ' is not JSON: ' + e.message);
+                    }
+                    Object.defineProperty(this, '_json', { value: json });
+                }
+                return this._json;
+            }, enumerable: false, configurable: true
+        });
         return Package;
     })();
 
@@ -1,14 +1,23 @@
 use duktape2::prelude::*;
+use super::super::super::content;
 use super::super::super::context::{Context as CrawlContext};
-use super::super::super::error::CrawlResult;
+use super::super::super::error::{CrawlError, CrawlErrorKind, CrawlResult};
 use vfs::physical::PhysicalFS;
 use conveyor_work::{package::Package};
+use super::super::super::package::{detect_content_type, PackageExt};
 use super::super::super::work::WorkOutput;
 use conveyor::{Result, ConveyorError};
 
 pub(crate) static REQUIRE_JS: &'static str = include_str!("./runtime.js");
 
 
+/// A package as handed to a script
+pub struct Input {
+    pub name: String,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
 pub struct VM {
     inner: Context,
     ctx: CrawlContext,
@@ -59,34 +68,46 @@ impl VM {
         &self.ctx
     }
 
-    pub fn run(&self, package:(String, Vec<u8>)) -> Result<Vec<WorkOutput<Package>>> {
-
-        let module = self.inner.require(&self.script).unwrap().push();
-        let function: Function = self.inner.getp().unwrap();
-        let p_ctor = self.inner.get_global_string("Package").getp::<Function>().unwrap();
-        let p = p_ctor.construct::<_, Object>((&package.0, package.1.as_slice())).unwrap();
-        let re = function.call::<_, Array>(p).unwrap();
-        let pack = parse(&re).unwrap();
-
-        // let pack = match deserialize_reference(&re) {
-        //     Err(e) => unimplemented!("could not do"),
-        //     Ok(s) => match s {
-        //         ValueOrBytes::Bytes(bs) => Package::new(package.0, bs),
-        //         ValueOrBytes::Value(v)  => {
-        //             println!("{}", serde_json::to_string_pretty(&v).unwrap());
-        //             Package::new(package.0, v)
-        //         },
-        //     }
-        // };
-
-        Ok(pack)
-
-        // return Ok(vec![WorkOutput::Result(Ok(pack))])
+    /// Runs the script on `package`. A script which throws, or returns
+    /// something else than an array of outputs, fails the package rather
+    /// than the worker.
+    pub fn run(&self, package: Input) -> Result<Vec<WorkOutput<Package>>> {
+        match self.call(package) {
+            Ok(pack) => Ok(pack),
+            Err(e) => Ok(vec![WorkOutput::Result(Err(CrawlError::new(CrawlErrorKind::Error(Box::new(e)))))]),
+        }
+    }
+
+    fn call(&self, package: Input) -> DukResult<Vec<WorkOutput<Package>>> {
+        self.inner.require(&self.script)?.push();
+        let function: Function = self.inner.getp()?;
+        let p_ctor = self.inner.get_global_string("Package").getp::<Function>()?;
+        let p = p_ctor.construct::<_, Object>((&package.name, package.body.as_slice()))?;
+        // Text is also given decoded, which the `json` getter of packages parses
+        let text = match &package.content_type {
+            Some(c) if !content::is_text(c) => None,
+            c => Some(content::decode(&package.body, c.as_ref().map(|c| c.as_str()))),
+        };
+        if let Some(content_type) = &package.content_type {
+            p.set("contentType", content_type.as_str());
+        }
+        if let Some(text) = &text {
+            p.set("text", text.as_ref());
+        }
+        let re = function.call::<_, Reference>(p)?;
+        match re.get_type() {
+            Type::Array => parse(&re.to()?),
+            t => Err(DukError::new(
+                DukErrorCode::Type,
+                format!("script {} returned {:?}, expected an array of outputs", self.script, t),
+            )),
+        }
     }
 }
 
 
 fn parse(array: &Array) -> DukResult<Vec<WorkOutput<Package>>> {
+    let mut out = Vec::new();
     let iter = array.iter();
     for entry in iter {
         let o: Object = entry.to()?;
@@ -98,27 +119,50 @@ fn parse(array: &Array) -> DukResult<Vec<WorkOutput<Package>>> {
                 WorkOutput::Then(parse_package(&o.get::<_,Object>("package")?)?)
             },
             "err" => {
-                unimplemented!("Error {}", o)
+                let message: &str = o.get::<_, Object>("error")?.get("message")?;
+                WorkOutput::Result(Err(CrawlErrorKind::Error(message.into()).into()))
             }
-            _ => {
-                 unimplemented!("Error")
+            t => {
+                return Err(DukError::new(DukErrorCode::Type, format!("unsupported output type: {}", t)))
             }
         };
+        out.push(w);
     }
 
-    Ok(Vec::new())
+    Ok(out)
 }
 
 fn parse_package(package: &Object) -> DukResult<Package> {
     let name: &str = package.get("name")?;
     let content: Reference = package.get("content")?;
 
-    let pack = match content.get_type() {
-        Type::String => Package::new(name, content.to::<&str>()?),
-        Type::Buffer => Package::new(name, content.to::<&[u8]>()?),
+    let mut pack = match content.get_type() {
+        Type::String => {
+            let content: &str = content.to()?;
+            detect_content_type(Package::new(name, content), content.as_bytes())
+        }
+        Type::Buffer => {
+            let content: &[u8] = content.to()?;
+            detect_content_type(Package::new(name, content), content)
+        }
         _ => return Err(DukError::new(DukErrorCode::Type, format!("invalid package content type: {:?}", content.get_type()))),
     };
 
+    let content_type: Reference = package.get("contentType")?;
+    if let Type::String = content_type.get_type() {
+        pack.set_content_type(content_type.to::<&str>()?);
+    }
+
+    let meta: Reference = package.get("meta")?;
+    if let Type::Object = meta.get_type() {
+        let meta: Object = meta.to()?;
+        for (key, value) in meta.iter() {
+            if let Type::String = value.get_type() {
+                pack.set_metadata(key.to_string(), value.to::<&str>()?);
+            }
+        }
+    }
+
     Ok(pack)
 }
 
@@ -144,7 +188,7 @@ fn deserialize_reference2(value: &Reference) -> DukResult<Value> {
         Type::String => Value::String(value.to()?),
         Type::Null | Type::Undefined => Value::Null,
         Type::Boolean => Value::Bool(value.to()?),
-        Type::Number => Value::Number(Number::from_f64(value.to()?).unwrap()),
+        Type::Number => number(value.to()?),
         Type::Object => {
             let o:Object = value.to()?;
             let iter = o.iter();
@@ -154,14 +198,21 @@ fn deserialize_reference2(value: &Reference) -> DukResult<Value> {
             }
             Value::Object(out)
         }
-        _ => {
-            unimplemented!("reference {:?}", value.get_type());
-        }
+        t => return Err(unsupported(t)),
     };
 
     Ok(val)
 }
 
+/// NaN and infinities have no JSON representation
+fn number(n: f64) -> Value {
+    Number::from_f64(n).map(Value::Number).unwrap_or(Value::Null)
+}
+
+fn unsupported(t: Type) -> DukError {
+    DukError::new(DukErrorCode::Type, format!("unsupported value type: {:?}", t))
+}
+
 fn deserialize_reference(value: &Reference) -> DukResult<ValueOrBytes> {
     let val = match value.get_type() {
         Type::Array => {
@@ -171,7 +222,7 @@ fn deserialize_reference(value: &Reference) -> DukResult<ValueOrBytes> {
         Type::String => Value::String(value.to()?),
         Type::Null | Type::Undefined => Value::Null,
         Type::Boolean => Value::Bool(value.to()?),
-        Type::Number => Value::Number(Number::from_f64(value.to()?).unwrap()),
+        Type::Number => number(value.to()?),
         Type::Buffer => {
             let bs = value.to()?;
             return Ok(ValueOrBytes::Bytes(bs));
@@ -185,9 +236,7 @@ fn deserialize_reference(value: &Reference) -> DukResult<ValueOrBytes> {
             }
             Value::Object(out)
         }
-        _ => {
-            unimplemented!("reference {:?}", value.get_type());
-        }
+        t => return Err(unsupported(t)),
     };
 
     Ok(ValueOrBytes::Value(val))
@@ -206,4 +255,85 @@ fn deserialize_array(array: &Array) -> DukResult<Value> {
 
     Ok(Value::Array(out))
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+
+    use super::super::super::super::context::{Args, ParentOrRoot, RootContext};
+    use super::super::super::super::descriptions::{TargetDescription, WorkTargetDescription};
+    use super::super::super::super::environment::Environment as CrawlEnvironment;
+    use super::super::super::super::target::Target;
+    use super::*;
+    use slog::{Discard, Logger};
+    use std::fs;
+
+    static SCRIPT: &'static str = r#"
+module.exports = function (p) {
+    if (p.name === 'throw') throw new Error('boom');
+    if (p.name === 'object') return { name: p.name };
+    return [$ok(p.name, JSON.stringify(p.json))];
+};
+"#;
+
+    fn vm() -> VM {
+        let dir = std::env::temp_dir().join(format!("crawler2-vm-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("script.js");
+        fs::write(&script, SCRIPT).unwrap();
+
+        let env = CrawlEnvironment::build("/", Logger::root(Discard, o!())).build();
+        let desc = TargetDescription {
+            name: "Test".to_string(),
+            imports: Vec::new(),
+            work: WorkTargetDescription {
+                input: Value::Null,
+                steps: Vec::new(),
+            },
+            flows: Vec::new(),
+        };
+        let target = Target::new("/", env, desc).unwrap();
+        let ctx = CrawlContext::new(ParentOrRoot::Root(RootContext::new(target, Args::new())), None, None);
+        VM::new(ctx, script.to_str().unwrap())
+    }
+
+    fn run(vm: &VM, name: &str, body: &str) -> Vec<WorkOutput<Package>> {
+        vm.run(Input {
+            name: name.to_string(),
+            content_type: Some("application/json".to_string()),
+            body: body.as_bytes().to_vec(),
+        })
+        .unwrap()
+    }
+
+    fn failed(outputs: &[WorkOutput<Package>]) -> bool {
+        match outputs {
+            [WorkOutput::Result(Err(_))] => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn script_failures() {
+        let vm = vm();
+        // The worker survives each failure and goes on with the next package
+        assert!(failed(&run(&vm, "throw", "{}")));
+        assert!(failed(&run(&vm, "object", "{}")));
+        match run(&vm, "json", r#"{"a": 1}"#).as_slice() {
+            [WorkOutput::Result(Ok(p))] => assert_eq!(p.name(), "json"),
+            _ => panic!("the worker should run the next package"),
+        }
+    }
+
+    #[test]
+    fn json_getter() {
+        let vm = vm();
+        match run(&vm, "json", "<html></html>").as_slice() {
+            [WorkOutput::Result(Err(e))] => assert!(e.to_string().contains("content of json is not JSON")),
+            _ => panic!("content which is not JSON should fail the package"),
+        }
+        match run(&vm, "json", r#"{"a": 1}"#).as_slice() {
+            [WorkOutput::Result(Ok(p))] => assert_eq!(p.name(), "json"),
+            _ => panic!("json content should be parsed"),
+        }
+    }
+}
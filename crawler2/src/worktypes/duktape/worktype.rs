@@ -1,5 +1,8 @@
 use super::super::super::context::{Context, ParentOrRoot};
+use super::super::super::descriptions::Validator;
 use super::super::super::error::*;
+use super::super::super::metrics;
+use super::super::super::package::PackageExt;
 use super::super::super::traits::WorkType;
 use super::super::super::utils::station_fn_ctx2;
 use super::super::super::work::{WorkBox, WorkOutput};
@@ -7,7 +10,8 @@ use conveyor::{into_box, station_fn, WorkStation, Chain};
 use conveyor_work::package::Package;
 use std::fmt;
 use std::sync::{Arc,Mutex};
-use super::vm::VM;
+use std::time::Instant;
+use super::vm::{Input, VM};
 
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -29,7 +33,7 @@ impl WorkType for Duktape {
 
         info!(log, "request duktape station");
 
-        let script = ctx.interpolate(self.script.as_str()).unwrap();
+        let script = ctx.interpolate(self.script.as_str())?;
         let script = ctx.root().resolve_path(script)?;
 
         info!(log, "using script"; "script" => &script);
@@ -37,11 +41,18 @@ impl WorkType for Duktape {
         let mut ctx = Context::new(ParentOrRoot::Parent(Box::new(ctx.clone())), None, Some(log));
 
         let station = station_fn(async move |mut package: Package| {
-            let buffer = await!(package.read_content())?;
-            Ok((package.name().to_string(), buffer))
-        }).pipe(WorkStation::new(2, |package: (String, Vec<u8>), work: &mut VM| {
+            let body = await!(package.read_content())?;
+            Ok(Input {
+                name: package.name().to_string(),
+                content_type: package.content_type().map(|c| c.to_string()),
+                body,
+            })
+        }).pipe(WorkStation::new(2, |package: Input, work: &mut VM| {
             info!(work.ctx().log(), "executing script";"script" => &work.script);
-            work.run(package)
+            let start = Instant::now();
+            let ret = work.run(package);
+            work.ctx().env().metrics().observe(&metrics::SCRIPT_DURATION, &[&work.script], start.elapsed());
+            ret
         }, || VM::new(ctx.clone(), &script)));
 
         Ok(into_box(station))
@@ -51,6 +62,10 @@ impl WorkType for Duktape {
         // }, || VM::new(ctx.clone(), &script))))
     }
 
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        validator.check_file(&format!("{}.script", path), &self.script);
+    }
+
     fn box_clone(&self) -> Box<WorkType> {
         Box::new(self.clone())
     }
@@ -0,0 +1,123 @@
+use super::super::context::Context;
+use super::super::descriptions::Validator;
+use super::super::error::*;
+use super::super::metrics;
+use super::super::predicate::{read_content, Content, Matcher, Predicate};
+use super::super::traits::WorkType;
+use super::super::utils::station_fn_ctx2;
+use super::super::work::{WorkBox, WorkOutput};
+use conveyor::into_box;
+use conveyor_work::package::Package;
+use std::sync::Arc;
+
+/// Passes on the packages matching `keep` and not matching `drop`.
+/// Dropped packages are counted in the run report.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub keep: Option<Predicate>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub drop: Option<Predicate>,
+}
+
+/// Whether `package` matches `keep` and not `drop`
+fn kept(
+    keep: Option<&Matcher>,
+    drop: Option<&Matcher>,
+    package: &Package,
+    content: Option<&Content>,
+) -> bool {
+    keep.map(|m| m.test(package, content)).unwrap_or(true)
+        && !drop.map(|m| m.test(package, content)).unwrap_or(false)
+}
+
+#[typetag::serde]
+impl WorkType for Filter {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        info!(ctx.log().new(o!("worktype" => "filter")), "request filter station");
+
+        let keep = match &self.keep {
+            Some(p) => Some(p.compile()?),
+            None => None,
+        };
+        let drop = match &self.drop {
+            Some(p) => Some(p.compile()?),
+            None => None,
+        };
+
+        Ok(into_box(station_fn_ctx2(
+            async move |package: Package, ctx: Arc<(Context, Option<Matcher>, Option<Matcher>)>| {
+                let needs_content = ctx.1.iter().chain(ctx.2.iter()).any(|m| m.needs_content());
+                let (package, content) = await!(read_content(package, needs_content))?;
+
+                if kept(ctx.1.as_ref(), ctx.2.as_ref(), &package, content.as_ref()) {
+                    return Ok(vec![WorkOutput::Result(Ok(package))]);
+                }
+
+                let path = ctx.0.path();
+                debug!(ctx.0.log(), "dropping package"; "package" => package.name());
+                ctx.0.reporter().dropped(path);
+                let target = ctx.0.target().description().name.as_str();
+                ctx.0.env().metrics().inc(&metrics::PACKAGES_DROPPED, &[target, path]);
+                Ok(Vec::new())
+            },
+            Arc::new((ctx.clone(), keep, drop)),
+        )))
+    }
+
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        if self.keep.is_none() && self.drop.is_none() {
+            validator.error(path, "filter needs keep or drop");
+        }
+        if let Some(keep) = &self.keep {
+            keep.validate(validator, &format!("{}.keep", path));
+        }
+        if let Some(drop) = &self.drop {
+            drop.validate(validator, &format!("{}.drop", path));
+        }
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::super::descriptions::WorkDescription;
+    use super::super::super::predicate::matcher;
+    use super::super::super::target::testing::{names, run_steps, step};
+    use super::*;
+
+    #[test]
+    fn keep_and_drop() {
+        let keep = matcher(r"name: \.json$");
+        let drop = matcher("size: {max: 0}");
+        let empty = Content::new(Vec::new(), None);
+        let full = Content::new(b"{}".to_vec(), None);
+        let (json, html) = (Package::new("a.json", ""), Package::new("c.html", ""));
+
+        assert!(kept(Some(&keep), Some(&drop), &json, Some(&full)));
+        assert!(!kept(Some(&keep), Some(&drop), &json, Some(&empty)));
+        assert!(!kept(Some(&keep), Some(&drop), &html, Some(&full)));
+        assert!(kept(None, Some(&drop), &html, Some(&full)));
+        assert!(!kept(Some(&keep), None, &html, None));
+    }
+
+    #[test]
+    fn filter_station() {
+        let emit = step(|_| {
+            vec![
+                WorkOutput::Result(Ok(Package::new("a.json", "{}"))),
+                WorkOutput::Result(Ok(Package::new("b.json", ""))),
+                WorkOutput::Result(Ok(Package::new("c.html", "{}"))),
+            ]
+        });
+        let filter = serde_yaml::from_str::<Filter>(r"{keep: {name: '\.json$'}, drop: {size: {max: 0}}}").unwrap();
+
+        let (packages, report) = run_steps(vec![emit, WorkDescription::new(filter).build().unwrap()]);
+        assert_eq!(names(&packages), vec!["a.json"]);
+        assert_eq!(report.dropped["work.steps[1]"], 2);
+    }
+}
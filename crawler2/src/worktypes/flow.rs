@@ -1,4 +1,5 @@
 use super::super::context::{Args, Context};
+use super::super::descriptions::Validator;
 use super::super::error::*;
 use super::super::traits::WorkType;
 use super::super::utils::station_fn_ctx2;
@@ -26,6 +27,11 @@ impl WorkType for Flow {
         }
     }
 
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        let args = self.arguments.clone().unwrap_or_default();
+        validator.check_flow(&format!("{}.flow_name", path), &self.flow_name, &args);
+    }
+
     fn box_clone(&self) -> Box<WorkType> {
         Box::new(self.clone())
     }
@@ -0,0 +1,241 @@
+use super::super::context::Context;
+use super::super::descriptions::{chain_steps, Validator, WorkDescription};
+use super::super::error::*;
+use super::super::package::copy_of;
+use super::super::traits::WorkType;
+use super::super::utils::{station_fn_ctx2, WorkArcWrapper};
+use super::super::work::{Work, WorkBox, WorkOutput};
+use super::concat::FailurePolicy;
+use super::concat_json::to_json_value;
+use conveyor::futures::prelude::*;
+use conveyor::futures::stream::FuturesUnordered;
+use conveyor::into_box;
+use conveyor_work::package::Package;
+use serde_json::{Map, Value};
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForkMode {
+    /// The outputs of every branch continue after the fork
+    Each,
+    /// The outputs are collected into one JSON object with an array of
+    /// results for each branch
+    Merge,
+}
+
+impl Default for ForkMode {
+    fn default() -> ForkMode {
+        ForkMode::Each
+    }
+}
+
+/// Runs a copy of each package through every branch concurrently
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Fork {
+    pub branches: BTreeMap<String, Vec<WorkDescription>>,
+    #[serde(default)]
+    pub mode: ForkMode,
+    /// Name of the merged package, the name of the input by default
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub name: Option<String>,
+    /// What to do when a branch fails while merging
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+}
+
+struct ForkState {
+    ctx: Context,
+    branches: Vec<(String, Arc<WorkBox<Package>>)>,
+    fork: Fork,
+}
+
+/// Collects the values of each branch into an object with an array for
+/// every branch, along with the errors `policy` forwards
+fn merge(
+    branches: Vec<(String, Vec<CrawlResult<Value>>)>,
+    policy: FailurePolicy,
+    log: &Logger,
+) -> CrawlResult<(Value, Vec<CrawlError>)> {
+    let mut merged = Map::new();
+    let mut errors = Vec::new();
+    for (name, values) in branches {
+        let (values, failed) = policy.partition(values, log)?;
+        merged.insert(name, Value::Array(values));
+        errors.extend(failed);
+    }
+    Ok((Value::Object(merged), errors))
+}
+
+#[typetag::serde]
+impl WorkType for Fork {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        info!(ctx.log().new(o!("worktype" => "fork")), "request fork station");
+
+        let path = ctx.path().to_string();
+        let mut branches = Vec::with_capacity(self.branches.len());
+        for (name, steps) in &self.branches {
+            let station = chain_steps(steps, ctx, &format!("{}.branches.{}", path, name))?;
+            branches.push((name.clone(), Arc::new(station)));
+        }
+
+        Ok(into_box(station_fn_ctx2(
+            async move |mut package: Package, ctx: Arc<ForkState>| {
+                let body = await!(package.read_content())?;
+
+                if ctx.fork.mode == ForkMode::Each {
+                    return Ok(ctx
+                        .branches
+                        .iter()
+                        .map(|(_, station)| {
+                            WorkOutput::Work(Work::new(
+                                copy_of(&package, body.clone()),
+                                WorkArcWrapper::new(station.clone()),
+                            ))
+                        })
+                        .collect());
+                }
+
+                // All the results are needed here, so the branches run while this step waits
+                let scheduler = ctx.ctx.scheduler().clone();
+                let runs = ctx
+                    .branches
+                    .iter()
+                    .map(|(name, station)| {
+                        let work = Work::new(copy_of(&package, body.clone()), WorkArcWrapper::new(station.clone()));
+                        let name = name.clone();
+                        scheduler.run(vec![work]).map(move |results| (name, results))
+                    })
+                    .collect::<FuturesUnordered<_>>();
+                let runs = await!(scheduler.lend(runs.collect::<Vec<_>>()));
+
+                let mut branches = Vec::with_capacity(runs.len());
+                for (name, results) in runs {
+                    let mut values = Vec::with_capacity(results.len());
+                    for result in results {
+                        values.push(match result {
+                            Ok(mut p) => match await!(p.read_content()) {
+                                Ok(body) => to_json_value(&body).map_err(CrawlError::from),
+                                Err(e) => Err(e.into()),
+                            },
+                            Err(e) => Err(e),
+                        });
+                    }
+                    branches.push((name, values));
+                }
+                let (merged, errors) = match merge(branches, ctx.fork.on_failure, ctx.ctx.log()) {
+                    Ok(m) => m,
+                    Err(e) => return Ok(vec![WorkOutput::Result(Err(e))]),
+                };
+
+                let name = match &ctx.fork.name {
+                    Some(name) => ctx.ctx.interpolate(name)?,
+                    None => package.name().to_string(),
+                };
+                let mut out = vec![WorkOutput::Result(Ok(Package::new(&name, merged)))];
+                out.extend(errors.into_iter().map(|e| WorkOutput::Result(Err(e))));
+                Ok(out)
+            },
+            Arc::new(ForkState {
+                ctx: ctx.clone(),
+                branches,
+                fork: self.clone(),
+            }),
+        )))
+    }
+
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        if self.branches.is_empty() {
+            validator.error(format!("{}.branches", path), "no branches defined");
+        }
+        for (name, steps) in &self.branches {
+            validator.check_steps(&format!("{}.branches.{}", path, name), steps);
+        }
+        if let Some(name) = &self.name {
+            validator.check_template(&format!("{}.name", path), name);
+        }
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::super::target::testing::{names, run_steps, step};
+    use super::*;
+    use conveyor::futures::executor::block_on;
+    use serde_json::json;
+    use slog::Discard;
+
+    #[test]
+    fn merge_branches() {
+        let log = Logger::root(Discard, o!());
+        let branches = |raw: CrawlResult<Value>| {
+            vec![
+                ("json".to_string(), vec![Ok(json!({"title": "Koncert"}))]),
+                ("raw".to_string(), vec![raw, Ok(json!("<h1>Koncert</h1>"))]),
+            ]
+        };
+        let failed = || Err(CrawlErrorKind::NotFound("page".to_string()).into());
+
+        let (merged, errors) = merge(branches(Ok(json!("<h1>"))), FailurePolicy::Fail, &log).unwrap();
+        assert_eq!(
+            merged,
+            json!({"json": [{"title": "Koncert"}], "raw": ["<h1>", "<h1>Koncert</h1>"]})
+        );
+        assert!(errors.is_empty());
+
+        // A failed branch result under each policy
+        assert!(merge(branches(failed()), FailurePolicy::Fail, &log).is_err());
+
+        let (merged, errors) = merge(branches(failed()), FailurePolicy::Skip, &log).unwrap();
+        assert_eq!(merged, json!({"json": [{"title": "Koncert"}], "raw": ["<h1>Koncert</h1>"]}));
+        assert!(errors.is_empty());
+
+        let (merged, errors) = merge(branches(failed()), FailurePolicy::Forward, &log).unwrap();
+        assert_eq!(merged, json!({"json": [{"title": "Koncert"}], "raw": ["<h1>Koncert</h1>"]}));
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn fork_station() {
+        let emit = || step(|_| vec![WorkOutput::Result(Ok(Package::new("page", r#"{"title": "Koncert"}"#)))]);
+        let mut branches = BTreeMap::new();
+        branches.insert(
+            "json".to_string(),
+            vec![step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("json:{}", n), "{}")))])],
+        );
+        branches.insert(
+            "raw".to_string(),
+            vec![step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("raw:{}", n), r#""<h1>""#)))])],
+        );
+        let each = Fork {
+            branches,
+            mode: ForkMode::Each,
+            name: None,
+            on_failure: FailurePolicy::Fail,
+        };
+
+        // Every branch gets a copy of the package
+        let (packages, _) = run_steps(vec![emit(), WorkDescription::new(each.clone()).build().unwrap()]);
+        assert_eq!(names(&packages), vec!["json:page", "raw:page"]);
+
+        let merging = Fork {
+            mode: ForkMode::Merge,
+            name: Some("merged".to_string()),
+            ..each
+        };
+        let (mut packages, _) = run_steps(vec![emit(), WorkDescription::new(merging).build().unwrap()]);
+        assert_eq!(names(&packages), vec!["merged"]);
+        let body = block_on(packages[0].read_content()).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({"json": [{}], "raw": ["<h1>"]})
+        );
+    }
+}
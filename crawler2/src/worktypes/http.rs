@@ -1,27 +1,70 @@
+use super::super::content;
 use super::super::context::*;
+use super::super::descriptions::Validator;
 use super::super::error::*;
+use super::super::metrics::{self, Metrics};
+use super::super::package::PackageExt;
+use super::super::report::Reporter;
 use super::super::traits::WorkType;
 use super::super::utils::*;
 use super::super::work::{WorkBox, WorkOutput};
 use conveyor::ConveyorError;
+use conveyor::futures::prelude::*;
 use conveyor::*;
 use conveyor_http::{Http as WHttp, HttpResponse, HttpResponseReader, Url};
 use conveyor_work::http::{HttpOptions, Method};
 use conveyor_work::prelude::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, USER_AGENT};
 use slog::Logger;
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Instant;
 
-#[derive(Clone, Debug)]
-pub struct HttpResponseStream;
+#[derive(Clone, Debug, Default)]
+pub struct HttpResponseStream {
+    metrics: Option<Metrics>,
+}
+
+impl HttpResponseStream {
+    /// Counts the status codes of responses in `metrics`
+    pub fn new(metrics: Metrics) -> HttpResponseStream {
+        HttpResponseStream {
+            metrics: Some(metrics),
+        }
+    }
+}
+
+pub type ResponseBody =
+    Pin<Box<conveyor::futures::stream::Stream<Item = Result<Vec<u8>>> + Send + 'static>>;
+
+/// The parts of a response kept on the package
+pub struct Response {
+    pub status: u16,
+    /// The Content-Type header
+    pub content_type: Option<String>,
+    pub body: ResponseBody,
+}
 
 impl Station for HttpResponseStream {
     type Input = HttpResponse;
-    type Output =
-        Pin<Box<conveyor::futures::stream::Stream<Item = Result<Vec<u8>>> + Send + 'static>>;
+    type Output = Response;
     type Future = conveyor::futures::future::Ready<Result<Self::Output>>;
     fn execute(&self, mut input: Self::Input) -> Self::Future {
-        conveyor::futures::future::ready(Ok(Box::pin(input.stream())))
+        let status = input.status().as_u16();
+        if let Some(metrics) = &self.metrics {
+            metrics.inc(&metrics::HTTP_RESPONSES, &[&status.to_string()]);
+        }
+        let content_type = input
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        conveyor::futures::future::ready(Ok(Response {
+            status,
+            content_type,
+            body: Box::pin(input.stream()),
+        }))
     }
 }
 
@@ -29,6 +72,26 @@ impl Station for HttpResponseStream {
 pub struct Http {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub method: Option<Method>,
+    /// Request headers, interpolated. Values may use `${secret:NAME}`.
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub headers: HashMap<String, String>,
+    /// Request body, interpolated
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub body: Option<String>,
+}
+
+struct HttpState {
+    conveyor: Conveyor<WHttp, HttpResponseStream>,
+    method: Method,
+    log: Logger,
+    headers: HeaderMap,
+    body: Option<String>,
+    reporter: Reporter,
+    metrics: Metrics,
+}
+
+fn header_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> CrawlError {
+    CrawlErrorKind::Error(Box::new(e)).into()
 }
 
 #[typetag::serde]
@@ -36,7 +99,8 @@ impl WorkType for Http {
     fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
         let method = self.method.as_ref().unwrap_or(&Method::GET).clone();
 
-        let http = WHttp::new().pipe(HttpResponseStream);
+        let metrics = ctx.env().metrics().clone();
+        let http = WHttp::new().pipe(HttpResponseStream::new(metrics.clone()));
 
         let log = ctx
             .log()
@@ -44,25 +108,106 @@ impl WorkType for Http {
 
         info!(log, "request http station");
 
+        let mut headers = HeaderMap::new();
+        if let Some(ua) = &ctx.root().target().env().config().user_agent {
+            headers.insert(USER_AGENT, HeaderValue::from_str(ua).map_err(header_error)?);
+        }
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(header_error)?;
+            let mut value = HeaderValue::from_str(&ctx.interpolate(value)?).map_err(header_error)?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+
+        let body = match &self.body {
+            Some(body) => Some(ctx.interpolate(body)?),
+            None => None,
+        };
+
         Ok(into_box(station_fn_ctx2(
-            async move |mut package: Package,
-                        ctx: Arc<(Conveyor<WHttp, HttpResponseStream>, Method, Logger)>| {
+            async move |mut package: Package, ctx: Arc<HttpState>| {
                 let body = await!(package.read_content())?;
 
-                let json: String =
-                    serde_json::from_slice(&body).map_err(|e| ConveyorError::new(e))?;
+                // The url is either a JSON string or plain text
+                let url = match serde_json::from_slice::<String>(&body) {
+                    Ok(url) => url,
+                    Err(_) => content::decode(&body, package.content_type()).trim().to_string(),
+                };
+                let url = Url::parse(&url).map_err(|e| ConveyorError::new(e))?;
+                info!(ctx.log, "making request"; "url" => url.as_str());
+                let options = HttpOptions::new(ctx.method.clone(), url);
+                let mut request = options.to_request();
+                for (name, value) in ctx.headers.iter() {
+                    request.headers_mut().insert(name.clone(), value.clone());
+                }
+                if let Some(body) = &ctx.body {
+                    *request.body_mut() = Some(body.clone().into());
+                }
+                let host = url.host_str().unwrap_or_default().to_string();
+                ctx.metrics.inc(&metrics::HTTP_REQUESTS, &[&host]);
+                let start = Instant::now();
+                let response = match await!(ctx.conveyor.execute(request)) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        ctx.metrics.inc(&metrics::HTTP_RESPONSES, &["error"]);
+                        return Err(e);
+                    }
+                };
+                ctx.metrics.observe(&metrics::HTTP_DURATION, &[&host], start.elapsed());
+
+                let (reporter, metrics) = (ctx.reporter.clone(), ctx.metrics.clone());
+                let mut body: ResponseBody =
+                    Box::pin(response.body.inspect(move |chunk| {
+                        if let Ok(chunk) = chunk {
+                            reporter.bytes(chunk.len());
+                            metrics.add(&metrics::HTTP_BYTES, &[&host], chunk.len() as f64);
+                        }
+                    }));
+                info!(ctx.log, "request done"; "url" => url.as_str());
 
-                let url = Url::parse(&json).map_err(|e| ConveyorError::new(e))?;
-                info!(ctx.2, "making request"; "url" => url.as_str());
-                let options = HttpOptions::new(ctx.1.clone(), url);
-                let body = await!(ctx.0.execute(options.to_request()))?;
-                info!(ctx.2, "request done"; "url" => &json);
-                Ok(vec![WorkOutput::Result(Ok(package.set_value(body)))])
+                let declared = response.content_type.as_ref().map(|c| c.as_str());
+                let package = match content::detect(url.path(), declared, None) {
+                    // Binary content keeps streaming
+                    Some(ref c) if !content::is_text(c) => package.set_value(body).with_content_type(c),
+                    content_type => {
+                        // Text is read to decode it to UTF-8, which also lets an unknown type be sniffed
+                        let mut bytes = Vec::new();
+                        while let Some(chunk) = await!(body.next()) {
+                            bytes.extend(chunk?);
+                        }
+                        match content_type.or_else(|| content::sniff(&bytes).map(|c| c.to_string())) {
+                            Some(ref c) if content::is_text(c) => {
+                                let text = content::to_utf8(&bytes, Some(c));
+                                package.set_value(text.into_bytes()).with_content_type(content::with_utf8(c))
+                            }
+                            Some(c) => package.set_value(bytes).with_content_type(c),
+                            None => package.set_value(bytes),
+                        }
+                    }
+                };
+                Ok(vec![WorkOutput::Result(Ok(package.with_http_status(response.status)))])
             },
-            Arc::new((http, method, log)),
+            Arc::new(HttpState {
+                conveyor: http,
+                method,
+                log,
+                headers,
+                body,
+                reporter: ctx.reporter().clone(),
+                metrics,
+            }),
         )))
     }
 
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        for (name, value) in &self.headers {
+            validator.check_template(&format!("{}.headers.{}", path, name), value);
+        }
+        if let Some(body) = &self.body {
+            validator.check_template(&format!("{}.body", path), body);
+        }
+    }
+
     fn box_clone(&self) -> Box<WorkType> {
         Box::new(self.clone())
     }
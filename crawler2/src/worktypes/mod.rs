@@ -1,17 +1,30 @@
-
+mod batch;
 mod child_process;
 mod concat;
+mod concat_json;
+mod convert;
 mod duktape;
+mod filter;
 mod flow;
+mod fork;
 mod http;
 mod pass_through;
+mod split;
+mod switch;
 mod write_directory;
 
 
+pub use batch::*;
 pub use child_process::*;
 pub use concat::*;
+pub use concat_json::*;
+pub use convert::*;
 pub use duktape::*;
+pub use filter::*;
 pub use flow::*;
+pub use fork::*;
 pub use http::*;
 pub use pass_through::*;
+pub use split::*;
+pub use switch::*;
 pub use write_directory::*;
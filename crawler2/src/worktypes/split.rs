@@ -0,0 +1,168 @@
+use super::super::content;
+use super::super::context::Context;
+use super::super::descriptions::Validator;
+use super::super::error::*;
+use super::super::package::{detect_content_type, PackageExt};
+use super::super::traits::WorkType;
+use super::super::utils::station_fn_ctx2;
+use super::super::work::{WorkBox, WorkOutput};
+use conveyor::into_box;
+use conveyor_work::package::Package;
+use serde_json::Value;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitFormat {
+    /// The content is a JSON array
+    Json,
+    /// The content is text with an element on each line
+    Lines,
+}
+
+impl Default for SplitFormat {
+    fn default() -> SplitFormat {
+        SplitFormat::Json
+    }
+}
+
+/// Turns a package holding a list into a package per element.
+/// Elements are named by the `name` template, which can refer to `name`,
+/// `index` and `value`, or `{name}.{index}` without one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Split {
+    #[serde(default)]
+    pub format: SplitFormat,
+    /// JSON pointer of the array to split, the whole content by default
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub pointer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub name: Option<String>,
+}
+
+/// Splits `body`, text of type `content_type`, into its elements. Lines are
+/// trimmed and empty lines skipped.
+pub(crate) fn split(
+    body: &[u8],
+    content_type: Option<&str>,
+    format: SplitFormat,
+    pointer: Option<&str>,
+) -> CrawlResult<Vec<Value>> {
+    let text = content::decode(body, content_type);
+    match format {
+        SplitFormat::Json => {
+            let mut value = serde_json::from_str::<Value>(&text)
+                .map_err(|e| CrawlError::new(CrawlErrorKind::Error(Box::new(e))))?;
+            if let Some(pointer) = pointer {
+                value = match value.pointer_mut(pointer) {
+                    Some(v) => v.take(),
+                    None => return Err(CrawlErrorKind::NotFound(format!("json pointer: {}", pointer)).into()),
+                };
+            }
+            match value {
+                Value::Array(elements) => Ok(elements),
+                _ => Err(CrawlErrorKind::NotFound("json array".to_string()).into()),
+            }
+        }
+        SplitFormat::Lines => {
+            Ok(text
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty())
+                .map(|l| Value::String(l.to_string()))
+                .collect())
+        }
+    }
+}
+
+#[typetag::serde]
+impl WorkType for Split {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        info!(ctx.log().new(o!("worktype" => "split")), "request split station");
+
+        Ok(into_box(station_fn_ctx2(
+            async move |mut package: Package, ctx: Arc<(Context, Split)>| {
+                let body = await!(package.read_content())?;
+                let pointer = ctx.1.pointer.as_ref().map(|p| p.as_str());
+                let elements = match split(&body, package.content_type(), ctx.1.format, pointer) {
+                    Ok(e) => e,
+                    Err(e) => return Ok(vec![WorkOutput::Result(Err(e))]),
+                };
+
+                let mut out = Vec::with_capacity(elements.len());
+                for (index, value) in elements.into_iter().enumerate() {
+                    let name = match &ctx.1.name {
+                        Some(template) => ctx.0.interpolate_with(
+                            template,
+                            &args! {
+                                "name" => package.name(),
+                                "index" => index,
+                                "value" => value
+                            },
+                        )?,
+                        None => format!("{}.{}", package.name(), index),
+                    };
+                    out.push(WorkOutput::Result(Ok(match (ctx.1.format, value) {
+                        (SplitFormat::Lines, Value::String(line)) => {
+                            detect_content_type(Package::new(&name, line.as_str()), line.as_bytes())
+                        }
+                        (_, value) => Package::new(&name, value).with_content_type("application/json"),
+                    })));
+                }
+                Ok(out)
+            },
+            Arc::new((ctx.clone(), self.clone())),
+        )))
+    }
+
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        if let Some(pointer) = &self.pointer {
+            if self.format == SplitFormat::Lines {
+                validator.error(format!("{}.pointer", path), "pointer only applies to json");
+            } else if !pointer.is_empty() && !pointer.starts_with('/') {
+                validator.error(format!("{}.pointer", path), "pointer must start with \"/\"");
+            }
+        }
+        if let Some(name) = &self.name {
+            let variables = args! {
+                "name" => Value::Null,
+                "index" => Value::Null,
+                "value" => Value::Null
+            };
+            validator.with_variables(variables, |v| {
+                v.check_template(&format!("{}.name", path), name);
+            });
+        }
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn split_content() {
+        let body = br#"{"events": [{"id": 1}, {"id": 2}]}"#;
+        assert_eq!(
+            split(body, None, SplitFormat::Json, Some("/events")).unwrap(),
+            vec![serde_json::json!({"id": 1}), serde_json::json!({"id": 2})]
+        );
+        assert!(split(body, None, SplitFormat::Json, None).is_err());
+        assert_eq!(
+            split(b"https://loppen.dk/1\r\n\nhttps://loppen.dk/2\n", None, SplitFormat::Lines, None).unwrap(),
+            vec![
+                Value::String("https://loppen.dk/1".to_string()),
+                Value::String("https://loppen.dk/2".to_string())
+            ]
+        );
+        assert_eq!(
+            split(b"Koncert p\xe5 Loppen\n", Some("text/plain; charset=iso-8859-1"), SplitFormat::Lines, None).unwrap(),
+            vec![Value::String("Koncert på Loppen".to_string())]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use super::super::context::Context;
+use super::super::descriptions::{chain_steps, Validator, WorkDescription};
+use super::super::error::*;
+use super::super::predicate::{read_content, Content, Matcher, Predicate};
+use super::super::traits::WorkType;
+use super::super::utils::{station_fn_ctx2, WorkArcWrapper};
+use super::super::work::{Work, WorkBox, WorkOutput};
+use conveyor::into_box;
+use conveyor_work::package::Package;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Case {
+    pub when: Predicate,
+    pub steps: Vec<WorkDescription>,
+}
+
+/// Routes each package to the steps of the first case whose `when` holds.
+/// Packages matching no case go to `default`, or are passed on unchanged
+/// without one. The outputs of the steps continue after the switch.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Switch {
+    pub cases: Vec<Case>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub default: Option<Vec<WorkDescription>>,
+}
+
+struct SwitchState {
+    cases: Vec<(Matcher, Arc<WorkBox<Package>>)>,
+    default: Option<Arc<WorkBox<Package>>>,
+    needs_content: bool,
+}
+
+/// The steps of the first case `package` matches, or the default ones
+fn route<'a, T>(
+    cases: &'a [(Matcher, T)],
+    default: Option<&'a T>,
+    package: &Package,
+    content: Option<&Content>,
+) -> Option<&'a T> {
+    cases
+        .iter()
+        .find(|c| c.0.test(package, content))
+        .map(|c| &c.1)
+        .or(default)
+}
+
+#[typetag::serde]
+impl WorkType for Switch {
+    fn request_station(&self, ctx: &mut Context) -> CrawlResult<WorkBox<Package>> {
+        info!(ctx.log().new(o!("worktype" => "switch")), "request switch station");
+
+        let path = ctx.path().to_string();
+        let mut cases = Vec::with_capacity(self.cases.len());
+        for (i, case) in self.cases.iter().enumerate() {
+            let station = chain_steps(&case.steps, ctx, &format!("{}.cases[{}].steps", path, i))?;
+            cases.push((case.when.compile()?, Arc::new(station)));
+        }
+        let default = match &self.default {
+            Some(steps) => Some(Arc::new(chain_steps(steps, ctx, &format!("{}.default", path))?)),
+            None => None,
+        };
+        let needs_content = cases.iter().any(|c| c.0.needs_content());
+
+        Ok(into_box(station_fn_ctx2(
+            async move |package: Package, ctx: Arc<SwitchState>| {
+                let (package, content) = await!(read_content(package, ctx.needs_content))?;
+                Ok(vec![match route(&ctx.cases, ctx.default.as_ref(), &package, content.as_ref()) {
+                    Some(station) => WorkOutput::Work(Work::new(package, WorkArcWrapper::new(station.clone()))),
+                    None => WorkOutput::Result(Ok(package)),
+                }])
+            },
+            Arc::new(SwitchState {
+                cases,
+                default,
+                needs_content,
+            }),
+        )))
+    }
+
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        if self.cases.is_empty() {
+            validator.error(format!("{}.cases", path), "no cases defined");
+        }
+        for (i, case) in self.cases.iter().enumerate() {
+            let case_path = format!("{}.cases[{}]", path, i);
+            case.when.validate(validator, &format!("{}.when", case_path));
+            validator.guarded(|v| v.check_steps(&format!("{}.steps", case_path), &case.steps));
+        }
+        if let Some(steps) = &self.default {
+            validator.guarded(|v| v.check_steps(&format!("{}.default", path), steps));
+        }
+    }
+
+    fn box_clone(&self) -> Box<WorkType> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::super::super::predicate::matcher;
+    use super::super::super::target::testing::{names, run_steps, step};
+    use super::*;
+
+    #[test]
+    fn first_matching_case() {
+        let cases = vec![
+            (matcher(r"name: \.json$"), "json"),
+            (matcher("content: Koncert"), "koncert"),
+        ];
+        let content = Content::new(b"<h1>Koncert</h1>".to_vec(), None);
+        let (json, html) = (Package::new("a.json", ""), Package::new("b.html", ""));
+
+        assert_eq!(route(&cases, None, &json, Some(&content)), Some(&"json"));
+        assert_eq!(route(&cases, None, &html, Some(&content)), Some(&"koncert"));
+        // Packages matching no case go to the default, if any
+        assert_eq!(route(&cases, Some(&"other"), &html, None), Some(&"other"));
+        assert_eq!(route(&cases, None, &html, None), None);
+    }
+
+    #[test]
+    fn switch_station() {
+        let emit = step(|_| {
+            vec![
+                WorkOutput::Result(Ok(Package::new("a.json", ""))),
+                WorkOutput::Result(Ok(Package::new("b.html", ""))),
+            ]
+        });
+        let switch = WorkDescription::new(Switch {
+            cases: vec![Case {
+                when: serde_yaml::from_str(r"name: \.json$").unwrap(),
+                steps: vec![step(|n| vec![WorkOutput::Result(Ok(Package::new(&format!("json:{}", n), "")))])],
+            }],
+            default: Some(vec![step(|n| {
+                vec![WorkOutput::Result(Ok(Package::new(&format!("other:{}", n), "")))]
+            })]),
+        })
+        .build()
+        .unwrap();
+
+        let (packages, _) = run_steps(vec![emit, switch]);
+        assert_eq!(names(&packages), vec!["json:a.json", "other:b.html"]);
+    }
+}
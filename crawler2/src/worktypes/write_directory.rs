@@ -1,4 +1,5 @@
 use super::super::context::{Context, ParentOrRoot};
+use super::super::descriptions::Validator;
 use super::super::error::*;
 use super::super::traits::WorkType;
 use super::super::utils::station_fn_ctx2;
@@ -30,7 +31,7 @@ impl WorkType for WriteDirectory {
 
         info!(log, "request write-directory station");
 
-        let mut path = ctx.interpolate(self.path.as_str()).unwrap();
+        let mut path = ctx.interpolate(self.path.as_str())?;
 
         if !pathutils::is_absolute(&path) {
             path = pathutils::to_absolute(&path, ctx.root().target().path().to_str().unwrap())
@@ -67,6 +68,10 @@ impl WorkType for WriteDirectory {
         )))
     }
 
+    fn validate(&self, validator: &mut Validator, path: &str) {
+        validator.check_file(&format!("{}.path", path), &self.path);
+    }
+
     fn box_clone(&self) -> Box<WorkType> {
         Box::new(self.clone())
     }